@@ -1,41 +1,59 @@
 use crate::{
-    config::CONFIG,
+    cache_policy::cache_policy,
+    client_ip::{resolve_client_ip, ClientIp},
+    config::{Config, CONFIG},
     controller::{
-        admin::{admin, admin_gallery, admin_post, admin_view},
+        activitypub::{inn_actor, inn_inbox, inn_outbox, webfinger},
+        admin::{
+            admin, admin_audit, admin_dashboard, admin_emojis, admin_emojis_post, admin_gallery,
+            admin_invites, admin_invites_post, admin_loglevel, admin_loglevel_put, admin_post,
+            admin_view,
+        },
         feed::{feed, feed_add, feed_add_post, feed_read, feed_star, feed_subscribe, feed_update},
+        image_proxy::image_proxy,
         inn::{
-            comment_delete, comment_downvote, comment_hide, comment_post, comment_upvote,
-            edit_post, edit_post_post, inn, inn_feed, inn_join, inn_list, mod_feed_post, mod_inn,
-            mod_inn_post, post, post_delete, post_downvote, post_hide, post_lock, post_pin,
-            post_upvote, preview, tag,
+            backlinks, comment_delete, comment_downvote, comment_hide, comment_post,
+            comment_upvote, edit_post, edit_post_post, inn, inn_feed, inn_join, inn_list,
+            mod_feed_post, mod_inn, mod_inn_post, permalink, post, post_delete, post_downvote,
+            post_hide, post_lock, post_pin, post_upvote, preview, reader_post, tag,
         },
         message::{inbox, key, key_post, message, message_post},
-        meta_handler::{encoding_js, encryption_js, favicon, handler_404, home, robots, style},
+        meta_handler::{
+            api_health, api_version, csp, encoding_js, encryption_js, favicon, handler_404, home,
+            maintenance, robots, style, theme, CACHE_CONTROL_IMMUTABLE,
+        },
         notification::notification,
+        rate_limit::rate_limit,
         solo::{solo, solo_delete, solo_like, solo_list, solo_post},
         tantivy::search,
         upload::{gallery, image_delete, upload, upload_pic_post, upload_post},
         user::{
             remove_session, reset, reset_post, role_post, signin, signin_post, signout, signup,
-            signup_post, user, user_follow, user_list, user_password_post, user_recovery_code,
-            user_setting, user_setting_post,
+            signup_post, user, user_delete_post, user_follow, user_list, user_password_post,
+            user_recovery_code, user_setting, user_setting_post,
         },
     },
+    origin_check::check_origin,
 };
 use axum::{
-    error_handling::HandleErrorLayer, extract::DefaultBodyLimit, handler::Handler,
-    http::StatusCode, routing::get, BoxError, Router,
+    body::Body, error_handling::HandleErrorLayer, extract::DefaultBodyLimit, handler::Handler,
+    http::StatusCode, middleware::from_fn, response::IntoResponse, routing::get, BoxError, Router,
 };
-use http::header::HeaderValue;
-use std::time::Duration;
-use tower::{timeout::TimeoutLayer, ServiceBuilder};
+use http::{
+    header::{self, HeaderName, HeaderValue},
+    Method, Request, Response,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{io, net::SocketAddr, path::Path, time::Duration};
+use tokio::net::TcpListener;
+use tower::{timeout::TimeoutLayer, ServiceBuilder, ServiceExt};
 use tower_http::{
     compression::CompressionLayer,
-    cors::{Any, CorsLayer},
-    services::ServeDir,
-    trace::{DefaultMakeSpan, TraceLayer},
+    cors::{AllowOrigin, Any, CorsLayer},
+    services::{fs::ServeFileSystemResponseBody, ServeDir, ServeFile},
+    set_header::SetResponseHeader,
+    trace::TraceLayer,
 };
-use tracing::Level;
 
 const UPLOAD_LIMIT: usize = 20 * 1024 * 1024;
 
@@ -50,9 +68,21 @@ pub async fn router() -> Router {
             StatusCode::REQUEST_TIMEOUT
         }))
         .layer(TimeoutLayer::new(Duration::from_secs(10)))
-        .layer(CompressionLayer::new())
-        .layer(TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new().level(Level::INFO)))
-        .layer(cors);
+        .layer(compression_layer(CONFIG.compression))
+        .layer(from_fn(resolve_client_ip))
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|req: &Request<Body>| {
+                let client_ip = req.extensions().get::<ClientIp>().map(|ClientIp(ip)| ip);
+                tracing::info_span!(
+                    "request",
+                    method = %req.method(),
+                    uri = %req.uri(),
+                    version = ?req.version(),
+                    client_ip = ?client_ip,
+                )
+            }),
+        )
+        .layer(from_fn(rate_limit));
 
     let router_db = Router::new()
         .route("/", get(home))
@@ -65,14 +95,28 @@ pub async fn router() -> Router {
         .route("/user/avatar", get(user_setting).post(upload_pic_post))
         .route("/user/password", get(user_setting).post(user_password_post))
         .route("/user/recovery", get(user_setting).post(user_recovery_code))
+        .route("/user/delete", get(user_setting).post(user_delete_post))
         .route("/user/reset", get(reset).post(reset_post))
         .route("/user/list", get(user_list))
         .route("/user/remove/:session_id", get(remove_session))
         .route("/role/:id/:uid", get(user_list).post(role_post))
         .route("/notification", get(notification))
         .route("/admin", get(admin).post(admin_post))
+        .route("/admin/dashboard", get(admin_dashboard))
+        .route(
+            "/admin/loglevel",
+            get(admin_loglevel).put(admin_loglevel_put),
+        )
+        .route("/admin/logo", get(admin).post(upload_pic_post))
+        .route("/admin/favicon", get(admin).post(upload_pic_post))
+        .route("/admin/emojis", get(admin_emojis).post(admin_emojis_post))
         .route("/admin/view", get(admin_view))
         .route("/admin/gallery", get(admin_gallery))
+        .route("/admin/audit", get(admin_audit))
+        .route(
+            "/admin/invites",
+            get(admin_invites).post(admin_invites_post),
+        )
         .route("/image/delete/:uid/:img_id", get(image_delete))
         .route("/mod/:iid", get(mod_inn).post(mod_inn_post))
         .route("/mod/feed/:iid", get(mod_inn).post(mod_feed_post))
@@ -88,7 +132,14 @@ pub async fn router() -> Router {
         .route("/inn/:i", get(inn))
         .route("/inn/:i/join", get(inn_join))
         .route("/inn/:i/atom.xml", get(inn_feed))
+        .route("/inn/:i/actor", get(inn_actor))
+        .route("/inn/:i/inbox", get(inn_inbox).post(inn_inbox))
+        .route("/inn/:i/outbox", get(inn_outbox))
+        .route("/.well-known/webfinger", get(webfinger))
         .route("/post/:iid/:pid", get(post).post(comment_post))
+        .route("/p/:code", get(permalink))
+        .route("/reader/post/:iid/:pid", get(reader_post))
+        .route("/post/:iid/:pid/backlinks", get(backlinks))
         .route("/post/:iid/:pid/:cid/delete", get(comment_delete))
         .route("/post/:iid/:pid/:cid/hide", get(comment_hide))
         .route("/post/edit/:pid", get(edit_post).post(edit_post_post))
@@ -114,20 +165,344 @@ pub async fn router() -> Router {
         .route("/feed/subscribe/:uid/:item_id", get(feed_subscribe))
         .route("/feed/read/:item_id", get(feed_read))
         .route("/search", get(search))
+        .route("/imgproxy", get(image_proxy))
         .route("/message/:uid", get(message).post(message_post))
         .route("/key", get(key).post(key_post))
         .route("/inbox/:mid", get(inbox));
 
     let router_static = Router::new()
         .route("/static/style.css", get(style))
+        .route("/static/theme.css", get(theme))
         .route("/favicon.svg", get(favicon))
         .route("/robots.txt", get(robots))
         .route("/static/js/encryption-helper.js", get(encryption_js))
         .route("/static/js/encoding-helper.js", get(encoding_js))
-        .nest_service("/static/avatars", ServeDir::new(&CONFIG.avatars_path))
-        .nest_service("/static/inn_icons", ServeDir::new(&CONFIG.inn_icons_path))
-        .nest_service("/static/upload", ServeDir::new(&CONFIG.upload_path));
+        .nest_service(
+            "/static/avatars",
+            serve_dir(&CONFIG.avatars_path, CACHE_CONTROL_REVALIDATE),
+        )
+        .nest_service(
+            "/static/inn_icons",
+            serve_dir(&CONFIG.inn_icons_path, CACHE_CONTROL_REVALIDATE),
+        )
+        .nest_service(
+            "/static/upload",
+            serve_dir(&CONFIG.upload_path, CACHE_CONTROL_IMMUTABLE),
+        );
+
+    let router_api = Router::new()
+        .route("/version", get(api_version))
+        .route("/health", get(api_health))
+        .layer(api_cors_layer(&CONFIG));
+
+    let app = router_static
+        .merge(router_db.layer(from_fn(check_origin)))
+        .layer(cors)
+        .nest("/api", router_api)
+        .layer(from_fn(maintenance))
+        .layer(from_fn(csp))
+        .layer(from_fn(cache_policy));
+    let app = app.layer(middleware_stack);
+    if CONFIG.spa_fallback {
+        app.fallback_service(spa_fallback(&CONFIG.spa_path))
+    } else {
+        app.fallback(handler_404)
+    }
+}
+
+/// Bind a listener on `addr`. An unspecified IPv6 address like `[::]:3001` is dual-stack,
+/// so IPv4 clients can connect too.
+pub fn listener(addr: &str) -> io::Result<TcpListener> {
+    let addr: SocketAddr = addr.parse().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid addr {addr}: {e}"),
+        )
+    })?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(not(target_os = "windows"))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Avatars and inn icons keep their names when replaced, so they are revalidated with
+/// `If-Modified-Since` after a day.
+const CACHE_CONTROL_REVALIDATE: &str = "public, max-age=86400";
+
+/// Serve files with a `Cache-Control` header on successful and `304` responses.
+/// [ServeDir] answers `If-Modified-Since` by itself.
+fn serve_dir(
+    path: &Path,
+    cache_control: &'static str,
+) -> SetResponseHeader<
+    ServeDir,
+    impl Fn(&Response<ServeFileSystemResponseBody>) -> Option<HeaderValue> + Clone,
+> {
+    SetResponseHeader::overriding(
+        ServeDir::new(path),
+        header::CACHE_CONTROL,
+        move |res: &Response<ServeFileSystemResponseBody>| {
+            let status = res.status();
+            (status.is_success() || status == StatusCode::NOT_MODIFIED)
+                .then(|| HeaderValue::from_static(cache_control))
+        },
+    )
+}
+
+/// Serve the JS client at `root` for requests no other route matches. Client side routes get
+/// `index.html`, files of the build are served as they are. Unknown `/api/*` and `/static/*`
+/// paths, and missing files, are still 404.
+fn spa_fallback(root: &Path) -> Router {
+    let assets = ServeDir::new(root);
+    let index = ServeFile::new(root.join("index.html"));
+    Router::new().fallback(move |req: Request<Body>| {
+        let (assets, index) = (assets.clone(), index.clone());
+        async move {
+            let uri = req.uri().clone();
+            let path = uri.path();
+            let is_get = req.method() == Method::GET || req.method() == Method::HEAD;
+            if !is_get
+                || path == "/api"
+                || path.starts_with("/api/")
+                || path.starts_with("/static/")
+            {
+                return handler_404(uri).await.into_response();
+            }
+
+            // the last segment of a file of the build has an extension, like `/assets/index.js`
+            let is_file = path.rsplit('/').next().is_some_and(|s| s.contains('.'));
+            let res = if is_file {
+                assets.oneshot(req).await
+            } else {
+                index.oneshot(req).await
+            };
+            match res {
+                Ok(res) if res.status() != StatusCode::NOT_FOUND => res.into_response(),
+                _ => handler_404(uri).await.into_response(),
+            }
+        }
+    })
+}
+
+/// Negotiates br/gzip/zstd by `Accept-Encoding`. The default predicate skips images,
+/// so uploads and avatars are not compressed twice.
+fn compression_layer(enabled: bool) -> CompressionLayer {
+    CompressionLayer::new()
+        .br(enabled)
+        .gzip(enabled)
+        .zstd(enabled)
+}
+
+/// CORS for `/api/*`, configured by `cors_*` in [Config]. Browsers reject a wildcard origin
+/// with credentials, so `*` is ignored when credentials are allowed.
+fn api_cors_layer(config: &Config) -> CorsLayer {
+    let methods: Vec<Method> = config
+        .cors_methods
+        .iter()
+        .filter_map(|m| m.parse().ok())
+        .collect();
+    let headers: Vec<HeaderName> = config
+        .cors_headers
+        .iter()
+        .filter_map(|h| h.parse().ok())
+        .collect();
+
+    let cors = CorsLayer::new()
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(config.cors_allow_credentials);
+
+    if config.cors_origins.iter().any(|o| o == "*") && !config.cors_allow_credentials {
+        cors.allow_origin(AllowOrigin::any())
+    } else {
+        let origins: Vec<HeaderValue> = config
+            .cors_origins
+            .iter()
+            .filter(|o| *o != "*")
+            .filter_map(|o| o.parse().ok())
+            .collect();
+        cors.allow_origin(origins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::Html;
+
+    fn app(compression: bool) -> Router {
+        Router::new()
+            .route("/", get(|| async { Html("<p>freedit</p>".repeat(1000)) }))
+            .layer(compression_layer(compression))
+    }
+
+    fn request() -> Request<Body> {
+        Request::builder()
+            .uri("/")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api_cors() {
+        let config = Config {
+            cors_origins: vec!["https://client.example".into()],
+            ..Default::default()
+        };
+        let app = Router::new()
+            .route("/api/version", get(api_version))
+            .layer(api_cors_layer(&config));
+
+        let request = |origin: &str| {
+            Request::builder()
+                .uri("/api/version")
+                .header(header::ORIGIN, origin)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app
+            .clone()
+            .oneshot(request("https://client.example"))
+            .await
+            .unwrap();
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://client.example"
+        );
+
+        let res = app.oneshot(request("https://evil.example")).await.unwrap();
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_upload_cache_headers() {
+        let dir = std::env::temp_dir().join(format!("freedit_upload_{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("0123456789abcdef0123.gif"), b"GIF89a").unwrap();
+        let app =
+            Router::new().nest_service("/static/upload", serve_dir(&dir, CACHE_CONTROL_IMMUTABLE));
+
+        let request = Request::builder()
+            .uri("/static/upload/0123456789abcdef0123.gif")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[header::CACHE_CONTROL],
+            CACHE_CONTROL_IMMUTABLE
+        );
+        let last_modified = res.headers()[header::LAST_MODIFIED].clone();
+
+        let request = Request::builder()
+            .uri("/static/upload/0123456789abcdef0123.gif")
+            .header(header::IF_MODIFIED_SINCE, last_modified)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let request = Request::builder()
+            .uri("/static/upload/missing.gif")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(request).await.unwrap();
+        assert!(res.headers().get(header::CACHE_CONTROL).is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spa_fallback() {
+        let dir = std::env::temp_dir().join(format!("freedit_spa_{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(dir.join("assets")).unwrap();
+        std::fs::write(dir.join("index.html"), "<div id=\"app\"></div>").unwrap();
+        std::fs::write(dir.join("assets/index.js"), "console.log(1)").unwrap();
+        let app = Router::new()
+            .route("/inn/list", get(|| async { "server rendered" }))
+            .nest("/api", Router::new().route("/version", get(api_version)))
+            .fallback_service(spa_fallback(&dir));
+
+        let fetch = |uri: &str| {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request)
+        };
+        let body = |res: Response<Body>| async move {
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(bytes.to_vec()).unwrap()
+        };
+
+        let res = fetch("/client/inn/1/settings").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await, "<div id=\"app\"></div>");
+
+        let res = fetch("/assets/index.js").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await, "console.log(1)");
+
+        // server rendered routes are not shadowed
+        let res = fetch("/inn/list").await.unwrap();
+        assert_eq!(body(res).await, "server rendered");
+
+        for uri in [
+            "/api/nope",
+            "/api",
+            "/assets/missing.js",
+            "/static/missing.css",
+        ] {
+            let res = fetch(uri).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/client/route")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_listener_ipv6() {
+        let ipv6 = listener("[::1]:0").unwrap();
+        let addr = ipv6.local_addr().unwrap();
+        assert!(addr.is_ipv6());
+        tokio::net::TcpStream::connect(addr).await.unwrap();
+
+        // dual-stack: an IPv4 client can connect to `[::]`
+        let dual_stack = listener("[::]:0").unwrap();
+        let port = dual_stack.local_addr().unwrap().port();
+        tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+
+        assert!(listener("::1:3001").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let res = app(true).oneshot(request()).await.unwrap();
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
 
-    let app = router_static.merge(router_db);
-    app.layer(middleware_stack).fallback(handler_404)
+        let res = app(false).oneshot(request()).await.unwrap();
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+    }
 }
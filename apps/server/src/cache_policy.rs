@@ -0,0 +1,138 @@
+//! `Cache-Control` of responses by path, configured by `cache_policies` in [Config], so
+//! operators can let browsers and CDNs cache feeds or profiles without touching the handlers.
+//!
+//! [Config]: crate::config::Config
+
+use crate::{config::CONFIG, controller::user::COOKIE_NAME};
+use axum::{extract::Request, middleware::Next, response::Response};
+use axum_extra::headers::{Cookie, HeaderMapExt};
+use http::{
+    header::{CACHE_CONTROL, SET_COOKIE},
+    HeaderValue, Method,
+};
+
+/// For signed-in users, whose pages show their own name and notifications
+const CACHE_CONTROL_PRIVATE: &str = "private, no-store";
+
+/// Middleware: set the `Cache-Control` of the first of `cache_policies` matching the path.
+/// Handlers that set their own header keep it.
+pub(crate) async fn cache_policy(req: Request, next: Next) -> Response {
+    let is_get = req.method() == Method::GET || req.method() == Method::HEAD;
+    let signed_in = req
+        .headers()
+        .typed_get::<Cookie>()
+        .is_some_and(|cookie| cookie.get(COOKIE_NAME).is_some());
+    let policy = is_get
+        .then(|| find_policy(&CONFIG.cache_policies, req.uri().path()))
+        .flatten();
+
+    let mut res = next.run(req).await;
+    if let Some(value) = cache_control(policy, signed_in, &res) {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            res.headers_mut().insert(CACHE_CONTROL, value);
+        }
+    }
+    res
+}
+
+/// Responses of signed-in users, or setting a cookie, are never cached. Errors keep the
+/// default of no header.
+fn cache_control<'a>(policy: Option<&'a str>, signed_in: bool, res: &Response) -> Option<&'a str> {
+    let policy = policy?;
+    if res.headers().contains_key(CACHE_CONTROL) {
+        None
+    } else if signed_in || res.headers().contains_key(SET_COOKIE) {
+        Some(CACHE_CONTROL_PRIVATE)
+    } else if res.status().is_success() {
+        Some(policy)
+    } else {
+        None
+    }
+}
+
+/// A policy is written as `pattern directives`, like `/inn/*/atom.xml public, max-age=300`.
+/// Returns the directives of the first policy whose pattern matches `path`.
+fn find_policy<'a>(policies: &'a [String], path: &str) -> Option<&'a str> {
+    policies.iter().find_map(|policy| {
+        let (pattern, directives) = policy.trim().split_once(char::is_whitespace)?;
+        is_match(pattern, path).then(|| directives.trim())
+    })
+}
+
+/// `*` matches one path segment, and `**` as the last segment matches the rest of the path.
+fn is_match(pattern: &str, path: &str) -> bool {
+    let mut segments = path.split('/');
+    for p in pattern.split('/') {
+        if p == "**" {
+            return true;
+        }
+        match segments.next() {
+            Some(s) if p == "*" && !s.is_empty() => {}
+            Some(s) if p == s => {}
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use axum::body::Body;
+
+    #[test]
+    fn test_find_policy() {
+        let policies = vec![
+            "/inn/*/atom.xml public, max-age=300".to_owned(),
+            "/user/*  public, max-age=60".to_owned(),
+            "/api/** no-store".to_owned(),
+            "malformed".to_owned(),
+        ];
+        let policy = |path| find_policy(&policies, path);
+
+        assert_eq!(policy("/inn/1/atom.xml"), Some("public, max-age=300"));
+        assert_eq!(policy("/user/42"), Some("public, max-age=60"));
+        assert_eq!(policy("/api/version"), Some("no-store"));
+        assert_eq!(policy("/api/v1/inns"), Some("no-store"));
+
+        assert_eq!(policy("/inn/1"), None);
+        assert_eq!(policy("/inn//atom.xml"), None);
+        assert_eq!(policy("/user/42/follow"), None);
+        assert_eq!(policy("/user"), None);
+        assert_eq!(policy("malformed"), None);
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let policies = Config::default().cache_policies;
+        let header = |path, signed_in, res: Response| {
+            cache_control(find_policy(&policies, path), signed_in, &res)
+        };
+        let ok = || Response::new(Body::empty());
+
+        // feeds and profiles of visitors
+        assert_eq!(
+            header("/inn/1/atom.xml", false, ok()),
+            Some("public, max-age=300")
+        );
+        assert_eq!(header("/user/1", false, ok()), Some("public, max-age=60"));
+
+        // signed-in users, or a new session, are private
+        assert_eq!(header("/user/1", true, ok()), Some(CACHE_CONTROL_PRIVATE));
+        let mut res = ok();
+        res.headers_mut()
+            .insert(SET_COOKIE, HeaderValue::from_static("id=1"));
+        assert_eq!(header("/user/1", false, res), Some(CACHE_CONTROL_PRIVATE));
+
+        // errors, other pages, and headers set by handlers are left alone
+        let mut res = ok();
+        *res.status_mut() = http::StatusCode::NOT_FOUND;
+        assert_eq!(header("/user/1", false, res), None);
+        assert_eq!(header("/inn/1", false, ok()), None);
+        let mut res = ok();
+        res.headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        assert_eq!(header("/user/1", false, res), None);
+    }
+}
@@ -0,0 +1,175 @@
+//! Optional virus scanning of uploads with ClamAV. Files are streamed to clamd with the
+//! `INSTREAM` command before they are written to disk.
+
+use crate::{config::CONFIG, error::AppError};
+use axum::body::Bytes;
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+use tracing::{error, warn};
+
+/// clamd rejects chunks larger than its `StreamMaxLength`, so stay well below the default
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    Clean,
+    /// the name of the matched signature
+    Infected(String),
+}
+
+/// Scan an upload if `clamd_addr` is set. Infected files are rejected. If clamd can't be
+/// reached, the upload is rejected unless `clamd_fail_open` is set.
+pub(crate) async fn scan_upload(data: &Bytes) -> Result<(), AppError> {
+    if CONFIG.clamd_addr.is_empty() {
+        return Ok(());
+    }
+
+    let timeout = Duration::from_millis(CONFIG.clamd_timeout_ms);
+    match scan(&CONFIG.clamd_addr, data.clone(), timeout).await {
+        Ok(Verdict::Clean) => Ok(()),
+        Ok(Verdict::Infected(signature)) => {
+            warn!(signature, "infected upload rejected");
+            Err(AppError::Infected(signature))
+        }
+        Err(e) if CONFIG.clamd_fail_open => {
+            error!(%e, "clamd scan failed, upload accepted without scanning");
+            Ok(())
+        }
+        Err(e) => {
+            error!(%e, "clamd scan failed, upload rejected");
+            Err(AppError::ScanUnavailable)
+        }
+    }
+}
+
+/// Run the blocking clamd exchange on the blocking pool, giving up after `timeout`.
+async fn scan(addr: &str, data: Bytes, timeout: Duration) -> io::Result<Verdict> {
+    let addr = addr.to_owned();
+    let task = tokio::task::spawn_blocking(move || instream(&addr, &data, timeout));
+    match tokio::time::timeout(timeout, task).await {
+        Ok(Ok(verdict)) => verdict,
+        Ok(Err(e)) => Err(io::Error::other(e)),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "clamd scan timed out",
+        )),
+    }
+}
+
+fn instream(addr: &str, data: &[u8], timeout: Duration) -> io::Result<Verdict> {
+    #[cfg(unix)]
+    if addr.starts_with('/') {
+        let stream = std::os::unix::net::UnixStream::connect(addr)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        return instream_with(stream, data);
+    }
+
+    let socket_addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid clamd_addr {addr}"),
+        )
+    })?;
+    let stream = TcpStream::connect_timeout(&socket_addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    instream_with(stream, data)
+}
+
+/// `zINSTREAM`: length prefixed chunks ended by a zero length chunk, then a `\0` terminated
+/// reply like `stream: OK` or `stream: Eicar-Signature FOUND`.
+fn instream_with<S: Read + Write>(mut stream: S, data: &[u8]) -> io::Result<Verdict> {
+    stream.write_all(b"zINSTREAM\0")?;
+    for chunk in data.chunks(CHUNK_SIZE) {
+        stream.write_all(&(chunk.len() as u32).to_be_bytes())?;
+        stream.write_all(chunk)?;
+    }
+    stream.write_all(&[0; 4])?;
+    stream.flush()?;
+
+    let mut reply = Vec::new();
+    BufReader::new(stream).read_until(b'\0', &mut reply)?;
+    parse_reply(&reply)
+}
+
+fn parse_reply(reply: &[u8]) -> io::Result<Verdict> {
+    let reply = String::from_utf8_lossy(reply);
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(signature.to_owned()))
+    } else {
+        Err(io::Error::other(format!("unexpected clamd reply: {reply}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    const EICAR: &[u8] = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    /// A clamd that flags streams containing the EICAR test string
+    fn mock_clamd(connections: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut stream = stream.unwrap();
+                let mut command = [0; 10];
+                stream.read_exact(&mut command).unwrap();
+                assert_eq!(&command, b"zINSTREAM\0");
+
+                let mut data = Vec::new();
+                loop {
+                    let mut len = [0; 4];
+                    stream.read_exact(&mut len).unwrap();
+                    let len = u32::from_be_bytes(len) as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    let mut chunk = vec![0; len];
+                    stream.read_exact(&mut chunk).unwrap();
+                    data.extend(chunk);
+                }
+
+                let found = data.windows(EICAR.len()).any(|w| w == EICAR);
+                let reply: &[u8] = if found {
+                    b"stream: Eicar-Signature FOUND\0"
+                } else {
+                    b"stream: OK\0"
+                };
+                stream.write_all(reply).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_clamd_scan() {
+        let addr = mock_clamd(2);
+        let timeout = Duration::from_secs(5);
+
+        let mut infected = vec![b'a'; CHUNK_SIZE + 10];
+        infected.extend_from_slice(EICAR);
+        assert_eq!(
+            scan(&addr, Bytes::from(infected), timeout).await.unwrap(),
+            Verdict::Infected("Eicar-Signature".into())
+        );
+        assert_eq!(
+            scan(&addr, Bytes::from_static(b"clean file"), timeout)
+                .await
+                .unwrap(),
+            Verdict::Clean
+        );
+
+        assert!(parse_reply(b"INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+}
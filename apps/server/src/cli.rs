@@ -0,0 +1,113 @@
+//! Command line: `freedit [config.toml] [command]`. Without a command the server is started.
+
+use std::{env, process, sync::LazyLock};
+
+pub static CLI: LazyLock<Cli> = LazyLock::new(|| {
+    Cli::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}\n\n{USAGE}");
+        process::exit(2);
+    })
+});
+
+const USAGE: &str = "Usage: freedit [config.toml] [command]
+
+Commands:
+    serve                       start the server (default)
+    reindex                     rebuild the search index
+    create-admin <username>     create an admin, or promote an existing user
+    reset-password <username>   set a random password and sign out all sessions
+    backup                      create a db snapshot in snapshots_path
+    export-inn <iid>            print an inn with its posts and comments as json";
+
+const COMMANDS: [&str; 6] = [
+    "serve",
+    "reindex",
+    "create-admin",
+    "reset-password",
+    "backup",
+    "export-inn",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Serve,
+    Reindex,
+    CreateAdmin(String),
+    ResetPassword(String),
+    Backup,
+    ExportInn(u32),
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub config: Option<String>,
+    pub command: Command,
+}
+
+impl Cli {
+    /// `args` without the program name. The first argument is the config path unless it is a
+    /// command, so `freedit config.toml` keeps working.
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.into_iter().peekable();
+        let config = args.next_if(|a| !COMMANDS.contains(&a.as_str()));
+
+        let command = match args.next().as_deref() {
+            None | Some("serve") => Command::Serve,
+            Some("reindex") => Command::Reindex,
+            Some("create-admin") => Command::CreateAdmin(args.next().ok_or("missing <username>")?),
+            Some("reset-password") => {
+                Command::ResetPassword(args.next().ok_or("missing <username>")?)
+            }
+            Some("backup") => Command::Backup,
+            Some("export-inn") => {
+                let iid = args.next().ok_or("missing <iid>")?;
+                Command::ExportInn(iid.parse().map_err(|_| format!("invalid iid: {iid}"))?)
+            }
+            Some(other) => return Err(format!("unknown command: {other}")),
+        };
+
+        if let Some(extra) = args.next() {
+            return Err(format!("unexpected argument: {extra}"));
+        }
+
+        Ok(Cli { config, command })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, String> {
+        Cli::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_cli_parse() {
+        let cli = parse(&[]).unwrap();
+        assert_eq!(cli.config, None);
+        assert_eq!(cli.command, Command::Serve);
+
+        let cli = parse(&["my.toml"]).unwrap();
+        assert_eq!(cli.config.as_deref(), Some("my.toml"));
+        assert_eq!(cli.command, Command::Serve);
+
+        let cli = parse(&["reindex"]).unwrap();
+        assert_eq!(cli.config, None);
+        assert_eq!(cli.command, Command::Reindex);
+
+        let cli = parse(&["my.toml", "create-admin", "alice"]).unwrap();
+        assert_eq!(cli.config.as_deref(), Some("my.toml"));
+        assert_eq!(cli.command, Command::CreateAdmin("alice".into()));
+
+        assert_eq!(
+            parse(&["export-inn", "3"]).unwrap().command,
+            Command::ExportInn(3)
+        );
+
+        assert!(parse(&["reset-password"]).is_err());
+        assert!(parse(&["export-inn", "x"]).is_err());
+        assert!(parse(&["my.toml", "nope"]).is_err());
+        assert!(parse(&["backup", "extra"]).is_err());
+    }
+}
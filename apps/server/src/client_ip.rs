@@ -0,0 +1,251 @@
+//! Resolve the client ip of a request. Proxy headers are only honored when
+//! `trust_proxy_headers` is set and the peer is one of `trusted_proxies`. With an empty list
+//! only the peer itself is trusted, so the last forwarded address is the client.
+
+use crate::config::CONFIG;
+use axum::{extract::ConnectInfo, extract::Request, middleware::Next, response::Response};
+use http::HeaderMap;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::LazyLock,
+};
+use tracing::warn;
+
+/// The resolved client ip, stored as a request extension by [resolve_client_ip].
+/// Handlers can read it with `Extension<ClientIp>`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientIp(pub(crate) IpAddr);
+
+static PROXY_TRUST: LazyLock<ProxyTrust> =
+    LazyLock::new(|| ProxyTrust::new(CONFIG.trust_proxy_headers, &CONFIG.trusted_proxies));
+
+/// Middleware that stores [ClientIp] in the request extensions.
+pub(crate) async fn resolve_client_ip(mut req: Request, next: Next) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Some(ip) = PROXY_TRUST.client_ip(req.headers(), peer) {
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(req).await
+}
+
+struct ProxyTrust {
+    enabled: bool,
+    /// empty means only the peer is trusted, whatever its address
+    proxies: Vec<Cidr>,
+}
+
+impl ProxyTrust {
+    fn new(enabled: bool, proxies: &[String]) -> Self {
+        let proxies = proxies
+            .iter()
+            .filter_map(|s| {
+                let cidr = Cidr::parse(s);
+                if cidr.is_none() {
+                    warn!("invalid trusted proxy: {s}");
+                }
+                cidr
+            })
+            .collect();
+        Self { enabled, proxies }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.enabled && self.proxies.iter().any(|c| c.contains(ip))
+    }
+
+    /// Walk the forwarded chain from the nearest proxy back to the client, and stop at the
+    /// first address that is not a trusted proxy. IPv4-mapped IPv6 addresses from a
+    /// dual-stack socket are converted back to IPv4.
+    fn client_ip(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        let mut client = peer?.to_canonical();
+        if self.enabled && self.proxies.is_empty() {
+            if let Some(ip) = forwarded_for(headers).pop().and_then(|hop| parse_ip(&hop)) {
+                client = ip.to_canonical();
+            }
+            return Some(client);
+        }
+        if !self.is_trusted(client) {
+            return Some(client);
+        }
+
+        for hop in forwarded_for(headers).iter().rev() {
+            let Some(ip) = parse_ip(hop) else {
+                break;
+            };
+            client = ip.to_canonical();
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+/// Addresses from `Forwarded` (RFC 7239), or `X-Forwarded-For` if there is none,
+/// ordered from the client to the nearest proxy.
+fn forwarded_for(headers: &HeaderMap) -> Vec<String> {
+    let forwarded: Vec<String> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (k, v) = pair.trim().split_once('=')?;
+                k.eq_ignore_ascii_case("for")
+                    .then(|| v.trim_matches('"').to_owned())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|s| s.trim().to_owned())
+        .collect()
+}
+
+/// Parse `1.2.3.4`, `2001:db8::1`, `[2001:db8::1]` and the same with a port.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    s.parse::<IpAddr>()
+        .ok()
+        .or_else(|| s.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            s.strip_prefix('[')
+                .and_then(|s| s.strip_suffix(']'))
+                .and_then(|s| s.parse().ok())
+        })
+}
+
+/// An ip network like `10.0.0.0/8` or `2001:db8::/32`. A plain address is a single host.
+struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (s.trim().parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Self {
+            addr: addr.to_canonical(),
+            prefix,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(!cidr.contains(ip("::1")));
+
+        assert!(Cidr::parse("2001:db8::/32")
+            .unwrap()
+            .contains(ip("2001:db8::1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("1.2.3.4")));
+        assert!(Cidr::parse("127.0.0.1").unwrap().contains(ip("127.0.0.1")));
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+        assert!(Cidr::parse("nope").is_none());
+    }
+
+    #[test]
+    fn test_client_ip_trusted_peer() {
+        let trust = ProxyTrust::new(true, &["10.0.0.0/8".into()]);
+        let peer = Some(ip("::ffff:10.0.0.1"));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(trust.client_ip(&headers, peer), Some(ip("10.0.0.1")));
+
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("198.51.100.7, [2001:db8::1]:4711, 10.0.0.2"),
+        );
+        // the spoofable leftmost entry is ignored, the first untrusted hop wins
+        assert_eq!(trust.client_ip(&headers, peer), Some(ip("2001:db8::1")));
+
+        headers.insert(
+            "forwarded",
+            HeaderValue::from_static(r#"for=192.0.2.60;proto=https, For="[2001:db8::2]:80""#),
+        );
+        assert_eq!(trust.client_ip(&headers, peer), Some(ip("2001:db8::2")));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("unknown"));
+        assert_eq!(trust.client_ip(&headers, peer), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_client_ip_untrusted_peer() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.7"));
+
+        // peer is not a trusted proxy
+        let trust = ProxyTrust::new(true, &["10.0.0.0/8".into()]);
+        let peer = Some(ip("203.0.113.9"));
+        assert_eq!(trust.client_ip(&headers, peer), Some(ip("203.0.113.9")));
+
+        // proxy headers are disabled
+        let trust = ProxyTrust::new(false, &[]);
+        let peer = Some(ip("10.0.0.1"));
+        assert_eq!(trust.client_ip(&headers, peer), Some(ip("10.0.0.1")));
+
+        // no list trusts only the peer
+        let trust = ProxyTrust::new(true, &[]);
+        assert_eq!(trust.client_ip(&headers, peer), Some(ip("198.51.100.7")));
+    }
+
+    #[test]
+    fn test_client_ip_spoofed_hop() {
+        let trust = ProxyTrust::new(true, &[]);
+        let peer = Some(ip("10.0.0.1"));
+
+        // the client sent its own header, which the proxy appended to
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 10.0.0.3, 203.0.113.9"),
+        );
+        assert_eq!(trust.client_ip(&headers, peer), Some(ip("203.0.113.9")));
+
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.1.1.1, nope"));
+        assert_eq!(trust.client_ip(&headers, peer), Some(ip("10.0.0.1")));
+    }
+}
@@ -1,7 +1,9 @@
+use crate::cli::CLI;
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::fs::{self, read_to_string, File};
 use std::io::Write;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
 use tracing::{info, warn};
@@ -19,6 +21,187 @@ pub struct Config {
     pub(crate) upload_path: PathBuf,
     pub(crate) tantivy_path: PathBuf,
     pub(crate) proxy: String,
+    #[serde(default)]
+    pub(crate) registration_mode: RegistrationMode,
+    /// Set to false if compression is done by a reverse proxy
+    #[serde(default = "default_true")]
+    pub(crate) compression: bool,
+    /// Origins allowed to call `/api/*`. Use `["*"]` to allow any origin without credentials.
+    #[serde(default)]
+    pub(crate) cors_origins: Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub(crate) cors_methods: Vec<String>,
+    #[serde(default = "default_cors_headers")]
+    pub(crate) cors_headers: Vec<String>,
+    #[serde(default)]
+    pub(crate) cors_allow_credentials: bool,
+    /// Honor `X-Forwarded-For` for the client ip. Only enable this behind a reverse proxy.
+    #[serde(default)]
+    pub(crate) trust_proxy_headers: bool,
+    /// Proxies allowed to set forwarded headers, like `["127.0.0.1", "10.0.0.0/8"]`.
+    /// Empty trusts only the peer when `trust_proxy_headers` is set, so the client ip is the
+    /// last forwarded address.
+    #[serde(default)]
+    pub(crate) trusted_proxies: Vec<String>,
+    /// Uploaded images larger than this on either axis are downscaled
+    #[serde(default = "default_max_image_px")]
+    pub(crate) max_image_px: u32,
+    /// OTLP gRPC endpoint like `http://127.0.0.1:4317`. Empty disables OpenTelemetry export.
+    #[serde(default)]
+    pub(crate) otlp_endpoint: String,
+    /// Ratio of traces sampled, from 0.0 to 1.0
+    #[serde(default = "default_otlp_sample_ratio")]
+    pub(crate) otlp_sample_ratio: f64,
+    /// Absolute lifetime of a "remember me" session
+    #[serde(default = "default_session_ttl_secs")]
+    pub(crate) session_ttl_secs: i64,
+    /// A session without "remember me" expires if it is not used for this long
+    #[serde(default = "default_session_idle_secs")]
+    pub(crate) session_idle_secs: i64,
+    /// Page cache of sled in MB. More cache means fewer disk reads, at the cost of memory.
+    #[serde(default = "default_db_cache_mb")]
+    pub(crate) db_cache_mb: u64,
+    /// How often sled flushes writes to disk. Writes made after the last flush are lost if
+    /// the process crashes, so a longer interval trades durability for write throughput.
+    #[serde(default = "default_db_flush_every_ms")]
+    pub(crate) db_flush_every_ms: u64,
+    /// Requests per minute of a signed-in user, or of a client ip for visitors. 0 disables it.
+    #[serde(default = "default_rate_global_per_min")]
+    pub(crate) rate_global_per_min: u32,
+    /// PEM certificate chain for built-in TLS and HTTP/2. Leave empty to serve plain HTTP,
+    /// like behind a reverse proxy. The certificate is reloaded on `SIGHUP`.
+    #[serde(default)]
+    pub tls_cert: PathBuf,
+    /// PEM private key of `tls_cert`
+    #[serde(default)]
+    pub tls_key: PathBuf,
+    /// Initial `tracing` filter, like `info,freedit=debug`. Admins can change it at runtime.
+    #[serde(default = "default_log_filter")]
+    pub(crate) log_filter: String,
+    #[serde(default)]
+    pub(crate) log_format: LogFormat,
+    /// ClamAV clamd address to scan uploads with, `host:port` or a unix socket path.
+    /// Empty disables scanning.
+    #[serde(default)]
+    pub(crate) clamd_addr: String,
+    /// Keep it well below the 10 s request timeout, so `clamd_fail_open` can still apply
+    #[serde(default = "default_clamd_timeout_ms")]
+    pub(crate) clamd_timeout_ms: u64,
+    /// Accept uploads when clamd is down or times out, instead of rejecting them
+    #[serde(default)]
+    pub(crate) clamd_fail_open: bool,
+    /// Check the outbound links of posts every this many hours. 0 disables it.
+    #[serde(default)]
+    pub dead_link_check_hours: u64,
+    /// Reject form posts whose `Origin` or `Referer` is another site. Turn it off for clients
+    /// that send a foreign origin on purpose.
+    #[serde(default = "default_true")]
+    pub(crate) check_origin: bool,
+    /// Other origins allowed to post forms, like `https://forum.example.org`, for sites served
+    /// on several domains
+    #[serde(default)]
+    pub(crate) trusted_origins: Vec<String>,
+    /// Serve the JS client from `spa_path`. Its `index.html` is the answer to unknown `GET`
+    /// routes, so client side routing works on reload.
+    #[serde(default)]
+    pub(crate) spa_fallback: bool,
+    /// Build output of the JS client, like `apps/client/dist`
+    #[serde(default = "default_spa_path")]
+    pub(crate) spa_path: PathBuf,
+    /// `Cache-Control` by path, as `pattern directives`. In patterns, `*` is one path segment
+    /// and a trailing `**` the rest of the path. The first match wins. Responses of signed-in
+    /// users always get `private, no-store`.
+    #[serde(default = "default_cache_policies")]
+    pub(crate) cache_policies: Vec<String>,
+    /// Record privileged actions, like site settings or role changes, in the audit log
+    #[serde(default = "default_true")]
+    pub(crate) audit_log: bool,
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+const fn default_max_image_px() -> u32 {
+    4096
+}
+
+const fn default_otlp_sample_ratio() -> f64 {
+    1.0
+}
+
+const fn default_session_ttl_secs() -> i64 {
+    14 * 24 * 3600
+}
+
+const fn default_session_idle_secs() -> i64 {
+    4 * 3600
+}
+
+const fn default_db_cache_mb() -> u64 {
+    1024
+}
+
+const fn default_db_flush_every_ms() -> u64 {
+    500
+}
+
+const fn default_rate_global_per_min() -> u32 {
+    600
+}
+
+const fn default_clamd_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_spa_path() -> PathBuf {
+    PathBuf::from("client")
+}
+
+fn default_cache_policies() -> Vec<String> {
+    vec![
+        "/inn/*/atom.xml public, max-age=300".into(),
+        "/user/* public, max-age=60".into(),
+    ]
+}
+
+const DB_CACHE_MB_RANGE: RangeInclusive<u64> = 16..=65536;
+const DB_FLUSH_EVERY_MS_RANGE: RangeInclusive<u64> = 10..=60_000;
+
+fn default_log_filter() -> String {
+    "info,tantivy=warn".into()
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".into(), "POST".into()]
+}
+
+fn default_cors_headers() -> Vec<String> {
+    vec!["content-type".into()]
+}
+
+/// Format of the log lines written to stdout
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    /// human readable lines
+    #[default]
+    Pretty,
+    /// one json object per line, for log collectors
+    Json,
+}
+
+/// Who is allowed to sign up
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RegistrationMode {
+    /// anyone can sign up
+    #[default]
+    Open,
+    /// a valid invite code is required
+    Invite,
+    /// nobody can sign up
+    Closed,
 }
 
 impl Config {
@@ -32,11 +215,7 @@ impl Config {
             .parent()
             .expect("Failed to get server directory");
 
-        let cfg_file = exe_dir.join(
-            env::args()
-                .nth(1)
-                .unwrap_or_else(|| "config.toml".to_owned()),
-        );
+        let cfg_file = exe_dir.join(CLI.config.as_deref().unwrap_or("config.toml"));
         let config = if let Ok(config_toml_content) = read_to_string(&cfg_file) {
             let mut config: Config =
                 basic_toml::from_str(&config_toml_content).expect("Failed to parse config.toml");
@@ -55,9 +234,36 @@ impl Config {
         };
 
         config.ensure_dirs();
+        config.clamp_db_settings();
         config
     }
 
+    /// Keep the sled settings within sane ranges.
+    fn clamp_db_settings(&mut self) {
+        let cache_mb = self
+            .db_cache_mb
+            .clamp(*DB_CACHE_MB_RANGE.start(), *DB_CACHE_MB_RANGE.end());
+        if cache_mb != self.db_cache_mb {
+            warn!(
+                "db_cache_mb {} out of range {DB_CACHE_MB_RANGE:?}, using {cache_mb}",
+                self.db_cache_mb
+            );
+            self.db_cache_mb = cache_mb;
+        }
+
+        let flush_ms = self.db_flush_every_ms.clamp(
+            *DB_FLUSH_EVERY_MS_RANGE.start(),
+            *DB_FLUSH_EVERY_MS_RANGE.end(),
+        );
+        if flush_ms != self.db_flush_every_ms {
+            warn!(
+                "db_flush_every_ms {} out of range {DB_FLUSH_EVERY_MS_RANGE:?}, using {flush_ms}",
+                self.db_flush_every_ms
+            );
+            self.db_flush_every_ms = flush_ms;
+        }
+    }
+
     fn resolve_paths(&mut self, base_dir: &Path) {
         let path_fields: &mut [&mut PathBuf] = &mut [
             &mut self.db,
@@ -66,11 +272,19 @@ impl Config {
             &mut self.inn_icons_path,
             &mut self.upload_path,
             &mut self.tantivy_path,
+            &mut self.spa_path,
         ];
 
         for p in path_fields.iter_mut() {
             **p = resolve_path(base_dir, p.as_path());
         }
+
+        // empty means no tls
+        for p in [&mut self.tls_cert, &mut self.tls_key] {
+            if !p.as_os_str().is_empty() {
+                *p = resolve_path(base_dir, p);
+            }
+        }
     }
 
     fn ensure_dirs(&self) {
@@ -101,6 +315,36 @@ impl Default for Config {
             upload_path: PathBuf::from("static/imgs/upload"),
             tantivy_path: PathBuf::from("tantivy"),
             proxy: "".into(),
+            registration_mode: RegistrationMode::Open,
+            compression: true,
+            cors_origins: vec![],
+            cors_methods: default_cors_methods(),
+            cors_headers: default_cors_headers(),
+            cors_allow_credentials: false,
+            trust_proxy_headers: false,
+            trusted_proxies: vec![],
+            max_image_px: default_max_image_px(),
+            otlp_endpoint: String::new(),
+            otlp_sample_ratio: default_otlp_sample_ratio(),
+            session_ttl_secs: default_session_ttl_secs(),
+            session_idle_secs: default_session_idle_secs(),
+            db_cache_mb: default_db_cache_mb(),
+            db_flush_every_ms: default_db_flush_every_ms(),
+            rate_global_per_min: default_rate_global_per_min(),
+            tls_cert: PathBuf::new(),
+            tls_key: PathBuf::new(),
+            log_filter: default_log_filter(),
+            log_format: LogFormat::Pretty,
+            clamd_addr: String::new(),
+            clamd_timeout_ms: default_clamd_timeout_ms(),
+            clamd_fail_open: false,
+            dead_link_check_hours: 0,
+            check_origin: true,
+            trusted_origins: vec![],
+            spa_fallback: false,
+            spa_path: default_spa_path(),
+            cache_policies: default_cache_policies(),
+            audit_log: true,
         }
     }
 }
@@ -0,0 +1,357 @@
+//! ## ActivityPub actor and outbox for inns
+//!
+//! Every inn is exposed as a `Group` actor. Only the read side is implemented for now:
+//! actor, outbox and webfinger. Delivery is not supported yet, so the `inbox`, which servers
+//! require on an actor, answers `405 Method Not Allowed`. The public key is RSA, as servers
+//! verify HTTP signatures with `rsa-sha256`.
+
+use super::{
+    db_utils::{get_id_by_name, get_one, u32_to_ivec},
+    inn::get_pids_by_iids,
+    meta_handler::ParamsPage,
+    Inn, Post, PostStatus, SiteConfig,
+};
+use crate::{error::AppError, DB};
+use axum::{
+    extract::{Path, Query},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use jiff::Timestamp;
+use rand::rngs::OsRng;
+use rinja::filters::{escape, Html};
+use rsa::{
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sled::Db;
+
+const AP_CONTENT_TYPE: &str = "application/activity+json";
+const AP_PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// Size of the RSA keys of inns
+const RSA_BITS: usize = 2048;
+
+/// `GET /inn/:iid/actor`
+pub(crate) async fn inn_actor(Path(i): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let inn = get_inn(&i)?;
+    let iid = inn.iid;
+    // generating the key pair of an inn takes a while, so keep it off the async workers
+    let public_key_pem = tokio::task::spawn_blocking(move || inn_public_key_pem(&DB, iid))
+        .await
+        .map_err(|e| AppError::Custom(e.to_string()))??;
+
+    let actor = actor_json(&site_config.domain, &inn, &public_key_pem);
+    Ok(ap_response(AP_CONTENT_TYPE, &actor))
+}
+
+fn actor_json(domain: &str, inn: &Inn, public_key_pem: &str) -> Value {
+    let actor_id = format!("{domain}/inn/{}/actor", inn.iid);
+    json!({
+        "@context": [
+            "https://www.w3.org/ns/activitystreams",
+            "https://w3id.org/security/v1"
+        ],
+        "id": actor_id,
+        "type": "Group",
+        "preferredUsername": inn.inn_name.replace(' ', "_"),
+        "name": inn.inn_name,
+        "summary": inn.about,
+        "url": format!("{domain}/inn/{}", inn.iid),
+        "inbox": format!("{domain}/inn/{}/inbox", inn.iid),
+        "outbox": format!("{domain}/inn/{}/outbox", inn.iid),
+        "icon": {
+            "type": "Image",
+            "url": format!("{domain}/static/inn_icons/{}.png", inn.iid),
+        },
+        "publicKey": {
+            "id": format!("{actor_id}#main-key"),
+            "owner": actor_id,
+            "publicKeyPem": public_key_pem,
+        },
+    })
+}
+
+/// `POST /inn/:iid/inbox`, activities sent to inns are not accepted yet
+pub(crate) async fn inn_inbox() -> StatusCode {
+    StatusCode::METHOD_NOT_ALLOWED
+}
+
+/// `GET /inn/:iid/outbox`
+pub(crate) async fn inn_outbox(Path(i): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let inn = get_inn(&i)?;
+    let domain = &site_config.domain;
+    let actor_id = format!("{domain}/inn/{}/actor", inn.iid);
+
+    let page_params = ParamsPage {
+        anchor: 0,
+        n: 20,
+        is_desc: true,
+    };
+    let pids = if inn.is_open_access() {
+        get_pids_by_iids(&DB, &[inn.iid], &page_params)?
+    } else {
+        vec![]
+    };
+
+    let mut items = Vec::with_capacity(pids.len());
+    for pid in pids {
+        let post: Post = get_one(&DB, "posts", pid)?;
+        let html = match post.status {
+            PostStatus::HiddenByMod => "<p><i>Hidden by mod.</i></p>".into(),
+            PostStatus::HiddenByUser => "<p><i>Hidden by user.</i></p>".into(),
+            _ => post.content.to_html(&DB, post.iid)?,
+        };
+        items.push(create_activity(domain, &actor_id, &post, &html)?);
+    }
+
+    let outbox = outbox_json(domain, inn.iid, items);
+    Ok(ap_response(AP_CONTENT_TYPE, &outbox))
+}
+
+/// `Create` activity of a post, `html` is its rendered content
+fn create_activity(
+    domain: &str,
+    actor_id: &str,
+    post: &Post,
+    html: &str,
+) -> Result<Value, AppError> {
+    let url = format!("{domain}/post/{}/{}", post.iid, post.pid);
+    let published = Timestamp::from_second(post.created_at)
+        .map_err(|e| AppError::Custom(format!("Invalid time of post {}: {e}", post.pid)))?
+        .strftime("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
+    let title = escape(&post.title, Html).unwrap();
+    let content = format!(r#"<p><a href="{url}">{title}</a></p>{html}"#);
+
+    let tags: Vec<Value> = post
+        .tags
+        .iter()
+        .map(|t| json!({"type": "Hashtag", "name": format!("#{t}")}))
+        .collect();
+
+    Ok(json!({
+        "id": format!("{url}/activity"),
+        "type": "Create",
+        "actor": actor_id,
+        "published": published,
+        "to": [AP_PUBLIC],
+        "object": {
+            "id": url,
+            "type": "Note",
+            "name": post.title,
+            "url": url,
+            "attributedTo": actor_id,
+            "published": published,
+            "content": content,
+            "tag": tags,
+            "to": [AP_PUBLIC],
+        },
+    }))
+}
+
+fn outbox_json(domain: &str, iid: u32, items: Vec<Value>) -> Value {
+    json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{domain}/inn/{iid}/outbox"),
+        "type": "OrderedCollection",
+        "totalItems": items.len(),
+        "orderedItems": items,
+    })
+}
+
+/// url params: `/.well-known/webfinger`
+#[derive(Deserialize)]
+pub(crate) struct ParamsWebfinger {
+    resource: String,
+}
+
+/// `GET /.well-known/webfinger?resource=acct:inn_name@host`
+pub(crate) async fn webfinger(
+    Query(params): Query<ParamsWebfinger>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let domain = &site_config.domain;
+    let name = acct_name(domain, &params.resource).ok_or(AppError::NotFound)?;
+    let iid = get_id_by_name(&DB, "inn_names", name)?.ok_or(AppError::NotFound)?;
+
+    let jrd = webfinger_json(domain, &params.resource, iid);
+    Ok(ap_response("application/jrd+json", &jrd))
+}
+
+/// The name of an `acct:name@host` resource, if `host` is the one of `domain`
+fn acct_name<'a>(domain: &str, resource: &'a str) -> Option<&'a str> {
+    let host = domain.split_once("://").map_or(domain, |(_, host)| host);
+    let (name, resource_host) = resource.strip_prefix("acct:")?.rsplit_once('@')?;
+    (resource_host == host).then_some(name)
+}
+
+fn webfinger_json(domain: &str, resource: &str, iid: u32) -> Value {
+    json!({
+        "subject": resource,
+        "aliases": [format!("{domain}/inn/{iid}")],
+        "links": [
+            {
+                "rel": "self",
+                "type": AP_CONTENT_TYPE,
+                "href": format!("{domain}/inn/{iid}/actor"),
+            },
+            {
+                "rel": "http://webfinger.net/rel/profile-page",
+                "type": "text/html",
+                "href": format!("{domain}/inn/{iid}"),
+            },
+        ],
+    })
+}
+
+fn ap_response(content_type: &'static str, value: &Value) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
+    (headers, value.to_string())
+}
+
+/// get inn by iid or inn name, only inns which are not hidden are exposed.
+fn get_inn(i: &str) -> Result<Inn, AppError> {
+    let iid = match i.parse::<u32>() {
+        Ok(iid) => iid,
+        Err(_) => get_id_by_name(&DB, "inn_names", i)?.ok_or(AppError::NotFound)?,
+    };
+    let inn: Inn = get_one(&DB, "inns", iid)?;
+    if !inn.is_open_access() {
+        return Err(AppError::NotFound);
+    }
+    Ok(inn)
+}
+
+/// Get the public key of the inn in PEM format. The RSA key pair is generated on first use
+/// and stored in `inn_keys` as pkcs8.
+fn inn_public_key_pem(db: &Db, iid: u32) -> Result<String, AppError> {
+    let inn_keys_tree = db.open_tree("inn_keys")?;
+    let k = u32_to_ivec(iid);
+    if !inn_keys_tree.contains_key(&k)? {
+        let key = RsaPrivateKey::new(&mut OsRng, RSA_BITS)
+            .map_err(|e| AppError::Custom(format!("Failed to generate key pair: {e}")))?;
+        let pkcs8 = key
+            .to_pkcs8_der()
+            .map_err(|e| AppError::Custom(format!("Failed to encode key pair: {e}")))?;
+        // another request may have generated the key pair at the same time
+        let _ =
+            inn_keys_tree.compare_and_swap(&k, None as Option<&[u8]>, Some(pkcs8.as_bytes()))?;
+    }
+
+    let pkcs8 = inn_keys_tree.get(&k)?.ok_or(AppError::NotFound)?;
+    let key = RsaPrivateKey::from_pkcs8_der(&pkcs8)
+        .map_err(|e| AppError::Custom(format!("Invalid key pair of inn {iid}: {e}")))?;
+    RsaPublicKey::from(&key)
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(|e| AppError::Custom(format!("Failed to encode public key of inn {iid}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::PostContent;
+    use rsa::pkcs8::DecodePublicKey;
+
+    const DOMAIN: &str = "https://example.com";
+
+    #[test]
+    fn test_actor_json() {
+        let inn = Inn {
+            iid: 3,
+            inn_name: "rust lang".into(),
+            about: "about".into(),
+            description: String::new(),
+            topics: vec![],
+            inn_type: 0,
+            early_birds: 0,
+            created_at: 0,
+            limit_edit_seconds: 0,
+        };
+        let actor = actor_json(DOMAIN, &inn, "pem");
+        assert_eq!(actor["id"], "https://example.com/inn/3/actor");
+        assert_eq!(actor["type"], "Group");
+        assert_eq!(actor["preferredUsername"], "rust_lang");
+        assert_eq!(actor["outbox"], "https://example.com/inn/3/outbox");
+        assert_eq!(actor["inbox"], "https://example.com/inn/3/inbox");
+        assert_eq!(
+            actor["publicKey"]["id"],
+            "https://example.com/inn/3/actor#main-key"
+        );
+        assert_eq!(actor["publicKey"]["publicKeyPem"], "pem");
+    }
+
+    #[test]
+    fn test_outbox_json() {
+        let post = Post {
+            pid: 7,
+            uid: 1,
+            iid: 3,
+            title: "hello".into(),
+            tags: vec!["rust".into()],
+            content: PostContent::Markdown(String::new()),
+            created_at: 0,
+            status: PostStatus::Normal,
+        };
+        let actor_id = "https://example.com/inn/3/actor";
+        let activity = create_activity(DOMAIN, actor_id, &post, "<p>hi</p>").unwrap();
+        assert_eq!(activity["type"], "Create");
+        assert_eq!(activity["published"], "1970-01-01T00:00:00Z");
+        let note = &activity["object"];
+        assert_eq!(note["id"], "https://example.com/post/3/7");
+        assert_eq!(note["attributedTo"], actor_id);
+        assert_eq!(
+            note["content"],
+            r#"<p><a href="https://example.com/post/3/7">hello</a></p><p>hi</p>"#
+        );
+        assert_eq!(note["tag"][0]["name"], "#rust");
+
+        let post = Post {
+            title: "<b>a & b</b>".into(),
+            ..post
+        };
+        let activity = create_activity(DOMAIN, actor_id, &post, "").unwrap();
+        assert_eq!(
+            activity["object"]["content"],
+            r#"<p><a href="https://example.com/post/3/7">&#60;b&#62;a &#38; b&#60;/b&#62;</a></p>"#
+        );
+        assert_eq!(activity["object"]["name"], "<b>a & b</b>");
+
+        let outbox = outbox_json(DOMAIN, 3, vec![activity]);
+        assert_eq!(outbox["type"], "OrderedCollection");
+        assert_eq!(outbox["totalItems"], 1);
+
+        let post = Post {
+            created_at: i64::MAX,
+            ..post
+        };
+        assert!(create_activity(DOMAIN, actor_id, &post, "").is_err());
+    }
+
+    #[test]
+    fn test_webfinger_json() {
+        assert_eq!(acct_name(DOMAIN, "acct:rust@example.com"), Some("rust"));
+        assert_eq!(acct_name(DOMAIN, "acct:rust@other.com"), None);
+        assert_eq!(acct_name(DOMAIN, "rust@example.com"), None);
+
+        let jrd = webfinger_json(DOMAIN, "acct:rust@example.com", 3);
+        assert_eq!(jrd["subject"], "acct:rust@example.com");
+        assert_eq!(jrd["links"][0]["rel"], "self");
+        assert_eq!(jrd["links"][0]["type"], AP_CONTENT_TYPE);
+        assert_eq!(jrd["links"][0]["href"], "https://example.com/inn/3/actor");
+    }
+
+    #[test]
+    fn test_inn_public_key_pem() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let pem = inn_public_key_pem(&db, 3).unwrap();
+        assert!(RsaPublicKey::from_public_key_pem(&pem).is_ok());
+        // the key pair is kept
+        assert_eq!(inn_public_key_pem(&db, 3).unwrap(), pem);
+    }
+}
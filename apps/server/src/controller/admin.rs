@@ -1,14 +1,20 @@
 use super::{
-    db_utils::{get_range, ivec_to_u32, set_one_with_key, u8_slice_to_u32, IterType},
-    fmt::{clean_html, ts_to_date},
+    audit::{add_audit_entry, audit_entries, verify_chain},
+    db_utils::{
+        get_count, get_count_by_prefix, get_one, get_range, ivec_to_u32, set_one_with_key,
+        u8_slice_to_u32, IterType,
+    },
+    fmt::{clean_html, ts_to_date, RenderConfig},
     inn::ParamsTag,
-    meta_handler::{PageData, ParamsPage},
+    meta_handler::{recent_errors, PageData, ParamsPage},
     user::Role,
-    Claim, Feed, FormPost, Item, SiteConfig,
+    Claim, Feed, FormPost, Invite, Item, SiteConfig,
 };
 use crate::{
+    config::{RegistrationMode, CONFIG},
     controller::{filters, Comment, Inn, Post, Solo, User},
     error::AppError,
+    telemetry::{log_filter, set_log_filter},
     DB,
 };
 use axum::{
@@ -19,9 +25,15 @@ use axum::{
 use axum_extra::{headers::Cookie, TypedHeader};
 use axum_garde::WithValidation;
 use bincode::config::standard;
+use garde::Validate;
+use jiff::Timestamp;
+use nanoid::nanoid;
 use rinja_axum::{into_response, Template};
 use serde::Deserialize;
+use serde_json::Value;
+use sled::Db;
 use snailquote::unescape;
+use tracing::info;
 
 /// Page data: `admin_view.html`
 #[derive(Template)]
@@ -102,6 +114,11 @@ pub(crate) async fn admin_view(
                         ones.push(format!("{key}: {v}"));
                     };
                 }
+                "invites" => {
+                    let key = String::from_utf8_lossy(&k);
+                    let (one, _): (Invite, usize) = bincode::decode_from_slice(&v, standard())?;
+                    ones.push(format!("{key}: {one:?}"));
+                }
                 "users" => {
                     let key = ivec_to_u32(&k);
                     let (mut one, _): (User, usize) = bincode::decode_from_slice(&v, standard())?;
@@ -289,6 +306,12 @@ pub(crate) async fn admin_view(
                     let lang = String::from_utf8_lossy(&v);
                     ones.push(format!("{uid}: {lang}"));
                 }
+                "dead_links" => {
+                    let pid = u8_slice_to_u32(&k[0..4]);
+                    let url = String::from_utf8_lossy(&k[4..]);
+                    let reason = String::from_utf8_lossy(&v);
+                    ones.push(format!("{pid} - {url}: {reason}"));
+                }
                 "tan" => {
                     let id = String::from_utf8_lossy(&k);
                     ones.push(format!("{id}: {:?}", v));
@@ -347,6 +370,129 @@ pub(crate) async fn admin(
     Ok(into_response(&page_admin))
 }
 
+/// Page data: `admin_dashboard.html`
+#[derive(Template)]
+#[template(path = "admin_dashboard.html")]
+struct PageAdminDashboard<'a> {
+    page_data: PageData<'a>,
+    dashboard: DashboardData,
+}
+
+struct DashboardData {
+    users: usize,
+    inns: usize,
+    posts: usize,
+    solos: usize,
+    imgs: usize,
+    db_size: String,
+    recent_users: Vec<(u32, String, String)>,
+    recent_posts: Vec<(u32, u32, String, String)>,
+    recent_errors: Vec<(String, String)>,
+}
+
+/// How many recent users and posts are shown on the dashboard
+const DASHBOARD_RECENT: usize = 10;
+
+impl DashboardData {
+    /// Only counters and the last few ids are read, so this is cheap on a big database.
+    fn get(db: &Db) -> Result<Self, AppError> {
+        let users = get_count(db, "default", "users_count")?;
+        let posts = get_count(db, "default", "posts_count")?;
+
+        let mut recent_users = Vec::with_capacity(DASHBOARD_RECENT);
+        for uid in (1..=users as u32).rev().take(DASHBOARD_RECENT) {
+            if let Ok(user) = get_one::<User>(db, "users", uid) {
+                recent_users.push((user.uid, user.username, ts_to_date(user.created_at)));
+            }
+        }
+
+        let mut recent_posts = Vec::with_capacity(DASHBOARD_RECENT);
+        for pid in (1..=posts as u32).rev().take(DASHBOARD_RECENT) {
+            if let Ok(post) = get_one::<Post>(db, "posts", pid) {
+                recent_posts.push((post.iid, post.pid, post.title, ts_to_date(post.created_at)));
+            }
+        }
+
+        let db_size = db.size_on_disk().unwrap_or_default();
+
+        Ok(Self {
+            users,
+            inns: get_count(db, "default", "inns_count")?,
+            posts,
+            solos: get_count(db, "default", "solos_count")?,
+            imgs: get_count(db, "default", "imgs_count")?,
+            db_size: format!("{:.1} MB", db_size as f64 / 1024.0 / 1024.0),
+            recent_users,
+            recent_posts,
+            recent_errors: recent_errors()
+                .into_iter()
+                .map(|(ts, error)| (ts_to_date(ts), error))
+                .collect(),
+        })
+    }
+}
+
+/// `GET /admin/dashboard`
+pub(crate) async fn admin_dashboard(
+    cookie: Option<TypedHeader<Cookie>>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let dashboard = DashboardData::get(&DB)?;
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("Dashboard", &site_config, Some(claim), has_unread);
+    let page_admin_dashboard = PageAdminDashboard {
+        page_data,
+        dashboard,
+    };
+
+    Ok(into_response(&page_admin_dashboard))
+}
+
+/// `GET /admin/loglevel`
+///
+/// The current log filter directive as plain text.
+pub(crate) async fn admin_loglevel(
+    cookie: Option<TypedHeader<Cookie>>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    log_filter().ok_or(AppError::NotFound)
+}
+
+/// `PUT /admin/loglevel`
+///
+/// Replace the log filter with the directive in the body, like `info,freedit=debug`. The change
+/// is lost on restart.
+pub(crate) async fn admin_loglevel_put(
+    cookie: Option<TypedHeader<Cookie>>,
+    body: String,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let directive = body.trim();
+    set_log_filter(directive).map_err(AppError::Custom)?;
+    info!(uid = claim.uid, "log filter set to {directive}");
+    add_audit_entry(&DB, claim.uid, "loglevel", directive)?;
+
+    Ok(directive.to_owned())
+}
+
 /// `POST /admin`
 pub(crate) async fn admin_post(
     cookie: Option<TypedHeader<Cookie>>,
@@ -375,10 +521,31 @@ pub(crate) async fn admin_post(
     site_config.captcha_difficulty = clean_html(&site_config.captcha_difficulty);
     site_config.captcha_name = clean_html(&site_config.captcha_name);
 
+    let render_config = RenderConfig::from_site_config(&site_config)
+        .map_err(AppError::Custom)?
+        .with_custom_emojis(&DB)?;
+
+    let changed = changed_fields(&SiteConfig::get(&DB)?, &site_config);
     set_one_with_key(&DB, "__sled__default", "site_config", &site_config)?;
+    render_config.set();
+    add_audit_entry(&DB, claim.uid, "site_config", &changed)?;
     Ok(Redirect::to("/admin"))
 }
 
+/// Names of the settings that differ, like `per_page, lang`
+fn changed_fields(old: &SiteConfig, new: &SiteConfig) -> String {
+    let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return String::new();
+    };
+    new.iter()
+        .filter(|(k, v)| old.get(k.as_str()) != Some(*v))
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 impl Default for SiteConfig {
     fn default() -> Self {
         SiteConfig {
@@ -399,6 +566,40 @@ impl Default for SiteConfig {
             home_page: 0,
             spam_regex: None,
             lang: "en".into(),
+            html_allowed_tags: String::new(),
+            html_allowed_attributes: String::new(),
+            html_allowed_url_schemes: String::new(),
+            autolink: false,
+            nofollow: false,
+            allow_raw_html: true,
+            emoji_shortcodes: false,
+            shift_headings: false,
+            image_proxy: false,
+            maintenance: false,
+            csp_script_src: "'self'".into(),
+            csp_style_src: "'self' 'unsafe-inline'".into(),
+            csp_img_src: "'self' data: https:".into(),
+            csp_connect_src: "'self'".into(),
+            csp_report_only: false,
+            csp_report_uri: String::new(),
+            relative_time: false,
+            strip_tracking_params: false,
+            logo: String::new(),
+            favicon: String::new(),
+            accent_color: String::new(),
+            video_embed_hosts: String::new(),
+            excerpt_chars: 500,
+            word_filter: String::new(),
+            word_filter_replacement: "***".into(),
+            banned_usernames: String::new(),
+            heading_anchors: false,
+            inn_post_limit: 0,
+            inn_post_window_secs: 600,
+            issue_refs: false,
+            auto_summary: false,
+            summary_min_chars: 2000,
+            embed_providers: String::new(),
+            backlinks: false,
         }
     }
 }
@@ -463,3 +664,302 @@ pub(crate) async fn admin_gallery(
 
     Ok(into_response(&page_gallery))
 }
+
+struct OutInvite {
+    code: String,
+    uses_remaining: u32,
+    used: usize,
+    expires_at: String,
+    created_by: u32,
+    created_at: String,
+}
+
+/// Page data: `admin_invites.html`
+#[derive(Template)]
+#[template(path = "admin_invites.html")]
+struct PageAdminInvites<'a> {
+    page_data: PageData<'a>,
+    invites: Vec<OutInvite>,
+    invite_mode: bool,
+}
+
+/// `GET /admin/invites`
+pub(crate) async fn admin_invites(
+    cookie: Option<TypedHeader<Cookie>>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let mut invites = Vec::new();
+    for i in DB.open_tree("invites")?.iter().rev() {
+        let (k, v) = i?;
+        let (invite, _): (Invite, usize) = bincode::decode_from_slice(&v, standard())?;
+        let mut prefix = k.to_vec();
+        prefix.push(b'#');
+        let used = get_count_by_prefix(&DB, "invite_uses", &prefix)?;
+        invites.push(OutInvite {
+            code: String::from_utf8_lossy(&k).to_string(),
+            uses_remaining: invite.uses_remaining,
+            used,
+            expires_at: invite.expires_at.map(ts_to_date).unwrap_or_default(),
+            created_by: invite.created_by,
+            created_at: ts_to_date(invite.created_at),
+        });
+    }
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("Admin invites", &site_config, Some(claim), has_unread);
+    let page_admin_invites = PageAdminInvites {
+        page_data,
+        invites,
+        invite_mode: CONFIG.registration_mode == RegistrationMode::Invite,
+    };
+
+    Ok(into_response(&page_admin_invites))
+}
+
+/// Form data: `/admin/invites`
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormInvite {
+    #[garde(range(min = 1, max = 10000))]
+    uses: u32,
+    /// 0 means the code never expires
+    #[garde(range(max = 3650))]
+    expire_days: i64,
+}
+
+/// `POST /admin/invites`
+pub(crate) async fn admin_invites_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    WithValidation(input): WithValidation<Form<FormInvite>>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let created_at = Timestamp::now().as_second();
+    let expires_at = if input.expire_days > 0 {
+        Some(created_at + input.expire_days * 24 * 3600)
+    } else {
+        None
+    };
+    let invite = Invite {
+        uses_remaining: input.uses,
+        expires_at,
+        created_by: claim.uid,
+        created_at,
+    };
+
+    let code = nanoid!(12);
+    set_one_with_key(&DB, "invites", &code, &invite)?;
+    add_audit_entry(&DB, claim.uid, "invite", &code)?;
+
+    Ok(Redirect::to("/admin/invites"))
+}
+
+/// Page data: `admin_emojis.html`
+#[derive(Template)]
+#[template(path = "admin_emojis.html")]
+struct PageAdminEmojis<'a> {
+    page_data: PageData<'a>,
+    emojis: Vec<(String, String)>,
+    emoji_shortcodes: bool,
+}
+
+/// `GET /admin/emojis`
+pub(crate) async fn admin_emojis(
+    cookie: Option<TypedHeader<Cookie>>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let mut emojis = Vec::new();
+    for i in DB.open_tree("custom_emojis")? {
+        let (k, v) = i?;
+        emojis.push((
+            String::from_utf8_lossy(&k).into_owned(),
+            String::from_utf8_lossy(&v).into_owned(),
+        ));
+    }
+
+    let emoji_shortcodes = site_config.emoji_shortcodes;
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("Admin emojis", &site_config, Some(claim), has_unread);
+    let page_admin_emojis = PageAdminEmojis {
+        page_data,
+        emojis,
+        emoji_shortcodes,
+    };
+
+    Ok(into_response(&page_admin_emojis))
+}
+
+/// Form data: `/admin/emojis`
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormEmoji {
+    #[garde(length(min = 1, max = 32), custom(is_shortcode))]
+    shortcode: String,
+    /// empty url removes the emoji
+    #[garde(length(max = 256), custom(is_emoji_url))]
+    url: String,
+}
+
+fn is_shortcode(value: &str, _: &()) -> garde::Result {
+    if value
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '+' | '-'))
+    {
+        Ok(())
+    } else {
+        Err(garde::Error::new("only a-z, 0-9, _, + and - are allowed"))
+    }
+}
+
+fn is_emoji_url(value: &str, _: &()) -> garde::Result {
+    if value.is_empty() || value.starts_with('/') || value.starts_with("https://") {
+        Ok(())
+    } else {
+        Err(garde::Error::new("url must start with / or https://"))
+    }
+}
+
+/// `POST /admin/emojis`
+pub(crate) async fn admin_emojis_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    WithValidation(input): WithValidation<Form<FormEmoji>>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let tree = DB.open_tree("custom_emojis")?;
+    if input.url.is_empty() {
+        tree.remove(&input.shortcode)?;
+    } else {
+        tree.insert(&input.shortcode, input.url.as_str())?;
+    }
+    add_audit_entry(&DB, claim.uid, "emoji", &input.shortcode)?;
+
+    RenderConfig::from_site_config(&site_config)
+        .map_err(AppError::Custom)?
+        .with_custom_emojis(&DB)?
+        .set();
+
+    Ok(Redirect::to("/admin/emojis"))
+}
+
+struct OutAuditEntry {
+    id: u32,
+    uid: u32,
+    created_at: String,
+    action: String,
+    target: String,
+}
+
+/// Page data: `admin_audit.html`
+#[derive(Template)]
+#[template(path = "admin_audit.html")]
+struct PageAdminAudit<'a> {
+    page_data: PageData<'a>,
+    entries: Vec<OutAuditEntry>,
+    /// whether [verify_chain](super::audit::verify_chain) was run
+    verified: bool,
+    /// first entry that fails it
+    broken_at: Option<u32>,
+    uid: String,
+    from: String,
+    to: String,
+    anchor: usize,
+    n: usize,
+}
+
+/// url params: `admin_audit.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsAdminAudit {
+    /// empty for all actors
+    uid: Option<String>,
+    /// dates like `2024-01-31`, both included
+    from: Option<String>,
+    to: Option<String>,
+    anchor: Option<usize>,
+    /// check the whole chain, which reads every entry
+    verify: Option<bool>,
+}
+
+/// `GET /admin/audit`
+pub(crate) async fn admin_audit(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsAdminAudit>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let from = params.from.unwrap_or_default();
+    let to = params.to.unwrap_or_default();
+    let uid = params.uid.unwrap_or_default();
+    let actor = uid.trim().parse::<u32>().ok();
+    let verified = params.verify.unwrap_or_default();
+    let broken_at = if verified { verify_chain(&DB)? } else { None };
+
+    let mut entries = Vec::with_capacity(n);
+    for i in audit_entries(&DB)? {
+        let (id, entry) = i?;
+        let date = ts_to_date(entry.created_at);
+        if actor.is_some_and(|actor| actor != entry.uid)
+            || (!from.is_empty() && date < from)
+            || (!to.is_empty() && date > to)
+        {
+            continue;
+        }
+        entries.push(OutAuditEntry {
+            id,
+            uid: entry.uid,
+            created_at: Timestamp::from_second(entry.created_at)
+                .unwrap()
+                .strftime("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            action: entry.action,
+            target: entry.target,
+        });
+        if entries.len() >= anchor + n {
+            break;
+        }
+    }
+    let entries = entries.into_iter().skip(anchor).collect();
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("Admin audit", &site_config, Some(claim), has_unread);
+    let page_admin_audit = PageAdminAudit {
+        page_data,
+        entries,
+        verified,
+        broken_at,
+        uid,
+        from,
+        to,
+        anchor,
+        n,
+    };
+
+    Ok(into_response(&page_admin_audit))
+}
@@ -0,0 +1,193 @@
+//! Append-only log of privileged actions, like site settings, role changes or backups. Each
+//! entry holds the hash of the one before, so editing or removing an entry breaks the chain
+//! from there on. The number of entries and the hash of the newest one are kept in the default
+//! tree as `audit_count` and `audit_head`, so removing the newest entries is noticed too, see
+//! [verify_chain].
+
+use super::db_utils::{ivec_to_u32, u32_to_ivec};
+use crate::{config::CONFIG, error::AppError};
+use bincode::{config::standard, Decode, Encode};
+use jiff::Timestamp;
+use ring::digest::{digest, SHA256};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError, Transactional},
+    Db,
+};
+use std::sync::{LazyLock, Mutex};
+
+/// Entries are appended one at a time, so two of them can't have the same previous hash, and
+/// not while the chain is verified
+static APPEND: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// value of `audit_log`
+#[derive(Encode, Decode, Debug, Clone)]
+pub(super) struct AuditEntry {
+    /// 0 for commands run on the server
+    pub(super) uid: u32,
+    pub(super) created_at: i64,
+    pub(super) action: String,
+    pub(super) target: String,
+    prev_hash: Vec<u8>,
+    pub(super) hash: Vec<u8>,
+}
+
+impl AuditEntry {
+    /// SHA-256 of the previous hash and all the fields of entry `id`
+    fn compute_hash(&self, id: u32) -> Vec<u8> {
+        let mut data = self.prev_hash.clone();
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&self.uid.to_be_bytes());
+        data.extend_from_slice(&self.created_at.to_be_bytes());
+        for field in [&self.action, &self.target] {
+            data.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            data.extend_from_slice(field.as_bytes());
+        }
+        digest(&SHA256, &data).as_ref().to_vec()
+    }
+}
+
+/// Append an entry to `audit_log`, unless `audit_log` is turned off in the config.
+pub fn add_audit_entry(db: &Db, uid: u32, action: &str, target: &str) -> Result<(), AppError> {
+    if !CONFIG.audit_log {
+        return Ok(());
+    }
+    append(db, uid, action, target)
+}
+
+fn append(db: &Db, uid: u32, action: &str, target: &str) -> Result<(), AppError> {
+    let _guard = APPEND.lock().unwrap();
+    let tree = db.open_tree("audit_log")?;
+    let created_at = Timestamp::now().as_second();
+    (&**db, &tree)
+        .transaction(|(default_tree, tree)| {
+            let id = default_tree
+                .get("audit_count")?
+                .map_or(0, |v| ivec_to_u32(&v))
+                + 1;
+            let prev_hash = default_tree
+                .get("audit_head")?
+                .map_or_else(Vec::new, |v| v.to_vec());
+            let mut entry = AuditEntry {
+                uid,
+                created_at,
+                action: action.to_owned(),
+                target: target.to_owned(),
+                prev_hash,
+                hash: vec![],
+            };
+            entry.hash = entry.compute_hash(id);
+
+            let v = bincode::encode_to_vec(&entry, standard())
+                .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            tree.insert(u32_to_ivec(id), v)?;
+            default_tree.insert("audit_count", u32_to_ivec(id))?;
+            default_tree.insert("audit_head", entry.hash)?;
+            Ok(())
+        })
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+}
+
+/// Entries from the newest, with their ids
+pub(super) fn audit_entries(
+    db: &Db,
+) -> Result<impl Iterator<Item = Result<(u32, AuditEntry), AppError>>, AppError> {
+    let iter = db.open_tree("audit_log")?.iter().rev();
+    Ok(iter.map(|i| {
+        let (k, v) = i?;
+        let (entry, _): (AuditEntry, usize) = bincode::decode_from_slice(&v, standard())?;
+        Ok((ivec_to_u32(&k), entry))
+    }))
+}
+
+/// Check the hash chain from the first entry, then that it ends with `audit_count` and
+/// `audit_head`. Returns the id of the first entry that was edited, or whose previous entry was
+/// edited or removed, or the id after the last entry if the newest ones were removed.
+///
+/// It reads the whole log, so only run it when asked to.
+pub(super) fn verify_chain(db: &Db) -> Result<Option<u32>, AppError> {
+    let _guard = APPEND.lock().unwrap();
+    let mut prev_hash = vec![];
+    let mut last_id = 0;
+    for i in db.open_tree("audit_log")?.iter() {
+        let (k, v) = i?;
+        let id = ivec_to_u32(&k);
+        let Ok((entry, _)) = bincode::decode_from_slice::<AuditEntry, _>(&v, standard()) else {
+            return Ok(Some(id));
+        };
+        if entry.prev_hash != prev_hash || entry.compute_hash(id) != entry.hash {
+            return Ok(Some(id));
+        }
+        prev_hash = entry.hash;
+        last_id = id;
+    }
+
+    let count = db.get("audit_count")?.map_or(0, |v| ivec_to_u32(&v));
+    let head = db.get("audit_head")?.map_or_else(Vec::new, |v| v.to_vec());
+    if last_id != count || prev_hash != head {
+        return Ok(Some(last_id + 1));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_chain() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        append(&db, 1, "site_config", "").unwrap();
+        append(&db, 1, "role", "user 2: Banned").unwrap();
+        append(&db, 0, "backup", "").unwrap();
+        assert_eq!(verify_chain(&db).unwrap(), None);
+
+        let entries: Vec<_> = audit_entries(&db).unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].0, 3);
+        assert_eq!(entries[1].1.action, "role");
+
+        // an edited entry, even with its own hash updated, breaks the chain
+        let tree = db.open_tree("audit_log").unwrap();
+        let (id, mut entry) = entries[1].clone();
+        entry.target = "user 2: Standard".into();
+        let v = bincode::encode_to_vec(&entry, standard()).unwrap();
+        tree.insert(u32_to_ivec(id), v).unwrap();
+        assert_eq!(verify_chain(&db).unwrap(), Some(2));
+
+        entry.hash = entry.compute_hash(id);
+        let v = bincode::encode_to_vec(&entry, standard()).unwrap();
+        tree.insert(u32_to_ivec(id), v).unwrap();
+        assert_eq!(verify_chain(&db).unwrap(), Some(3));
+
+        // so does a removed entry
+        let (id, entry) = entries[1].clone();
+        let v = bincode::encode_to_vec(&entry, standard()).unwrap();
+        tree.insert(u32_to_ivec(id), v).unwrap();
+        assert_eq!(verify_chain(&db).unwrap(), None);
+        tree.remove(u32_to_ivec(1)).unwrap();
+        assert_eq!(verify_chain(&db).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn test_verify_chain_truncated() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        assert_eq!(verify_chain(&db).unwrap(), None);
+        for action in ["site_config", "role", "backup"] {
+            append(&db, 1, action, "").unwrap();
+        }
+        assert_eq!(verify_chain(&db).unwrap(), None);
+
+        // the newest entries are removed, leaving a valid chain
+        let tree = db.open_tree("audit_log").unwrap();
+        tree.remove(u32_to_ivec(3)).unwrap();
+        tree.remove(u32_to_ivec(2)).unwrap();
+        assert_eq!(verify_chain(&db).unwrap(), Some(2));
+
+        // the count alone is not enough to hide it
+        db.insert("audit_count", u32_to_ivec(1)).unwrap();
+        assert_eq!(verify_chain(&db).unwrap(), Some(2));
+    }
+}
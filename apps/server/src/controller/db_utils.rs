@@ -41,7 +41,7 @@ where
     get_one_by_key(db, tree_name, u32_to_ivec(id))
 }
 
-fn get_one_by_key<T, K>(db: &Db, tree_name: &str, key: K) -> Result<T, AppError>
+pub(super) fn get_one_by_key<T, K>(db: &Db, tree_name: &str, key: K) -> Result<T, AppError>
 where
     T: Decode,
     K: AsRef<[u8]>,
@@ -415,3 +415,49 @@ pub(super) fn i64_to_ivec(number: i64) -> IVec {
 pub(super) fn u8_slice_to_i64(bytes: &[u8]) -> i64 {
     i64::from_be_bytes(bytes.try_into().unwrap())
 }
+
+const BASE62: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// encode `u32` as base62, used by short permalinks `/p/:code`
+pub(super) fn base62_encode(mut number: u32) -> String {
+    let mut out = Vec::with_capacity(6);
+    loop {
+        out.push(BASE62[(number % 62) as usize]);
+        number /= 62;
+        if number == 0 {
+            break;
+        }
+    }
+    out.reverse();
+    String::from_utf8(out).unwrap()
+}
+
+/// decode base62 to `u32`. Returns `None` for invalid chars or overflow.
+pub(super) fn base62_decode(s: &str) -> Option<u32> {
+    if s.is_empty() {
+        return None;
+    }
+    s.bytes().try_fold(0u32, |acc, b| {
+        let digit = BASE62.iter().position(|c| *c == b)? as u32;
+        acc.checked_mul(62)?.checked_add(digit)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base62() {
+        assert_eq!(base62_encode(0), "0");
+        assert_eq!(base62_encode(61), "z");
+        assert_eq!(base62_encode(62), "10");
+        assert_eq!(base62_encode(u32::MAX), "4gfFC3");
+        for n in [0, 1, 61, 62, 3843, 3844, 123456, u32::MAX] {
+            assert_eq!(base62_decode(&base62_encode(n)), Some(n));
+        }
+        assert_eq!(base62_decode(""), None);
+        assert_eq!(base62_decode("a-b"), None);
+        assert_eq!(base62_decode("4gfFC4"), None);
+    }
+}
@@ -0,0 +1,268 @@
+//! Periodic check of the outbound links of posts. Broken links are recorded in `dead_links`
+//! and shown to the author and mods on the post page. Each url is checked at most once every
+//! [RECHECK_SECS], at most one request per [HOST_DELAY] to the same host, and only if the
+//! host's `robots.txt` allows it.
+
+use super::{
+    db_utils::{get_one, ivec_to_u32, u32_to_ivec},
+    feed::CLIENT,
+    image_proxy::is_fetchable,
+    Post, PostContent,
+};
+use crate::error::AppError;
+use bincode::{config::standard, Decode, Encode};
+use jiff::Timestamp;
+use pulldown_cmark::{Event, Parser, Tag};
+use reqwest::{header::USER_AGENT, Client, StatusCode, Url};
+use sled::{Batch, Db};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+use tokio::time::{sleep_until, Instant};
+use tracing::info;
+
+/// A url is checked again after a week
+const RECHECK_SECS: i64 = 7 * 24 * 3600;
+
+/// Minimum delay between two requests to the same host
+const HOST_DELAY: Duration = Duration::from_secs(2);
+
+const LINK_CHECKER: &str = "freedit-linkcheck";
+
+/// value of `link_status`
+#[derive(Encode, Decode, Debug)]
+struct LinkStatus {
+    checked_at: i64,
+    /// why the link is broken, `None` if it works or its state is unknown
+    dead: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum LinkState {
+    Alive,
+    Dead(String),
+    /// blocked, rate limited, timed out or a server error, which may not last
+    Unknown,
+}
+
+#[derive(Default)]
+struct Host {
+    last_request: Option<Instant>,
+    /// `Disallow` rules of robots.txt, `None` until fetched
+    disallow: Option<Vec<String>>,
+}
+
+/// Check the outbound links of all posts and update `dead_links`.
+pub async fn cron_dead_links(db: &Db) -> Result<(), AppError> {
+    let link_status_tree = db.open_tree("link_status")?;
+    let dead_links_tree = db.open_tree("dead_links")?;
+    let mut hosts = HashMap::new();
+    let (mut checked, mut dead) = (0, 0);
+
+    let pids = db
+        .open_tree("posts")?
+        .iter()
+        .keys()
+        .map(|k| k.map(|k| ivec_to_u32(&k)))
+        .collect::<Result<Vec<_>, _>>()?;
+    for pid in pids {
+        let post: Post = get_one(db, "posts", pid)?;
+        let PostContent::Markdown(ref md) = post.content else {
+            continue;
+        };
+
+        let prefix = u32_to_ivec(pid);
+        let mut batch = Batch::default();
+        for k in dead_links_tree.scan_prefix(&prefix).keys() {
+            batch.remove(k?);
+        }
+
+        for url in outbound_links(md) {
+            let cached = match link_status_tree.get(&url)? {
+                Some(v) => Some(bincode::decode_from_slice::<LinkStatus, _>(&v, standard())?.0),
+                None => None,
+            };
+            let now = Timestamp::now().as_second();
+            let status = match cached {
+                Some(status) if now - status.checked_at < RECHECK_SECS => status,
+                _ => {
+                    let Some(state) = check_politely(&mut hosts, &url).await else {
+                        continue;
+                    };
+                    let status = LinkStatus {
+                        checked_at: now,
+                        dead: match state {
+                            LinkState::Dead(reason) => Some(reason),
+                            LinkState::Alive | LinkState::Unknown => None,
+                        },
+                    };
+                    link_status_tree.insert(&url, bincode::encode_to_vec(&status, standard())?)?;
+                    checked += 1;
+                    status
+                }
+            };
+
+            if let Some(reason) = status.dead {
+                batch.insert([&prefix[..], url.as_bytes()].concat(), reason.as_bytes());
+                dead += 1;
+            }
+        }
+        dead_links_tree.apply_batch(batch)?;
+    }
+
+    info!(checked, dead, "dead link check done");
+    Ok(())
+}
+
+/// The `http(s)` links of a post, skipping the local network.
+fn outbound_links(md: &str) -> BTreeSet<String> {
+    Parser::new(md)
+        .filter_map(|event| match event {
+            Event::Start(Tag::Link { dest_url, .. }) if is_fetchable(&dest_url) => {
+                Some(dest_url.into_string())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Check `url` after fetching robots.txt of its host, waiting [HOST_DELAY] between requests to
+/// the same host. `None` if robots.txt disallows it.
+async fn check_politely(hosts: &mut HashMap<String, Host>, url: &str) -> Option<LinkState> {
+    let parsed = Url::parse(url).ok()?;
+    let origin = parsed.origin().ascii_serialization();
+    let host = hosts.entry(origin.clone()).or_default();
+
+    if host.disallow.is_none() {
+        wait_turn(host).await;
+        let robots = match CLIENT
+            .get(format!("{origin}/robots.txt"))
+            .header(USER_AGENT, LINK_CHECKER)
+            .send()
+            .await
+        {
+            Ok(res) if res.status().is_success() => res.text().await.unwrap_or_default(),
+            _ => String::new(),
+        };
+        host.disallow = Some(robots_disallow(&robots));
+    }
+
+    let path = parsed.path();
+    if host
+        .disallow
+        .as_ref()
+        .is_some_and(|rules| rules.iter().any(|rule| path.starts_with(rule.as_str())))
+    {
+        return None;
+    }
+
+    wait_turn(host).await;
+    Some(check_link(&CLIENT, url).await)
+}
+
+async fn wait_turn(host: &mut Host) {
+    if let Some(last_request) = host.last_request {
+        sleep_until(last_request + HOST_DELAY).await;
+    }
+    host.last_request = Some(Instant::now());
+}
+
+/// `Disallow` rules of the `User-agent: *` group. Wildcards and `Allow` are not supported, so
+/// this errs on the side of not checking.
+fn robots_disallow(robots: &str) -> Vec<String> {
+    let mut rules = Vec::new();
+    let mut in_group = false;
+    let mut reading_agents = false;
+    for line in robots.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match field.trim().to_ascii_lowercase().as_str() {
+            "user-agent" => {
+                if !reading_agents {
+                    in_group = false;
+                    reading_agents = true;
+                }
+                in_group |= value == "*" || value.eq_ignore_ascii_case(LINK_CHECKER);
+            }
+            "disallow" => {
+                reading_agents = false;
+                if in_group && !value.is_empty() {
+                    rules.push(value.to_owned());
+                }
+            }
+            _ => reading_agents = false,
+        }
+    }
+    rules
+}
+
+async fn check_link(client: &Client, url: &str) -> LinkState {
+    let res = match client
+        .head(url)
+        .header(USER_AGENT, LINK_CHECKER)
+        .send()
+        .await
+    {
+        Ok(res) if res.status() == StatusCode::METHOD_NOT_ALLOWED => {
+            client
+                .get(url)
+                .header(USER_AGENT, LINK_CHECKER)
+                .send()
+                .await
+        }
+        res => res,
+    };
+    match res {
+        Ok(res) => classify(res.status()),
+        Err(e) if e.is_connect() => LinkState::Dead(e.to_string()),
+        Err(_) => LinkState::Unknown,
+    }
+}
+
+fn classify(status: StatusCode) -> LinkState {
+    if status.is_success() || status.is_redirection() {
+        LinkState::Alive
+    } else if status == StatusCode::NOT_FOUND || status == StatusCode::GONE {
+        LinkState::Dead(status.to_string())
+    } else {
+        LinkState::Unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+
+    #[tokio::test]
+    async fn test_check_link() {
+        let app = Router::new().route("/ok", get(|| async { "ok" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = Client::new();
+        assert_eq!(
+            check_link(&client, &format!("http://{addr}/ok")).await,
+            LinkState::Alive
+        );
+        assert_eq!(
+            check_link(&client, &format!("http://{addr}/missing")).await,
+            LinkState::Dead("404 Not Found".into())
+        );
+        assert_eq!(classify(StatusCode::FORBIDDEN), LinkState::Unknown);
+
+        let md = "[a](https://example.com/a) [b](http://127.0.0.1/b) <https://example.com/a>";
+        assert_eq!(
+            outbound_links(md).into_iter().collect::<Vec<_>>(),
+            vec!["https://example.com/a"]
+        );
+
+        let robots = "User-agent: googlebot\nDisallow: /\n\nUser-agent: *\nDisallow: /private # x\nDisallow:\n";
+        assert_eq!(robots_disallow(robots), vec!["/private"]);
+    }
+}
@@ -487,7 +487,7 @@ pub(crate) struct FormFeedAdd {
     is_public: bool,
 }
 
-static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+pub(super) static CLIENT: LazyLock<Client> = LazyLock::new(|| {
     let mut client = reqwest::Client::builder().timeout(Duration::from_secs(6));
     if !CONFIG.proxy.is_empty() {
         let proxy = reqwest::Proxy::all(&CONFIG.proxy).unwrap();
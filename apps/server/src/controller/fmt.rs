@@ -1,7 +1,18 @@
-use std::sync::LazyLock;
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{Arc, LazyLock, Mutex, RwLock},
+};
 
-use jiff::Timestamp;
-use pulldown_cmark::{html, CodeBlockKind, Event, Options, Tag};
+use super::{db_utils::base62_decode, filters::l10n, image_proxy, SiteConfig};
+use crate::{error::AppError, DB};
+use jiff::{tz::TimeZone, Timestamp};
+use pulldown_cmark::{
+    html, CodeBlockKind, Event, HeadingLevel, LinkType, Options, Tag, TagEnd, TextMergeStream,
+};
+use regex::{NoExpand, Regex};
+use ring::hmac;
+use sled::Db;
 use syntect::{highlighting::ThemeSet, html::highlighted_html_for_string, parsing::SyntaxSet};
 
 /// convert a `i64` timestamp to a date [`String`]
@@ -12,6 +23,34 @@ pub(super) fn ts_to_date(timestamp: i64) -> String {
         .to_string()
 }
 
+/// The date of a timestamp in the IANA time zone `tz`, like `Asia/Shanghai`. UTC if `tz` is
+/// empty or unknown.
+pub(super) fn ts_to_local_date(timestamp: i64, tz: &str) -> String {
+    let Ok(tz) = TimeZone::get(tz) else {
+        return ts_to_date(timestamp);
+    };
+    Timestamp::from_second(timestamp)
+        .unwrap()
+        .to_zoned(tz)
+        .strftime("%Y-%m-%d")
+        .to_string()
+}
+
+/// Relative time like "3 hours ago" within a week, the date in `tz` otherwise
+pub(super) fn ts_to_relative(timestamp: i64, now: i64, lang: &str, tz: &str) -> String {
+    let diff = now - timestamp;
+    let (key, n) = match diff {
+        0..60 => return l10n("just_now", lang).unwrap_or_default().to_owned(),
+        60..3600 => ("minutes_ago", diff / 60),
+        3600..86400 => ("hours_ago", diff / 3600),
+        86400..604_800 => ("days_ago", diff / 86400),
+        _ => return ts_to_local_date(timestamp, tz),
+    };
+    l10n(key, lang)
+        .unwrap_or_default()
+        .replace("{n}", &n.to_string())
+}
+
 // list of mathml tags obtained from
 // <https://www.tutorialspoint.com/mathml/mathml_all_elements.htm>
 const MATHML_TAGS: [&str; 31] = [
@@ -58,15 +97,138 @@ const MATHML_TAGS: [&str; 31] = [
 // You should have received a copy of the GNU General Public License
 // along with cmark-syntax. If not, see <http://www.gnu.org/licenses/>
 pub(super) fn md2html(md: &str) -> String {
-    let parser = pulldown_cmark::Parser::new_ext(md, Options::all());
-    let processed = SyntaxPreprocessor::new(parser);
+    let config = RENDER_CONFIG.read().unwrap().clone();
+    md2html_with(md, &config)
+}
+
+/// Like [md2html], with issue references linked to the issue tracker of the inn, if
+/// [SiteConfig] `issue_refs` is on.
+pub(super) fn md2html_in_inn(md: &str, tracker: Option<&str>) -> String {
+    let config = RENDER_CONFIG.read().unwrap().clone();
+    md2html_with_tracker(md, &config, tracker)
+}
+
+fn md2html_with(md: &str, config: &RenderConfig) -> String {
+    md2html_with_tracker(md, config, None)
+}
+
+fn md2html_with_tracker(md: &str, config: &RenderConfig, tracker: Option<&str>) -> String {
+    let escape_raw_html = config.escape_raw_html;
+    let shift_headings = config.shift_headings;
+    let parser = pulldown_cmark::Parser::new_ext(md, Options::all())
+        // the cut marker only matters for excerpts
+        .filter(|event| match event {
+            Event::Html(html) | Event::InlineHtml(html) => html.trim() != CUT_MARKER,
+            _ => true,
+        })
+        .map(move |event| match event {
+            // raw html is shown as text, before any html is generated by the renderer itself
+            Event::Html(html) | Event::InlineHtml(html) if escape_raw_html => Event::Text(html),
+            // the post title is the only h1 of a page
+            Event::Start(Tag::Heading {
+                level,
+                id,
+                classes,
+                attrs,
+            }) if shift_headings => Event::Start(Tag::Heading {
+                level: demote(level),
+                id,
+                classes,
+                attrs,
+            }),
+            Event::End(TagEnd::Heading(level)) if shift_headings => {
+                Event::End(TagEnd::Heading(demote(level)))
+            }
+            other => other,
+        });
+    let mut events = footnotes(TextMergeStream::new(SyntaxPreprocessor::new(parser)));
+    if config.emoji_shortcodes {
+        events = emoji_shortcodes(events.into_iter(), &config.custom_emojis);
+    }
+    if config.autolink {
+        events = autolink(events.into_iter());
+    }
+    if let Some(tracker) = tracker.filter(|_| config.issue_refs) {
+        events = issue_refs(events.into_iter(), tracker);
+    }
+    if config.strip_tracking_params {
+        events = strip_link_tracking(events.into_iter());
+    }
+    if let Some(ref word_filter) = config.word_filter {
+        events = mask_words(events.into_iter(), word_filter);
+    }
+    if config.heading_anchors {
+        events = heading_anchors(events.into_iter());
+    }
     let mut html_output = String::with_capacity(md.len() * 2);
-    html::push_html(&mut html_output, processed);
-    clean_html(&html_output)
+    html::push_html(&mut html_output, events.into_iter());
+    let cleaned = clean_html_with(&html_output, config);
+    if config.video_hosts.is_empty() && config.embed_providers.is_empty() {
+        cleaned
+    } else {
+        video_embeds(&cleaned, &config.video_hosts, &config.embed_providers)
+    }
 }
 
 pub(super) fn clean_html(raw: &str) -> String {
-    ammonia::Builder::default()
+    let config = RENDER_CONFIG.read().unwrap().clone();
+    clean_html_with(raw, &config)
+}
+
+fn clean_html_with(raw: &str, config: &RenderConfig) -> String {
+    let cleaned = sanitize(raw, &config.allowlist, config.nofollow);
+    let cleaned = if config.nofollow {
+        add_nofollow(&cleaned, &config.host)
+    } else {
+        cleaned
+    };
+    let cleaned = match &config.image_proxy {
+        Some(key) => proxy_images(&cleaned, &config.host, key),
+        None => cleaned,
+    };
+    lazy_images(&cleaned)
+}
+
+/// The ammonia builder of [sanitize], with the allowlist and `nofollow` it was built for
+static SANITIZER: LazyLock<RwLock<Option<(HtmlAllowlist, bool, ammonia::Builder<'static>)>>> =
+    LazyLock::new(|| RwLock::new(None));
+
+/// Clean `raw` with the cached builder, building it again only when the allowlist or
+/// `nofollow` changed.
+fn sanitize(raw: &str, allowlist: &HtmlAllowlist, nofollow: bool) -> String {
+    if let Some((cached_allowlist, cached_nofollow, builder)) = &*SANITIZER.read().unwrap() {
+        if cached_allowlist == allowlist && *cached_nofollow == nofollow {
+            return builder.clean(raw).to_string();
+        }
+    }
+
+    let builder = sanitizer(allowlist, nofollow);
+    let cleaned = builder.clean(raw).to_string();
+    *SANITIZER.write().unwrap() = Some((allowlist.clone(), nofollow, builder));
+    cleaned
+}
+
+/// Strings of the allowlists the sanitizer was built with, see [intern]
+static INTERNED: LazyLock<Mutex<HashSet<&'static str>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// A `'static` copy of `s`. Each distinct string is leaked once, so saving the site config
+/// again, or going back to an older allowlist, doesn't leak more memory.
+fn intern(s: &str) -> &'static str {
+    let mut interned = INTERNED.lock().unwrap();
+    if let Some(s) = interned.get(s) {
+        return s;
+    }
+    let s: &'static str = s.to_owned().leak();
+    interned.insert(s);
+    s
+}
+
+/// The builder borrows the allowlist for as long as it is cached, so its strings are
+/// [intern]ed.
+fn sanitizer(allowlist: &HtmlAllowlist, nofollow: bool) -> ammonia::Builder<'static> {
+    let mut builder = ammonia::Builder::default();
+    builder
         .add_tags(&MATHML_TAGS)
         .add_allowed_classes("span", &["replytag"])
         .add_tag_attributes("pre", &["style"])
@@ -74,12 +236,1222 @@ pub(super) fn clean_html(raw: &str) -> String {
         // allow task list
         .add_tags(&["input"])
         .add_tag_attributes("input", &["type", "checked", "disabled"])
+        .add_tag_attributes("img", &["loading", "decoding"])
         // allow footnotes
         .add_allowed_classes("sup", &["footnote-reference", "footnote-definition-label"])
-        .add_allowed_classes("div", &["footnote-definition"])
+        .add_allowed_classes("div", &["footnote-definition", "footnotes"])
+        .add_allowed_classes("a", &["footnote-backref"])
         .add_tag_attributes("div", &["id"])
-        .clean(raw)
-        .to_string()
+        .add_tag_attributes("sup", &["id"])
+        .add_tag_attributes("li", &["id"])
+        // allow heading anchors
+        .add_allowed_classes("a", &["anchor"]);
+    for tag in ["h1", "h2", "h3", "h4", "h5", "h6"] {
+        builder.add_tag_attributes(tag, &["id"]);
+    }
+
+    // extra html allowed by admin
+    builder
+        .add_tags(allowlist.tags.iter().map(|s| intern(s)))
+        .add_url_schemes(allowlist.url_schemes.iter().map(|s| intern(s)));
+    for (tag, attr) in &allowlist.attributes {
+        builder.add_tag_attributes(intern(tag), [intern(attr)]);
+    }
+    if nofollow {
+        builder.link_rel(None);
+    }
+    builder
+}
+
+/// Ends the excerpt of a post, shown in feeds. It is removed from the full post.
+const CUT_MARKER: &str = "<!-- more -->";
+
+/// The markdown before [CUT_MARKER]. Without a marker, about the first `max_chars` chars, cut at
+/// the end of a paragraph if there is one, and never inside a fenced code block. `None` if the
+/// whole post should be shown, as it is short enough or `max_chars` is 0.
+pub(super) fn md_excerpt(md: &str, max_chars: usize) -> Option<&str> {
+    if let Some((before, _)) = md.split_once(CUT_MARKER) {
+        return Some(before.trim_end());
+    }
+    if max_chars == 0 {
+        return None;
+    }
+    let (end, _) = md.char_indices().nth(max_chars)?;
+
+    let head = &md[..end];
+    let mut cut = head
+        .rfind("\n\n")
+        .or_else(|| head.rfind(char::is_whitespace))
+        .unwrap_or(end);
+    let head = &md[..cut];
+    if head.matches("```").count() % 2 == 1 {
+        cut = head.rfind("```").unwrap_or(cut);
+    }
+    Some(md[..cut].trim_end())
+}
+
+/// Sentences of a summary, see [summarize]
+const SUMMARY_SENTENCES: usize = 3;
+
+/// Length of the summaries of long posts
+pub(super) const SUMMARY_MAX_CHARS: usize = 300;
+
+/// An extractive summary of `md`, at most `max_chars` chars: the first sentences of the
+/// paragraphs sharing the most words with the rest of the post, in the order they are written.
+/// Code blocks, headings, tables and raw html are left out. The same markdown always gets the
+/// same summary.
+pub(super) fn summarize(md: &str, max_chars: usize) -> String {
+    let mut paragraphs: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut skip_depth = 0_usize;
+    for event in pulldown_cmark::Parser::new_ext(md, Options::all()) {
+        match event {
+            Event::Start(
+                Tag::CodeBlock(_)
+                | Tag::Heading { .. }
+                | Tag::Table(_)
+                | Tag::HtmlBlock
+                | Tag::Image { .. },
+            ) => skip_depth += 1,
+            Event::End(
+                TagEnd::CodeBlock
+                | TagEnd::Heading(_)
+                | TagEnd::Table
+                | TagEnd::HtmlBlock
+                | TagEnd::Image,
+            ) => skip_depth = skip_depth.saturating_sub(1),
+            Event::Text(text) | Event::Code(text) if skip_depth == 0 => current.push_str(&text),
+            Event::SoftBreak | Event::HardBreak if skip_depth == 0 => current.push(' '),
+            Event::Start(Tag::List(_)) | Event::End(TagEnd::Paragraph | TagEnd::Item) => {
+                let paragraph = current.split_whitespace().collect::<Vec<_>>().join(" ");
+                if !paragraph.is_empty() {
+                    paragraphs.push(paragraph);
+                }
+                current.clear();
+            }
+            _ => {}
+        }
+    }
+
+    fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|w| w.chars().count() >= 4)
+            .map(str::to_lowercase)
+    }
+    let mut freq: HashMap<String, usize> = HashMap::new();
+    for word in paragraphs.iter().flat_map(|p| words(p)) {
+        *freq.entry(word).or_default() += 1;
+    }
+
+    // words used more than once tell what the post is about
+    let mut ranked: Vec<(usize, usize)> = paragraphs
+        .iter()
+        .enumerate()
+        .map(|(idx, p)| {
+            let mut seen: Vec<String> = words(p).collect();
+            seen.sort_unstable();
+            seen.dedup();
+            let score = seen.iter().map(|w| freq[w] - 1).sum();
+            (idx, score)
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut picked: Vec<(usize, &str)> = Vec::new();
+    let mut len = 0;
+    for (idx, _) in ranked.into_iter().take(SUMMARY_SENTENCES) {
+        let sentence = first_sentence(&paragraphs[idx]);
+        let n = sentence.chars().count();
+        if len + n + picked.len() > max_chars {
+            if picked.is_empty() {
+                let cut: String = sentence.chars().take(max_chars.saturating_sub(1)).collect();
+                return format!("{}…", cut.trim_end());
+            }
+            break;
+        }
+        len += n;
+        picked.push((idx, sentence));
+    }
+    picked.sort_unstable_by_key(|(idx, _)| *idx);
+    picked
+        .into_iter()
+        .map(|(_, s)| s)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Up to the first `.`, `!` or `?` followed by a space, or `。`, `！`, `？`
+fn first_sentence(text: &str) -> &str {
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '.' | '!' | '?' if chars.peek().is_none_or(|(_, next)| next.is_whitespace()) => {
+                return &text[..=i];
+            }
+            '。' | '！' | '？' => return &text[..i + c.len_utf8()],
+            _ => {}
+        }
+    }
+    text
+}
+
+static WIKI_LINK_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\[\[(\d+)\]\]").unwrap());
+
+/// Pids of the posts `md` links to, by `[[pid]]` or by a link to `/post/:iid/:pid` or
+/// `/p/:code`, either relative or on the site `domain`. Links in code are left out.
+pub(super) fn linked_pids(md: &str, domain: &str) -> BTreeSet<u32> {
+    let mut pids = BTreeSet::new();
+    let mut in_code_block = false;
+    let parser = pulldown_cmark::Parser::new_ext(md, Options::all());
+    for event in TextMergeStream::new(parser) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+            Event::End(TagEnd::CodeBlock) => in_code_block = false,
+            Event::Start(Tag::Link { dest_url, .. }) => {
+                pids.extend(linked_pid(&dest_url, domain));
+            }
+            Event::Text(text) if !in_code_block => {
+                for caps in WIKI_LINK_REGEX.captures_iter(&text) {
+                    pids.extend(caps[1].parse::<u32>().ok());
+                }
+            }
+            _ => {}
+        }
+    }
+    pids
+}
+
+fn linked_pid(url: &str, domain: &str) -> Option<u32> {
+    let path = if url.starts_with('/') && !url.starts_with("//") {
+        url
+    } else {
+        let domain = domain.trim_end_matches('/');
+        url.strip_prefix(domain)
+            .filter(|path| path.starts_with('/'))?
+    };
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    match segments[..] {
+        ["", "post", iid, pid] if iid.parse::<u32>().is_ok() => pid.parse().ok(),
+        ["", "p", code] => base62_decode(code),
+        _ => None,
+    }
+}
+
+/// Give headings an id made from their text and a `#` link to it, so readers can link to a
+/// section. The id only changes when the heading text does, or when a heading with the same
+/// text is added before it, as repeated headings get `-1`, `-2`... An explicit `{#id}` is
+/// replaced, so ids always have the `h-` prefix and can't clash with those of the page.
+fn heading_anchors<'a>(events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+    let mut out = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    // index of the heading start in `out`, and its text
+    let mut heading: Option<(usize, String)> = None;
+    for event in events {
+        match event {
+            Event::Start(Tag::Heading { .. }) => {
+                heading = Some((out.len(), String::new()));
+                out.push(event);
+            }
+            Event::Text(ref text) | Event::Code(ref text) => {
+                if let Some((_, ref mut heading_text)) = heading {
+                    heading_text.push_str(text);
+                }
+                out.push(event);
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some((start, text)) = heading.take() {
+                    let slug = slugify(&text);
+                    let n = seen.entry(slug.clone()).or_insert(0);
+                    let slug = match *n {
+                        0 => format!("h-{slug}"),
+                        n => format!("h-{slug}-{n}"),
+                    };
+                    *n += 1;
+                    if let Event::Start(Tag::Heading { ref mut id, .. }) = out[start] {
+                        *id = Some(slug.clone().into());
+                    }
+                    out.push(Event::InlineHtml(
+                        format!(r##" <a class="anchor" href="#{slug}">#</a>"##).into(),
+                    ));
+                }
+                out.push(event);
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Lowercase letters and digits joined by `-`, like `getting-started`.
+fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "section".to_owned()
+    } else {
+        slug.to_owned()
+    }
+}
+
+/// Demote a heading by one level, h6 stays h6.
+const fn demote(level: HeadingLevel) -> HeadingLevel {
+    match level {
+        HeadingLevel::H1 => HeadingLevel::H2,
+        HeadingLevel::H2 => HeadingLevel::H3,
+        HeadingLevel::H3 => HeadingLevel::H4,
+        HeadingLevel::H4 => HeadingLevel::H5,
+        HeadingLevel::H5 | HeadingLevel::H6 => HeadingLevel::H6,
+    }
+}
+
+/// Number footnotes in first-reference order and render the definitions as a list with
+/// back-references at the end. Unreferenced definitions are dropped, references without a
+/// definition are kept as plain text.
+fn footnotes<'a>(events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+    let mut body = Vec::new();
+    let mut definitions: HashMap<String, Vec<Event<'a>>> = HashMap::new();
+    let mut current: Option<(String, Vec<Event<'a>>)> = None;
+    for event in events {
+        match event {
+            Event::Start(Tag::FootnoteDefinition(label)) => {
+                current = Some((label.to_string(), Vec::new()));
+            }
+            Event::End(TagEnd::FootnoteDefinition) => {
+                if let Some((label, content)) = current.take() {
+                    definitions.entry(label).or_insert(content);
+                }
+            }
+            other => match current.as_mut() {
+                Some((_, content)) => content.push(other),
+                None => body.push(other),
+            },
+        }
+    }
+
+    let mut order = Vec::new();
+    let mut out = number_footnote_refs(body, &definitions, &mut order);
+
+    // definitions may reference other footnotes, so `order` can grow while rendering
+    let mut rendered = Vec::new();
+    let mut i = 0;
+    while i < order.len() {
+        let content = definitions.remove(&order[i]).unwrap_or_default();
+        rendered.push(number_footnote_refs(content, &definitions, &mut order));
+        i += 1;
+    }
+    for label in definitions.keys() {
+        tracing::warn!("dropped unreferenced footnote definition: {label}");
+    }
+
+    if rendered.is_empty() {
+        return out;
+    }
+    out.push(Event::Html(r#"<div class="footnotes"><hr><ol>"#.into()));
+    for (idx, mut content) in rendered.into_iter().enumerate() {
+        let n = idx + 1;
+        let backref =
+            Event::Html(format!(r##"<a href="#fnref-{n}" class="footnote-backref">↩</a>"##).into());
+        out.push(Event::Html(format!(r#"<li id="fn-{n}">"#).into()));
+        // keep the back-reference inside the last paragraph
+        if matches!(content.last(), Some(Event::End(TagEnd::Paragraph))) {
+            let end = content.pop().unwrap();
+            out.extend(content);
+            out.push(Event::Text(" ".into()));
+            out.push(backref);
+            out.push(end);
+        } else {
+            out.extend(content);
+            out.push(backref);
+        }
+        out.push(Event::Html("</li>".into()));
+    }
+    out.push(Event::Html("</ol></div>".into()));
+    out
+}
+
+/// Replace footnote references with numbered links. Only the first reference of a footnote gets
+/// the id the back-reference points to.
+fn number_footnote_refs<'a>(
+    events: Vec<Event<'a>>,
+    definitions: &HashMap<String, Vec<Event<'a>>>,
+    order: &mut Vec<String>,
+) -> Vec<Event<'a>> {
+    events
+        .into_iter()
+        .map(|event| match event {
+            Event::FootnoteReference(label) => {
+                if let Some(idx) = order.iter().position(|l| l == label.as_ref()) {
+                    let n = idx + 1;
+                    Event::Html(
+                        format!(r##"<sup class="footnote-reference"><a href="#fn-{n}">{n}</a></sup>"##)
+                            .into(),
+                    )
+                } else if definitions.contains_key(label.as_ref()) {
+                    order.push(label.to_string());
+                    let n = order.len();
+                    Event::Html(
+                        format!(r##"<sup class="footnote-reference" id="fnref-{n}"><a href="#fn-{n}">{n}</a></sup>"##)
+                            .into(),
+                    )
+                } else {
+                    Event::Text(format!("[^{label}]").into())
+                }
+            }
+            other => other,
+        })
+        .collect()
+}
+
+static SHORTCODE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r":([a-z0-9_+-]{1,32}):").unwrap());
+
+/// Built-in emoji shortcodes, custom emojis of the instance take precedence.
+const EMOJIS: [(&str, &str); 48] = [
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("bug", "🐛"),
+    ("clap", "👏"),
+    ("coffee", "☕"),
+    ("confused", "😕"),
+    ("crab", "🦀"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("hugs", "🤗"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("lock", "🔒"),
+    ("memo", "📝"),
+    ("ok_hand", "👌"),
+    ("pensive", "😔"),
+    ("point_right", "👉"),
+    ("pray", "🙏"),
+    ("question", "❓"),
+    ("raised_hands", "🙌"),
+    ("relaxed", "☺️"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("smirk", "😏"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("sunglasses", "😎"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("warning", "⚠️"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+    ("x", "❌"),
+    ("zap", "⚡"),
+];
+
+/// Expand `:shortcode:` to an emoji, or to an image for custom emojis. Text in code blocks is
+/// left as is, inline code is a separate event and never touched.
+fn emoji_shortcodes<'a>(
+    events: impl Iterator<Item = Event<'a>>,
+    custom_emojis: &HashMap<String, String>,
+) -> Vec<Event<'a>> {
+    let mut out = Vec::new();
+    let mut in_code_block = false;
+    for event in events {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                out.push(event);
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                out.push(event);
+            }
+            Event::Text(text) if !in_code_block && SHORTCODE_REGEX.is_match(&text) => {
+                let mut last = 0;
+                let mut buf = String::new();
+                for caps in SHORTCODE_REGEX.captures_iter(&text) {
+                    let m = caps.get(0).unwrap();
+                    let name = &caps[1];
+                    if let Some(url) = custom_emojis.get(name) {
+                        buf.push_str(&text[last..m.start()]);
+                        if !buf.is_empty() {
+                            out.push(Event::Text(std::mem::take(&mut buf).into()));
+                        }
+                        out.push(Event::Start(Tag::Image {
+                            link_type: LinkType::Inline,
+                            dest_url: url.clone().into(),
+                            title: m.as_str().to_owned().into(),
+                            id: "".into(),
+                        }));
+                        out.push(Event::Text(m.as_str().to_owned().into()));
+                        out.push(Event::End(TagEnd::Image));
+                    } else if let Ok(idx) = EMOJIS.binary_search_by(|(k, _)| (*k).cmp(name)) {
+                        buf.push_str(&text[last..m.start()]);
+                        buf.push_str(EMOJIS[idx].1);
+                    } else {
+                        buf.push_str(&text[last..m.end()]);
+                    }
+                    last = m.end();
+                }
+                buf.push_str(&text[last..]);
+                if !buf.is_empty() {
+                    out.push(Event::Text(buf.into()));
+                }
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+static URL_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://[^\s<>]+").unwrap());
+
+/// Turn bare urls in text into links. Urls in link text, image alt text or code blocks are
+/// left as is.
+fn autolink<'a>(events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+    let mut out = Vec::new();
+    let mut link_depth = 0_usize;
+    let mut in_code_block = false;
+    for event in events {
+        match event {
+            Event::Start(Tag::Link { .. } | Tag::Image { .. }) => {
+                link_depth += 1;
+                out.push(event);
+            }
+            Event::End(TagEnd::Link | TagEnd::Image) => {
+                link_depth = link_depth.saturating_sub(1);
+                out.push(event);
+            }
+            Event::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                out.push(event);
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                out.push(event);
+            }
+            Event::Text(text) if link_depth == 0 && !in_code_block && URL_REGEX.is_match(&text) => {
+                let mut last = 0;
+                for m in URL_REGEX.find_iter(&text) {
+                    let url = m
+                        .as_str()
+                        .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'', '"']);
+                    if url.len() <= "https://".len() {
+                        continue;
+                    }
+                    if m.start() > last {
+                        out.push(Event::Text(text[last..m.start()].to_owned().into()));
+                    }
+                    out.push(Event::Start(Tag::Link {
+                        link_type: LinkType::Autolink,
+                        dest_url: url.to_owned().into(),
+                        title: "".into(),
+                        id: "".into(),
+                    }));
+                    out.push(Event::Text(url.to_owned().into()));
+                    out.push(Event::End(TagEnd::Link));
+                    last = m.start() + url.len();
+                }
+                if last < text.len() {
+                    out.push(Event::Text(text[last..].to_owned().into()));
+                }
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// `#123` or `org/repo#123`. The id has at most 9 digits, so it fits in a `u32`.
+static ISSUE_REF_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:([A-Za-z0-9][A-Za-z0-9_.-]{0,38})/([A-Za-z0-9_.-]{1,100}))?#([0-9]{1,9})\b")
+        .unwrap()
+});
+
+/// Link issue references in text, GitHub style. `tracker` is the url of a repository, like
+/// `https://github.com/freedit-org/freedit`: `#123` links to `{tracker}/issues/123`, and
+/// `org/repo#123` to `/org/repo/issues/123` of the same host. Code and link text are left as is.
+fn issue_refs<'a>(events: impl Iterator<Item = Event<'a>>, tracker: &str) -> Vec<Event<'a>> {
+    let tracker = tracker.trim_end_matches('/');
+    let origin = match tracker.split_once("://") {
+        Some((scheme, rest)) => {
+            let host = rest.split('/').next().unwrap_or_default();
+            format!("{scheme}://{host}")
+        }
+        None => return events.collect(),
+    };
+
+    let mut out = Vec::new();
+    let mut link_depth = 0_usize;
+    let mut in_code_block = false;
+    for event in events {
+        match event {
+            Event::Start(Tag::Link { .. } | Tag::Image { .. }) => {
+                link_depth += 1;
+                out.push(event);
+            }
+            Event::End(TagEnd::Link | TagEnd::Image) => {
+                link_depth = link_depth.saturating_sub(1);
+                out.push(event);
+            }
+            Event::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                out.push(event);
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                out.push(event);
+            }
+            Event::Text(text)
+                if link_depth == 0 && !in_code_block && ISSUE_REF_REGEX.is_match(&text) =>
+            {
+                let mut last = 0;
+                for caps in ISSUE_REF_REGEX.captures_iter(&text) {
+                    let m = caps.get(0).unwrap();
+                    // not a part of a word, a path or an html entity
+                    let standalone = text[..m.start()]
+                        .chars()
+                        .next_back()
+                        .is_none_or(|c| !c.is_alphanumeric() && !"/#&_-.".contains(c));
+                    let id = caps[3].parse::<u32>().unwrap_or_default();
+                    let url = match (caps.get(1), caps.get(2)) {
+                        (Some(org), Some(repo)) => {
+                            let (org, repo) = (org.as_str(), repo.as_str());
+                            if repo.starts_with('.') {
+                                continue;
+                            }
+                            format!("{origin}/{org}/{repo}/issues/{id}")
+                        }
+                        _ => format!("{tracker}/issues/{id}"),
+                    };
+                    if !standalone || id == 0 {
+                        continue;
+                    }
+                    if m.start() > last {
+                        out.push(Event::Text(text[last..m.start()].to_owned().into()));
+                    }
+                    out.push(Event::Start(Tag::Link {
+                        link_type: LinkType::Inline,
+                        dest_url: url.into(),
+                        title: "".into(),
+                        id: "".into(),
+                    }));
+                    out.push(Event::Text(m.as_str().to_owned().into()));
+                    out.push(Event::End(TagEnd::Link));
+                    last = m.end();
+                }
+                if last < text.len() {
+                    out.push(Event::Text(text[last..].to_owned().into()));
+                }
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Whether `url` can be the issue tracker of an inn, see [issue_refs].
+pub(super) fn is_valid_tracker(url: &str) -> bool {
+    let Some(rest) = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+    else {
+        return false;
+    };
+    url.len() <= 256
+        && !rest.is_empty()
+        && !rest.starts_with('/')
+        && rest
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~/:".contains(c))
+}
+
+/// Query params only used to track where a click came from. Params starting with `utm_` are
+/// matched by prefix.
+const TRACKING_PARAMS: [&str; 14] = [
+    "_hsenc", "_hsmi", "dclid", "fbclid", "gbraid", "gclid", "igshid", "mc_cid", "mc_eid",
+    "mkt_tok", "msclkid", "twclid", "wbraid", "yclid",
+];
+
+/// Words of [SiteConfig] `word_filter`, matched as whole words ignoring case
+#[derive(Debug)]
+struct WordFilter {
+    regex: Regex,
+    replacement: String,
+}
+
+impl WordFilter {
+    fn new(words: &str, replacement: &str) -> Option<Self> {
+        let words: Vec<String> = split_list(words).map(|w| regex::escape(&w)).collect();
+        if words.is_empty() {
+            return None;
+        }
+        let regex = Regex::new(&format!(r"(?i)\b(?:{})\b", words.join("|"))).ok()?;
+        Some(Self {
+            regex,
+            replacement: replacement.to_owned(),
+        })
+    }
+
+    fn mask<'h>(&self, text: &'h str) -> Cow<'h, str> {
+        self.regex.replace_all(text, NoExpand(&self.replacement))
+    }
+}
+
+/// Mask filtered words in text, before html is generated, so the replacement is escaped and
+/// then sanitized like any text. Code is left as written.
+fn mask_words<'a>(events: impl Iterator<Item = Event<'a>>, filter: &WordFilter) -> Vec<Event<'a>> {
+    let mut in_code_block = false;
+    events
+        .map(|event| match event {
+            Event::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                event
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                event
+            }
+            Event::Text(text) if !in_code_block && filter.regex.is_match(&text) => {
+                Event::Text(filter.mask(&text).into_owned().into())
+            }
+            other => other,
+        })
+        .collect()
+}
+
+/// Mask filtered words in plain text outside of markdown, like post titles.
+pub(super) fn mask_text(text: &str) -> String {
+    let config = RENDER_CONFIG.read().unwrap().clone();
+    match config.word_filter {
+        Some(ref word_filter) => word_filter.mask(text).into_owned(),
+        None => text.to_owned(),
+    }
+}
+
+/// Remove tracking params from link destinations. The link text is left as written.
+fn strip_link_tracking<'a>(events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+    events
+        .map(|event| match event {
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => Event::Start(Tag::Link {
+                link_type,
+                dest_url: strip_tracking(&dest_url).into(),
+                title,
+                id,
+            }),
+            other => other,
+        })
+        .collect()
+}
+
+/// Drop tracking params from the query of `url`, keeping the other params in order and the
+/// fragment. Some sites need such a param for the link to work, those links will break.
+fn strip_tracking(url: &str) -> String {
+    let (rest, fragment) = match url.split_once('#') {
+        Some((rest, fragment)) => (rest, Some(fragment)),
+        None => (url, None),
+    };
+    let Some((base, query)) = rest.split_once('?') else {
+        return url.to_owned();
+    };
+    let is_tracking = |param: &str| {
+        let key = param.split('=').next().unwrap_or_default().to_lowercase();
+        key.starts_with("utm_") || TRACKING_PARAMS.contains(&key.as_str())
+    };
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|param| !param.is_empty() && !is_tracking(param))
+        .collect();
+
+    let mut out = base.to_owned();
+    if !kept.is_empty() {
+        out.push('?');
+        out.push_str(&kept.join("&"));
+    }
+    if let Some(fragment) = fragment {
+        out.push('#');
+        out.push_str(fragment);
+    }
+    out
+}
+
+static A_TAG_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<a((?:\s+[^\s=>]+="[^"]*")*)>"#).unwrap());
+static HREF_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"\shref="([^"]*)""#).unwrap());
+
+static IMG_TAG_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<img((?:\s+[^\s=>]+="[^"]*")*)(\s*/?)>"#).unwrap());
+
+/// Add `loading="lazy"` and `decoding="async"` to images, unless the author set them.
+/// The input must be the output of ammonia.
+fn lazy_images(html: &str) -> String {
+    IMG_TAG_REGEX
+        .replace_all(html, |caps: &regex::Captures| {
+            let attrs = &caps[1];
+            let mut extra = String::new();
+            if !attrs.contains(" loading=") {
+                extra.push_str(r#" loading="lazy""#);
+            }
+            if !attrs.contains(" decoding=") {
+                extra.push_str(r#" decoding="async""#);
+            }
+            format!("<img{attrs}{extra}{}>", &caps[2])
+        })
+        .into_owned()
+}
+
+static SRC_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"\ssrc="([^"]*)""#).unwrap());
+
+/// Point external `http(s)` images to the signed `/imgproxy` url. The input must be the output
+/// of ammonia.
+fn proxy_images(html: &str, host: &str, key: &hmac::Key) -> String {
+    IMG_TAG_REGEX
+        .replace_all(html, |caps: &regex::Captures| {
+            let tag = &caps[0];
+            let Some(src) = SRC_REGEX.captures(tag) else {
+                return tag.to_owned();
+            };
+            let url = src[1]
+                .replace("&quot;", "\"")
+                .replace("&nbsp;", "\u{a0}")
+                .replace("&amp;", "&");
+            let lower = url.to_lowercase();
+            if !(lower.starts_with("https://") || lower.starts_with("http://"))
+                || !is_external_link(&url, host)
+            {
+                return tag.to_owned();
+            }
+            let proxied = image_proxy::signed_url(key, &url).replace('&', "&amp;");
+            tag.replacen(&src[0], &format!(r#" src="{proxied}""#), 1)
+        })
+        .into_owned()
+}
+
+static VIDEO_PARAGRAPH_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"<p>(?:<a href="([^"]+)"[^>]*>https?://[^<]+</a>|(https?://[^\s<]+))</p>"#)
+        .unwrap()
+});
+
+/// Permissions of the iframes of video players
+const VIDEO_ALLOW: &str = "autoplay; fullscreen; picture-in-picture";
+
+/// Replace a video url on its own line with a click-to-load player: an iframe showing a local
+/// placeholder (`srcdoc`), whose link loads the player of the video host.
+///
+/// Only urls of `hosts` and of `providers` are embedded. Iframes never pass ammonia, they are
+/// added here to its output.
+fn video_embeds(html: &str, hosts: &[String], providers: &[EmbedProvider]) -> String {
+    VIDEO_PARAGRAPH_REGEX
+        .replace_all(html, |caps: &regex::Captures| {
+            let url = caps
+                .get(1)
+                .or_else(|| caps.get(2))
+                .map_or("", |m| m.as_str())
+                .replace("&amp;", "&");
+            let embed = video_embed_url(&url, hosts)
+                .map(|(host, embed)| (host, embed, VIDEO_ALLOW))
+                .or_else(|| {
+                    providers.iter().find_map(|provider| {
+                        let embed = provider.embed_url(&url)?;
+                        Some((provider.host.clone(), embed, provider.allow.as_str()))
+                    })
+                });
+            let Some((host, embed, allow)) = embed else {
+                return caps[0].to_owned();
+            };
+            let placeholder = format!(
+                "<style>a{{display:flex;height:100%;align-items:center;justify-content:center;\
+                background:#000;color:#fff;font:1.5em sans-serif;text-decoration:none}}\
+                body{{margin:0;height:100vh}}</style><a href=\"{embed}\">▶ {host}</a>"
+            );
+            format!(
+                r#"<iframe class="video-embed" width="560" height="315" title="{host}" allow="{allow}" allowfullscreen srcdoc="{}"></iframe>"#,
+                placeholder.replace('&', "&amp;").replace('"', "&quot;")
+            )
+        })
+        .into_owned()
+}
+
+/// `(host, player url)` of a video page on one of `hosts`. YouTube, Vimeo and PeerTube urls
+/// are known.
+fn video_embed_url(url: &str, hosts: &[String]) -> Option<(String, String)> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))?;
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let host = host.to_lowercase();
+    if !hosts.contains(&host) {
+        return None;
+    }
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let is_id = |id: &str| {
+        !id.is_empty()
+            && id.len() <= 64
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+
+    let embed = match host.trim_start_matches("www.").trim_start_matches("m.") {
+        "youtube.com" => {
+            let id = match path.strip_prefix("shorts/") {
+                Some(id) => id,
+                None if path == "watch" => query
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("v="))?,
+                None => return None,
+            };
+            is_id(id).then(|| format!("https://www.youtube-nocookie.com/embed/{id}?autoplay=1"))?
+        }
+        "youtu.be" => is_id(path)
+            .then(|| format!("https://www.youtube-nocookie.com/embed/{path}?autoplay=1"))?,
+        "vimeo.com" => (!path.is_empty() && path.chars().all(|c| c.is_ascii_digit()))
+            .then(|| format!("https://player.vimeo.com/video/{path}?autoplay=1"))?,
+        // PeerTube
+        _ => {
+            let id = path
+                .strip_prefix("w/")
+                .or_else(|| path.strip_prefix("videos/watch/"))?;
+            is_id(id).then(|| format!("https://{host}/videos/embed/{id}?autoplay=1"))?
+        }
+    };
+    Some((host, embed))
+}
+
+/// A provider of [SiteConfig] `embed_providers`, for embeds other than the known video hosts
+#[derive(Debug)]
+pub(super) struct EmbedProvider {
+    host: String,
+    /// matches the whole url of a page of the provider
+    regex: Regex,
+    /// embed url, with `$1`, `$2`... replaced by the groups of `regex`
+    template: String,
+    /// `allow` attribute of the iframe
+    allow: String,
+}
+
+impl EmbedProvider {
+    /// Providers are written one per line, as `host url_regex embed_url [allow]`:
+    ///
+    /// `codepen.io ^https://codepen\.io/(\w+)/pen/(\w+)$ https://codepen.io/$1/embed/$2 fullscreen`
+    ///
+    /// The embed url must be https, and the groups can only be used in its path, so the
+    /// iframe can't load another host. Empty lines and lines starting with `#` are skipped.
+    pub(super) fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        s.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Self::parse)
+            .collect()
+    }
+
+    fn parse(line: &str) -> Result<Self, String> {
+        let mut parts = line.split_whitespace();
+        let (Some(host), Some(regex), Some(template)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!(
+                "embed provider needs a host, a regex and a url: {line}"
+            ));
+        };
+        let allow = parts.collect::<Vec<_>>().join(" ");
+
+        let host = host.to_lowercase();
+        let is_host = |h: &str| {
+            !h.is_empty()
+                && h.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        };
+        if !is_host(&host) {
+            return Err(format!("invalid embed provider host: {host}"));
+        }
+        let regex = Regex::new(regex).map_err(|e| format!("invalid embed provider regex: {e}"))?;
+        let embed_host = template
+            .strip_prefix("https://")
+            .map(|rest| rest.split('/').next().unwrap_or_default())
+            .unwrap_or_default();
+        if !is_host(embed_host) {
+            return Err(format!("embed url must be https://host/...: {template}"));
+        }
+        if !allow
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c == '-' || c == ';' || c == ' ')
+        {
+            return Err(format!("invalid iframe allow: {allow}"));
+        }
+
+        Ok(Self {
+            host,
+            regex,
+            template: template.to_owned(),
+            allow,
+        })
+    }
+
+    /// Embed url of `url`, if the provider has a page there. Groups may only hold url path
+    /// characters, so they can't break out of the path.
+    fn embed_url(&self, url: &str) -> Option<String> {
+        let caps = self.regex.captures(url)?;
+        let is_path = |s: &str| {
+            s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-._~/=&%+".contains(c))
+        };
+        if caps.iter().skip(1).flatten().any(|m| !is_path(m.as_str())) {
+            return None;
+        }
+        let mut embed = String::new();
+        caps.expand(&self.template, &mut embed);
+        Some(embed)
+    }
+
+    /// `https://host` of the embed url, for the CSP `frame-src`
+    fn frame_src(&self) -> String {
+        let rest = &self.template["https://".len()..];
+        format!("https://{}", rest.split('/').next().unwrap_or_default())
+    }
+}
+
+/// The CSP `frame-src` sources of the players of `hosts` and of the embed `providers`, or
+/// `'none'`.
+pub(super) fn video_frame_src(hosts: &str, providers: &str) -> String {
+    let mut sources: Vec<String> = split_list(hosts)
+        .map(
+            |host| match host.trim_start_matches("www.").trim_start_matches("m.") {
+                "youtube.com" | "youtu.be" => "https://www.youtube-nocookie.com".to_owned(),
+                "vimeo.com" => "https://player.vimeo.com".to_owned(),
+                _ => format!("https://{host}"),
+            },
+        )
+        .collect();
+    // invalid providers are rejected when the site config is saved
+    for provider in EmbedProvider::parse_list(providers).unwrap_or_default() {
+        sources.push(provider.frame_src());
+    }
+    sources.sort();
+    sources.dedup();
+    if sources.is_empty() {
+        "'none'".to_owned()
+    } else {
+        sources.join(" ")
+    }
+}
+
+/// Items separated by commas or whitespace, lowercased.
+fn split_list(s: &str) -> impl Iterator<Item = String> + '_ {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|i| !i.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Add `rel="nofollow ugc noopener"` to external links. The input must be the output of
+/// ammonia, so the attributes are always double quoted.
+fn add_nofollow(html: &str, host: &str) -> String {
+    A_TAG_REGEX
+        .replace_all(html, |caps: &regex::Captures| {
+            let attrs = &caps[1];
+            let is_external = HREF_REGEX
+                .captures(attrs)
+                .is_some_and(|href| is_external_link(&href[1], host));
+            if is_external {
+                format!(r#"<a{attrs} rel="nofollow ugc noopener">"#)
+            } else {
+                caps[0].to_owned()
+            }
+        })
+        .into_owned()
+}
+
+/// Links with another host are external. Relative links are internal.
+fn is_external_link(href: &str, host: &str) -> bool {
+    let lower = href.to_lowercase();
+    let Some(rest) = lower
+        .strip_prefix("https://")
+        .or_else(|| lower.strip_prefix("http://"))
+        .or_else(|| lower.strip_prefix("//"))
+    else {
+        return false;
+    };
+    let link_host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let link_host = link_host.rsplit_once('@').map_or(link_host, |(_, h)| h);
+    link_host != host
+}
+
+/// Tags that can never be allowed by [HtmlAllowlist].
+const DENIED_TAGS: [&str; 16] = [
+    "applet", "base", "embed", "form", "frame", "frameset", "iframe", "link", "meta", "noscript",
+    "object", "script", "style", "svg", "template", "textarea",
+];
+
+/// Attributes that can never be allowed by [HtmlAllowlist]. `class` and `rel` are managed
+/// by ammonia itself and adding them would panic. Event handlers (`on*`) are checked separately.
+const DENIED_ATTRIBUTES: [&str; 5] = ["class", "formaction", "rel", "srcdoc", "style"];
+
+const DENIED_URL_SCHEMES: [&str; 4] = ["data", "file", "javascript", "vbscript"];
+
+/// Extra tags, attributes and url schemes allowed by the admin, on top of the default ammonia
+/// allowlist.
+#[derive(Default, Debug, Clone, PartialEq)]
+struct HtmlAllowlist {
+    tags: Vec<String>,
+    /// `(tag, attribute)`
+    attributes: Vec<(String, String)>,
+    url_schemes: Vec<String>,
+}
+
+/// Render settings taken from [SiteConfig], cached until the site config is saved again.
+#[derive(Default, Debug)]
+pub(super) struct RenderConfig {
+    allowlist: HtmlAllowlist,
+    autolink: bool,
+    strip_tracking_params: bool,
+    nofollow: bool,
+    /// negation of [SiteConfig] `allow_raw_html`, so the default keeps raw html
+    escape_raw_html: bool,
+    shift_headings: bool,
+    heading_anchors: bool,
+    issue_refs: bool,
+    emoji_shortcodes: bool,
+    /// shortcode to image url, from the `custom_emojis` tree
+    custom_emojis: HashMap<String, String>,
+    /// host of [SiteConfig] `domain`, used to tell external links
+    host: String,
+    /// signing key of `/imgproxy`, set if [SiteConfig] `image_proxy` is on
+    image_proxy: Option<hmac::Key>,
+    /// hosts of [SiteConfig] `video_embed_hosts`
+    video_hosts: Vec<String>,
+    embed_providers: Vec<EmbedProvider>,
+    word_filter: Option<WordFilter>,
+}
+
+static RENDER_CONFIG: LazyLock<RwLock<Arc<RenderConfig>>> = LazyLock::new(|| {
+    let config = SiteConfig::get(&DB)
+        .ok()
+        .and_then(|site_config| RenderConfig::from_site_config(&site_config).ok())
+        .and_then(|config| config.with_custom_emojis(&DB).ok())
+        .unwrap_or_default();
+    RwLock::new(Arc::new(config))
+});
+
+impl RenderConfig {
+    /// Parse and validate the render fields of [SiteConfig].
+    pub(super) fn from_site_config(site_config: &SiteConfig) -> Result<Self, String> {
+        let allowlist = HtmlAllowlist::parse(
+            &site_config.html_allowed_tags,
+            &site_config.html_allowed_attributes,
+            &site_config.html_allowed_url_schemes,
+        )?;
+        let domain = site_config.domain.to_lowercase();
+        let host = domain
+            .split_once("://")
+            .map_or(domain.as_str(), |(_, h)| h)
+            .trim_end_matches('/')
+            .to_owned();
+
+        Ok(RenderConfig {
+            allowlist,
+            autolink: site_config.autolink,
+            strip_tracking_params: site_config.strip_tracking_params,
+            nofollow: site_config.nofollow,
+            escape_raw_html: !site_config.allow_raw_html,
+            shift_headings: site_config.shift_headings,
+            heading_anchors: site_config.heading_anchors,
+            issue_refs: site_config.issue_refs,
+            emoji_shortcodes: site_config.emoji_shortcodes,
+            custom_emojis: HashMap::new(),
+            host,
+            image_proxy: site_config
+                .image_proxy
+                .then(|| image_proxy::PROXY_KEY.clone()),
+            video_hosts: split_list(&site_config.video_embed_hosts).collect(),
+            embed_providers: EmbedProvider::parse_list(&site_config.embed_providers)?,
+            word_filter: WordFilter::new(
+                &site_config.word_filter,
+                &site_config.word_filter_replacement,
+            ),
+        })
+    }
+
+    /// Load custom emojis of the instance.
+    pub(super) fn with_custom_emojis(mut self, db: &Db) -> Result<Self, AppError> {
+        for i in &db.open_tree("custom_emojis")? {
+            let (k, v) = i?;
+            self.custom_emojis.insert(
+                String::from_utf8_lossy(&k).into_owned(),
+                String::from_utf8_lossy(&v).into_owned(),
+            );
+        }
+        Ok(self)
+    }
+
+    /// Replace the config used by [md2html] and [clean_html]. Called after the site config is saved.
+    pub(super) fn set(self) {
+        *RENDER_CONFIG.write().unwrap() = Arc::new(self);
+    }
+}
+
+impl HtmlAllowlist {
+    /// Items are separated by commas or whitespace. Attributes are written as `tag:attribute`.
+    fn parse(tags: &str, attributes: &str, url_schemes: &str) -> Result<Self, String> {
+        fn is_valid(s: &str) -> bool {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        }
+
+        let mut allowlist = HtmlAllowlist::default();
+        for tag in split_list(tags) {
+            if !is_valid(&tag) || DENIED_TAGS.contains(&tag.as_str()) {
+                return Err(format!("html tag <{tag}> is not allowed"));
+            }
+            allowlist.tags.push(tag);
+        }
+
+        for i in split_list(attributes) {
+            let Some((tag, attr)) = i.split_once(':') else {
+                return Err(format!(
+                    "html attribute {i} should be written as tag:attribute"
+                ));
+            };
+            if !is_valid(tag) || DENIED_TAGS.contains(&tag) {
+                return Err(format!("html tag <{tag}> is not allowed"));
+            }
+            if !is_valid(attr) || attr.starts_with("on") || DENIED_ATTRIBUTES.contains(&attr) {
+                return Err(format!("html attribute {attr} is not allowed"));
+            }
+            allowlist.attributes.push((tag.to_owned(), attr.to_owned()));
+        }
+
+        for scheme in split_list(url_schemes) {
+            if !is_valid(&scheme) || DENIED_URL_SCHEMES.contains(&scheme.as_str()) {
+                return Err(format!("url scheme {scheme} is not allowed"));
+            }
+            allowlist.url_schemes.push(scheme);
+        }
+
+        Ok(allowlist)
+    }
 }
 
 struct SyntaxPreprocessor<'a, I: Iterator<Item = Event<'a>>> {
@@ -118,10 +1490,9 @@ impl<'a, I: Iterator<Item = Event<'a>>> Iterator for SyntaxPreprocessor<'a, I> {
                         .into(),
                 ));
             }
-            // for security reasons, we change all html to code blocks, but not `Event::InlineHtml` as @mention needs it
-            // inlined html is cleaned by ammonia `clean_html`
-            // `<button name="my_btn" id="my_btn" onClick="alert('Hello I am alert')">Alert</button>`
-            Event::Html(html) => return Some(Event::Html(code_highlighter(&html, "html").into())),
+            // raw html, block or inline, is either escaped to text before this by `allow_raw_html`
+            // or passed on to ammonia in `clean_html`, which removes anything unsafe like
+            // `<button onClick="alert('Hello I am alert')">Alert</button>`
             other => return Some(other),
         };
 
@@ -153,3 +1524,481 @@ fn code_highlighter(code: &str, lang: &str) -> String {
     )
     .unwrap_or_else(|e| e.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ts_to_relative() {
+        let now = 1_700_000_000;
+        assert_eq!(ts_to_relative(now - 30, now, "en", ""), "just now");
+        assert_eq!(ts_to_relative(now - 150, now, "en", ""), "2 min ago");
+        assert_eq!(ts_to_relative(now - 3600, now, "en", ""), "1 h ago");
+        assert_eq!(ts_to_relative(now - 5 * 86400, now, "en", ""), "5 d ago");
+        assert_eq!(ts_to_relative(now - 5 * 86400, now, "zh_cn", ""), "5 天前");
+        // older than a week, or in the future
+        assert_eq!(ts_to_relative(now - 8 * 86400, now, "en", ""), "2023-11-06");
+        assert_eq!(ts_to_relative(now + 60, now, "en", ""), "2023-11-14");
+        assert_eq!(
+            ts_to_relative(now - 8 * 86400, now, "en", "Asia/Shanghai"),
+            "2023-11-07"
+        );
+    }
+
+    #[test]
+    fn test_ts_to_local_date() {
+        // 2023-11-14 22:13:20 UTC
+        let ts = 1_700_000_000;
+        assert_eq!(ts_to_local_date(ts, ""), "2023-11-14");
+        assert_eq!(ts_to_local_date(ts, "UTC"), "2023-11-14");
+        assert_eq!(ts_to_local_date(ts, "Asia/Shanghai"), "2023-11-15");
+        assert_eq!(ts_to_local_date(ts, "America/New_York"), "2023-11-14");
+        // 2023-11-15 03:13:20 UTC, still the 14th in New York
+        assert_eq!(
+            ts_to_local_date(ts + 5 * 3600, "America/New_York"),
+            "2023-11-14"
+        );
+        assert_eq!(ts_to_local_date(ts, "Mars/Olympus_Mons"), "2023-11-14");
+    }
+
+    #[test]
+    fn test_html_allowlist() {
+        let raw = r#"<meter value="0.6">60%</meter><marquee>hi</marquee>"#;
+        let allowlist = HtmlAllowlist::parse("meter", "meter:value", "").unwrap();
+        let config = RenderConfig {
+            allowlist,
+            ..Default::default()
+        };
+        let html = clean_html_with(raw, &config);
+        assert!(html.contains(r#"<meter value="0.6">60%</meter>"#));
+        assert!(!html.contains("marquee"));
+
+        let html = clean_html_with(raw, &RenderConfig::default());
+        assert!(!html.contains("<meter"));
+
+        assert!(HtmlAllowlist::parse("meter, script", "", "").is_err());
+        assert!(HtmlAllowlist::parse("", "img:onerror", "").is_err());
+        assert!(HtmlAllowlist::parse("", "", "javascript").is_err());
+    }
+
+    #[test]
+    fn test_intern() {
+        let a = intern("ruby");
+        let b = intern(&String::from("ruby"));
+        assert!(std::ptr::eq(a, b));
+        assert!(!std::ptr::eq(a, intern("rt")));
+    }
+
+    #[test]
+    fn test_nofollow() {
+        let config = RenderConfig {
+            nofollow: true,
+            host: "freedit.eu".into(),
+            ..Default::default()
+        };
+        let raw = r#"<a href="https://example.com/a">a</a> <a href="https://freedit.eu/inn/1">b</a> <a href="/post/1/2">c</a>"#;
+        let html = clean_html_with(raw, &config);
+        assert!(
+            html.contains(r#"<a href="https://example.com/a" rel="nofollow ugc noopener">a</a>"#)
+        );
+        assert!(html.contains(r#"<a href="https://freedit.eu/inn/1">b</a>"#));
+        assert!(html.contains(r#"<a href="/post/1/2">c</a>"#));
+    }
+
+    #[test]
+    fn test_autolink() {
+        let config = RenderConfig {
+            autolink: true,
+            ..Default::default()
+        };
+        let html = md2html_with(
+            "see https://example.com/a_b, and [link](https://freedit.eu)",
+            &config,
+        );
+        assert!(html.contains(r#"<a href="https://example.com/a_b" rel="noopener noreferrer">https://example.com/a_b</a>,"#));
+        assert!(html.contains(r#"<a href="https://freedit.eu" rel="noopener noreferrer">link</a>"#));
+
+        let html = md2html_with("see https://example.com", &RenderConfig::default());
+        assert!(!html.contains("<a"));
+
+        // urls in code blocks stay text
+        let html = md2html_with(
+            "    see https://example.com/indented\n\n```\nsee https://example.com/fenced\n```",
+            &config,
+        );
+        assert!(html.contains("<pre><code>see https://example.com/indented"));
+        assert!(html.contains("https://example.com/fenced"));
+        assert!(!html.contains("<a"));
+    }
+
+    #[test]
+    fn test_issue_refs() {
+        let config = RenderConfig {
+            issue_refs: true,
+            ..Default::default()
+        };
+        let tracker = Some("https://github.com/freedit-org/freedit/");
+        let html = md2html_with_tracker("fixed in #123, see rust-lang/rust#42.", &config, tracker);
+        assert!(html.contains(r#"<a href="https://github.com/freedit-org/freedit/issues/123" rel="noopener noreferrer">#123</a>,"#));
+        assert!(html.contains(r#"<a href="https://github.com/rust-lang/rust/issues/42" rel="noopener noreferrer">rust-lang/rust#42</a>."#));
+
+        // code is left as written
+        let html = md2html_with_tracker("`#123`\n\n```\n#7\n```", &config, tracker);
+        assert!(!html.contains("<a"));
+
+        // malformed or not standalone
+        for md in ["#0", "#1234567890", "#12ab", "a#12", "x/../..#12"] {
+            let html = md2html_with_tracker(md, &config, tracker);
+            assert!(!html.contains("<a"), "{md}: {html}");
+        }
+
+        // no tracker, or off
+        assert!(!md2html_with("#123", &config).contains("<a"));
+        let html = md2html_with_tracker("#123", &RenderConfig::default(), tracker);
+        assert!(!html.contains("<a"));
+
+        assert!(is_valid_tracker("https://git.example.com/team/app"));
+        assert!(!is_valid_tracker("javascript:alert(1)"));
+        assert!(!is_valid_tracker("https://example.com/a\"onclick"));
+        assert!(!is_valid_tracker("https:///path"));
+    }
+
+    #[test]
+    fn test_summarize() {
+        let md = "# Sled tuning\n\n\
+            Thanks for reading. This part is about other things entirely.\n\n\
+            The sled cache decides how fast pages load. A bigger cache uses memory.\n\n\
+            ```\nsled cache sled cache sled cache\n```\n\n\
+            - Flush the sled cache less often! It helps writes.\n\
+            - Unrelated item\n\n\
+            Set the cache in config.toml, then restart.";
+        let summary = summarize(md, 300);
+        assert_eq!(
+            summary,
+            "The sled cache decides how fast pages load. Flush the sled cache less often! \
+            Set the cache in config.toml, then restart."
+        );
+        assert_eq!(summarize(md, 300), summary);
+
+        assert_eq!(
+            summarize(md, 60),
+            "The sled cache decides how fast pages load."
+        );
+        assert_eq!(summarize(md, 20), "The sled cache deci…");
+        assert_eq!(summarize("一二三四五。六七八", 10), "一二三四五。");
+        assert_eq!(summarize("```\ncode\n```", 10), "");
+    }
+
+    #[test]
+    fn test_linked_pids() {
+        let domain = "https://forum.example.com";
+        let md = "See [[12]] and [the intro](/post/1/3), [short](/p/12W) \
+            or [full](https://forum.example.com/post/2/5#7).\n\n\
+            Not [elsewhere](https://other.example.com/post/1/6), [an inn](/inn/1) \
+            or [[abc]].\n\n\
+            ```\n[[8]] /post/1/9\n```\n\n\
+            Inline `[[10]]` code.";
+        let pids: Vec<u32> = linked_pids(md, domain).into_iter().collect();
+        assert_eq!(pids, vec![3, 5, 12, 4000]);
+
+        assert!(linked_pids("no links", domain).is_empty());
+    }
+
+    #[test]
+    fn test_heading_anchors() {
+        let config = RenderConfig {
+            heading_anchors: true,
+            ..Default::default()
+        };
+        let md = "## Getting Started!\n\ntext\n\n### `cargo` & Rust 2024\n\n## Getting started";
+        let html = md2html_with(md, &config);
+        assert!(html.contains(r##"<h2 id="h-getting-started">Getting Started! <a class="anchor" href="#h-getting-started""##));
+        assert!(html.contains(r#"<h3 id="h-cargo-rust-2024">"#));
+        assert!(html.contains(r#"<h2 id="h-getting-started-1">"#));
+
+        // the same text gives the same ids, whatever else changes
+        let edited = md2html_with(&format!("intro\n\n{md}\n\nmore"), &config);
+        for id in [
+            "h-getting-started",
+            "h-cargo-rust-2024",
+            "h-getting-started-1",
+        ] {
+            assert!(edited.contains(&format!(r#"id="{id}""#)));
+        }
+
+        assert_eq!(slugify("Ünïcode 标题 ?"), "ünïcode-标题");
+        assert_eq!(slugify("!!!"), "section");
+        let explicit = md2html_with("# Title {#evil}", &config);
+        assert!(explicit.contains(r#"id="h-title""#));
+    }
+
+    #[test]
+    fn test_word_filter() {
+        let config = RenderConfig {
+            word_filter: WordFilter::new("darn, heck", "***"),
+            ..Default::default()
+        };
+        let html = md2html_with(
+            "Darn it, what the **heck**. Darnation and heckle stay.\n\n`darn`\n\n```\nheck\n```\n\n    darn",
+            &config,
+        );
+        assert!(html.contains("*** it, what the <strong>***</strong>."));
+        assert!(html.contains("Darnation and heckle stay."));
+        assert!(html.contains("<code>darn</code>"));
+        // only the two words of the paragraph, the code blocks are left as written
+        assert_eq!(html.matches("***").count(), 2);
+        assert!(html.contains("<pre><code>darn</code></pre>"));
+
+        assert!(WordFilter::new(" , ", "***").is_none());
+    }
+
+    #[test]
+    fn test_strip_tracking() {
+        assert_eq!(
+            strip_tracking("https://example.com/a?id=1&utm_source=x&UTM_Medium=y&fbclid=z#top"),
+            "https://example.com/a?id=1#top"
+        );
+        assert_eq!(
+            strip_tracking("https://example.com/a?gclid=1&utm_campaign=2"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            strip_tracking("https://example.com/a?utm=1&q=rust#utm_source"),
+            "https://example.com/a?utm=1&q=rust#utm_source"
+        );
+        assert_eq!(strip_tracking("/post/1/2"), "/post/1/2");
+
+        let config = RenderConfig {
+            autolink: true,
+            strip_tracking_params: true,
+            ..Default::default()
+        };
+        let html = md2html_with(
+            "[a](https://example.com/?utm_source=x&q=1) https://example.com/b?fbclid=1",
+            &config,
+        );
+        assert!(html.contains(r#"href="https://example.com/?q=1""#));
+        assert!(html.contains(r#"href="https://example.com/b""#));
+
+        let html = md2html_with(
+            "[a](https://example.com/?utm_source=x)",
+            &RenderConfig::default(),
+        );
+        assert!(html.contains("utm_source=x"));
+    }
+
+    #[test]
+    fn test_video_embeds() {
+        let config = RenderConfig {
+            autolink: true,
+            video_hosts: vec!["www.youtube.com".into(), "tube.example.org".into()],
+            ..Default::default()
+        };
+        let md = "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=10\n\n\
+            https://tube.example.org/w/abc123\n\n\
+            https://vimeo.com/76979871\n\n\
+            see https://www.youtube.com/watch?v=dQw4w9WgXcQ";
+        let html = md2html_with(md, &config);
+        assert_eq!(html.matches("<iframe").count(), 2);
+        assert!(html.contains(
+            "href=&quot;https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ?autoplay=1&quot;"
+        ));
+        assert!(html.contains("https://tube.example.org/videos/embed/abc123?autoplay=1"));
+        // not allowlisted, and not on its own line
+        assert!(html.contains(r#"<a href="https://vimeo.com/76979871""#));
+        assert!(html.contains(r#"see <a href="https://www.youtube.com/watch?v=dQw4w9WgXcQ""#));
+
+        let html = md2html_with(md, &RenderConfig::default());
+        assert!(!html.contains("<iframe"));
+
+        assert_eq!(video_frame_src("", ""), "'none'");
+        assert_eq!(
+            video_frame_src("youtube.com, youtu.be tube.example.org", ""),
+            "https://tube.example.org https://www.youtube-nocookie.com"
+        );
+    }
+
+    #[test]
+    fn test_embed_providers() {
+        let providers = "# maps\n\
+            maps.example.com ^https://maps\\.example\\.com/m/(\\d+)$ https://maps.example.com/embed/$1 fullscreen; geolocation\n\
+            codepen.io ^https://codepen\\.io/(\\w+)/pen/(\\w+)$ https://codepen.io/$1/embed/$2";
+        let config = RenderConfig {
+            autolink: true,
+            embed_providers: EmbedProvider::parse_list(providers).unwrap(),
+            ..Default::default()
+        };
+        let md = "https://maps.example.com/m/42\n\n\
+            https://codepen.io/team/pen/abc\n\n\
+            https://sandbox.example.net/s/abc";
+        let html = md2html_with(md, &config);
+        assert_eq!(html.matches("<iframe").count(), 2);
+        assert!(html.contains(r#"title="maps.example.com" allow="fullscreen; geolocation""#));
+        assert!(html.contains("href=&quot;https://maps.example.com/embed/42&quot;"));
+        assert!(html.contains("href=&quot;https://codepen.io/team/embed/abc&quot;"));
+        // not registered, so it stays a link
+        assert!(html.contains(r#"<a href="https://sandbox.example.net/s/abc""#));
+
+        assert_eq!(
+            video_frame_src("", providers),
+            "https://codepen.io https://maps.example.com"
+        );
+
+        for invalid in [
+            "maps.example.com ^https://maps",
+            "maps.example.com ( https://maps.example.com/embed",
+            "maps.example.com ^(.*)$ http://maps.example.com/$1",
+            "maps.example.com ^(.*)$ https://$1/embed",
+            "maps.example.com ^(.*)$ https://maps.example.com/$1 allow\"",
+        ] {
+            assert!(EmbedProvider::parse_list(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_md_excerpt() {
+        let md = "intro\n\n<!-- more -->\n\nrest";
+        assert_eq!(md_excerpt(md, 500), Some("intro"));
+        assert_eq!(md_excerpt(md, 0), Some("intro"));
+        let html = md2html_with(md, &RenderConfig::default());
+        assert!(html.contains("intro") && html.contains("rest"));
+        assert!(!html.contains("more"));
+        let html = md2html_with(
+            md,
+            &RenderConfig {
+                escape_raw_html: true,
+                ..Default::default()
+            },
+        );
+        assert!(!html.contains("more"));
+
+        // no marker
+        assert_eq!(md_excerpt("short post", 500), None);
+        assert_eq!(md_excerpt("short post", 0), None);
+        let md = "first paragraph\n\nsecond paragraph is longer";
+        assert_eq!(md_excerpt(md, 30), Some("first paragraph"));
+        assert_eq!(md_excerpt("one two three", 9), Some("one two"));
+        let md = "text\n\n```\nlet a = 1;\n\nlet b = 2;\n```";
+        assert_eq!(md_excerpt(md, 24), Some("text"));
+    }
+
+    #[test]
+    fn test_footnotes() {
+        let md = "b[^b] a[^a] b again[^b]\n\n[^a]: A note\n\n[^b]: B note\n\n[^c]: C note\n";
+        let html = md2html_with(md, &RenderConfig::default());
+        // numbered by first reference, not by definition order
+        let fn1 = html.find(r#"id="fn-1""#).unwrap();
+        let fn2 = html.find(r#"id="fn-2""#).unwrap();
+        let b = html.find("B note").unwrap();
+        let a = html.find("A note").unwrap();
+        assert!(fn1 < b && b < fn2 && fn2 < a);
+        assert_eq!(html.matches(r#"id="fnref-1""#).count(), 1);
+        assert_eq!(html.matches(r##"href="#fn-1""##).count(), 2);
+        assert!(html.contains(r##"href="#fnref-2""##));
+        assert!(html.contains(r#"class="footnote-backref""#));
+        // unreferenced definition is dropped
+        assert!(!html.contains("C note"));
+    }
+
+    #[test]
+    fn test_footnote_undefined() {
+        let html = md2html_with("text[^nope]", &RenderConfig::default());
+        assert!(html.contains("[^nope]"));
+        assert!(!html.contains("footnote"));
+    }
+
+    #[test]
+    fn test_lazy_images() {
+        let html = md2html_with("![cat](/static/upload/cat.jpeg)", &RenderConfig::default());
+        assert!(html.contains(r#"loading="lazy""#));
+        assert!(html.contains(r#"decoding="async""#));
+
+        let html = clean_html_with(
+            r#"<img src="/a.png" loading="eager">"#,
+            &RenderConfig::default(),
+        );
+        assert!(html.contains(r#"loading="eager""#));
+        assert!(!html.contains(r#"loading="lazy""#));
+    }
+
+    #[test]
+    fn test_image_proxy() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"test");
+        let config = RenderConfig {
+            host: "freedit.eu".into(),
+            image_proxy: Some(key.clone()),
+            ..Default::default()
+        };
+        let url = "http://example.com/cat.png?a=1&b=2";
+        let md =
+            format!("![cat]({url}) ![dog](/static/upload/dog.png) ![x](https://freedit.eu/x.png)");
+        let html = md2html_with(&md, &config);
+        let signed = image_proxy::signed_url(&key, url).replace('&', "&amp;");
+        assert!(html.contains(&format!(r#"src="{signed}""#)));
+        assert!(html.contains(r#"src="/static/upload/dog.png""#));
+        assert!(html.contains(r#"src="https://freedit.eu/x.png""#));
+
+        let html = md2html_with(&md, &RenderConfig::default());
+        assert!(!html.contains("/imgproxy"));
+    }
+
+    #[test]
+    fn test_raw_html() {
+        let md = "<div>raw</div>\n\ninline <span>html</span>\n\n```rust\nlet a = 1;\n```";
+        let html = md2html_with(md, &RenderConfig::default());
+        assert!(html.contains("<div>raw</div>"));
+        assert!(html.contains("<span>html</span>"));
+
+        let config = RenderConfig {
+            escape_raw_html: true,
+            ..Default::default()
+        };
+        let html = md2html_with(md, &config);
+        assert!(html.contains("&lt;div&gt;raw&lt;/div&gt;"));
+        assert!(html.contains("&lt;span&gt;html&lt;/span&gt;"));
+        assert!(!html.contains("<div>"));
+        // code blocks are still highlighted
+        assert!(html.contains("<pre"));
+    }
+
+    #[test]
+    fn test_emoji_shortcodes() {
+        let mut config = RenderConfig {
+            emoji_shortcodes: true,
+            ..Default::default()
+        };
+        config
+            .custom_emojis
+            .insert("ferris".into(), "/static/upload/ferris.png".into());
+
+        let html = md2html_with("hi :smile: :ferris: :nope: `:smile:`", &config);
+        assert!(html.contains("hi 😄 "));
+        assert!(html.contains(r#"src="/static/upload/ferris.png" alt=":ferris:""#));
+        assert!(html.contains(":nope:"));
+        assert!(html.contains("<code>:smile:</code>"));
+
+        let html = md2html_with("```\n:smile:\n```", &config);
+        assert!(html.contains(":smile:"));
+
+        let html = md2html_with(":smile:", &RenderConfig::default());
+        assert!(html.contains(":smile:"));
+    }
+
+    #[test]
+    fn test_shift_headings() {
+        let md = "# Title\n\n## Section\n\n###### Deep";
+        let html = md2html_with(md, &RenderConfig::default());
+        assert!(html.contains("<h1>Title</h1>"));
+
+        let config = RenderConfig {
+            shift_headings: true,
+            ..Default::default()
+        };
+        let html = md2html_with(md, &config);
+        assert!(!html.contains("<h1"));
+        assert!(html.contains("<h2>Title</h2>"));
+        assert!(html.contains("<h3>Section</h3>"));
+        assert!(html.contains("<h6>Deep</h6>"));
+    }
+}
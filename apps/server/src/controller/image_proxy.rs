@@ -0,0 +1,280 @@
+//! `GET /imgproxy` re-serves external images of rendered markdown, so readers don't connect
+//! to third-party hosts and `http` images don't cause mixed content.
+//!
+//! Only urls signed by the renderer are fetched, so the endpoint can't be used as an open
+//! proxy. Hosts are only connected to on public addresses, also after a redirect.
+
+use super::SiteConfig;
+use crate::{config::CONFIG, error::AppError, DB};
+use axum::{
+    body::Bytes,
+    extract::Query,
+    http::{HeaderMap, HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use cached::{Cached, SizedCache};
+use data_encoding::BASE64URL_NOPAD;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Client,
+};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Deserialize;
+use sled::Db;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+/// Images larger than this are not proxied
+const MAX_PROXY_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+
+const MAX_REDIRECTS: usize = 5;
+
+/// svg is left out on purpose, as it can carry scripts
+const ALLOWED_TYPES: [&str; 5] = [
+    "image/avif",
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/webp",
+];
+
+pub(super) static PROXY_KEY: LazyLock<hmac::Key> =
+    LazyLock::new(|| proxy_key(&DB).expect("failed to load image proxy key"));
+
+/// Like [feed::CLIENT](super::feed::CLIENT), but every redirect is checked by [is_fetchable]
+/// and hosts only resolve to public addresses. With `CONFIG.proxy` set the proxy resolves
+/// hosts, so it has to keep the local network out itself.
+static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    let redirect = redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if is_fetchable(attempt.url().as_str()) {
+            attempt.follow()
+        } else {
+            attempt.error("redirect to a url that is not allowed")
+        }
+    });
+    let mut client = Client::builder()
+        .timeout(Duration::from_secs(6))
+        .redirect(redirect)
+        .dns_resolver(Arc::new(PublicResolver));
+    if !CONFIG.proxy.is_empty() {
+        let proxy = reqwest::Proxy::all(&CONFIG.proxy).unwrap();
+        client = client.proxy(proxy);
+    }
+    client.build().unwrap()
+});
+
+/// Resolves a host to its public addresses only, so a name pointing at the local network
+/// can't be fetched. Checking the resolved addresses also rules out dns rebinding.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("no public address for {}", name.as_str()).into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// `url` to `(content_type, body)`
+static IMAGE_CACHE: LazyLock<Mutex<SizedCache<String, (&'static str, Bytes)>>> =
+    LazyLock::new(|| Mutex::new(SizedCache::with_size(64)));
+
+/// Load the signing key from the default tree, generating it on first use.
+fn proxy_key(db: &Db) -> Result<hmac::Key, AppError> {
+    let mut secret = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| AppError::Custom("failed to generate image proxy key".into()))?;
+    // keep the existing key if another thread got there first
+    let _ = db.compare_and_swap("imgproxy_key", None as Option<&[u8]>, Some(&secret[..]))?;
+    let secret = db.get("imgproxy_key")?.ok_or(AppError::NotFound)?;
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, &secret))
+}
+
+/// The local proxy url of an external image.
+pub(super) fn signed_url(key: &hmac::Key, url: &str) -> String {
+    let tag = hmac::sign(key, url.as_bytes());
+    format!(
+        "/imgproxy?url={}&sig={}",
+        BASE64URL_NOPAD.encode(url.as_bytes()),
+        BASE64URL_NOPAD.encode(tag.as_ref())
+    )
+}
+
+/// Decode the url and check its signature.
+fn verify(key: &hmac::Key, url: &str, sig: &str) -> Option<String> {
+    let url = BASE64URL_NOPAD.decode(url.as_bytes()).ok()?;
+    let sig = BASE64URL_NOPAD.decode(sig.as_bytes()).ok()?;
+    hmac::verify(key, &url, &sig).ok()?;
+    String::from_utf8(url).ok()
+}
+
+/// Only `http(s)` urls, and no ip literals of the local network.
+pub(super) fn is_fetchable(url: &str) -> bool {
+    let Some(rest) = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+    else {
+        return false;
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority.rsplit_once('@').map_or(authority, |(_, h)| h);
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    if host.is_empty() || host.eq_ignore_ascii_case("localhost") {
+        return false;
+    }
+    host.parse::<IpAddr>().map_or(true, is_public)
+}
+
+/// Not an address of this host or the local network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // shared address space, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || segment & 0xfe00 == 0xfc00
+                || segment & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+async fn fetch(url: &str) -> Result<(&'static str, Bytes), AppError> {
+    if !is_fetchable(url) {
+        return Err(AppError::Custom("Image url not allowed".into()));
+    }
+
+    let mut res = CLIENT.get(url).send().await?.error_for_status()?;
+    let content_type = res
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_lowercase())
+        .unwrap_or_default();
+    let content_type = ALLOWED_TYPES
+        .into_iter()
+        .find(|t| *t == content_type)
+        .ok_or_else(|| AppError::Custom(format!("Unsupported image type: {content_type}")))?;
+
+    let too_large = || AppError::Custom("Image too large".into());
+    if res
+        .content_length()
+        .is_some_and(|len| len > MAX_PROXY_IMAGE_BYTES as u64)
+    {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        if body.len() + chunk.len() > MAX_PROXY_IMAGE_BYTES {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok((content_type, Bytes::from(body)))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ParamsImageProxy {
+    url: String,
+    sig: String,
+}
+
+/// `GET /imgproxy`
+pub(crate) async fn image_proxy(
+    Query(params): Query<ParamsImageProxy>,
+) -> Result<Response, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    if !site_config.image_proxy {
+        return Err(AppError::NotFound);
+    }
+    let url = verify(&PROXY_KEY, &params.url, &params.sig).ok_or(AppError::Unauthorized)?;
+
+    let cached = IMAGE_CACHE.lock().unwrap().cache_get(&url).cloned();
+    let (content_type, body) = match cached {
+        Some(image) => image,
+        None => {
+            let image = fetch(&url).await?;
+            IMAGE_CACHE.lock().unwrap().cache_set(url, image.clone());
+            image
+        }
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        HeaderName::from_static("content-type"),
+        HeaderValue::from_static(content_type),
+    );
+    headers.insert(
+        HeaderName::from_static("cache-control"),
+        HeaderValue::from_static("public, max-age=86400"),
+    );
+    headers.insert(
+        HeaderName::from_static("x-content-type-options"),
+        HeaderValue::from_static("nosniff"),
+    );
+    Ok((headers, body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_url() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"test");
+        let url = "https://example.com/cat.png?a=1&b=2";
+        let signed = signed_url(&key, url);
+        let query = signed.strip_prefix("/imgproxy?url=").unwrap();
+        let (encoded, sig) = query.split_once("&sig=").unwrap();
+        assert_eq!(verify(&key, encoded, sig).as_deref(), Some(url));
+
+        let other = BASE64URL_NOPAD.encode(b"https://example.com/dog.png");
+        assert!(verify(&key, &other, sig).is_none());
+
+        assert!(is_fetchable(url));
+        assert!(!is_fetchable("http://127.0.0.1/a.png"));
+        assert!(!is_fetchable("http://[::1]:8080/a.png"));
+        assert!(!is_fetchable("http://user@192.168.1.1/a.png"));
+        assert!(!is_fetchable("file:///etc/passwd"));
+        assert!(!is_fetchable("http://169.254.169.254/latest/meta-data/"));
+        assert!(!is_fetchable("http://[::ffff:10.0.0.1]/a.png"));
+        assert!(!is_fetchable("http://100.64.0.1/a.png"));
+    }
+
+    #[tokio::test]
+    async fn test_public_resolver() {
+        let name: Name = "localhost".parse().unwrap();
+        assert!(PublicResolver.resolve(name).await.is_err());
+    }
+}
@@ -13,15 +13,16 @@
 
 use super::{
     db_utils::{
-        extract_element, get_batch, get_count, get_count_by_prefix, get_id_by_name,
+        base62_decode, extract_element, get_batch, get_count, get_count_by_prefix, get_id_by_name,
         get_ids_by_prefix, get_ids_by_tag, get_one, get_range, is_valid_name, ivec_to_u32, set_one,
         set_one_with_key, u32_to_ivec, u8_slice_to_u32, IterType,
     },
     feed::{inn_feed_to_post, update},
-    fmt::{clean_html, md2html, ts_to_date},
+    fmt::{clean_html, is_valid_tracker, linked_pids, md2html, summarize, SUMMARY_MAX_CHARS},
     incr_id,
     meta_handler::{PageData, ParamsPage},
     notification::{add_notification, mark_read, NtType},
+    rate_limit::check_inn_flood,
     user::{InnRole, Role},
     Claim, Comment, Feed, FormPost, Inn, InnType, Post, PostContent, PostStatus, SiteConfig, User,
 };
@@ -43,7 +44,11 @@ use rinja_axum::{into_response, Template};
 use serde::Deserialize;
 use sled::{transaction::ConflictableTransactionError, Transactional};
 use sled::{Batch, Db};
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{LazyLock, Mutex},
+};
+use tracing::info_span;
 
 /// Page data: `inn_create.html`
 #[derive(Template)]
@@ -59,6 +64,7 @@ struct PageInnEdit<'a> {
     page_data: PageData<'a>,
     inn: Inn,
     inn_feeds: Vec<Feed>,
+    issue_tracker: String,
 }
 
 /// `GET /mod/:iid` inn create/edit page
@@ -103,10 +109,12 @@ pub(crate) async fn mod_inn(
             let feed: Feed = get_one(&DB, "feeds", feed_id)?;
             inn_feeds.push(feed);
         }
+        let issue_tracker = Inn::issue_tracker(&DB, iid)?.unwrap_or_default();
         let page_inn_edit = PageInnEdit {
             page_data,
             inn,
             inn_feeds,
+            issue_tracker,
         };
         Ok(into_response(&page_inn_edit))
     }
@@ -129,6 +137,9 @@ pub(crate) struct FormInn {
     early_birds: u32,
     #[garde(skip)]
     limit_edit_seconds: u32,
+    #[serde(default)]
+    #[garde(length(max = 256))]
+    issue_tracker: String,
 }
 
 /// `POST /mod/:iid` inn create/edit page
@@ -146,6 +157,11 @@ pub(crate) async fn mod_inn_post(
 
     let inn_name_key = inn_name.replace(' ', "_").to_lowercase();
 
+    let issue_tracker = input.issue_tracker.trim();
+    if !issue_tracker.is_empty() && !is_valid_tracker(issue_tracker) {
+        return Err(AppError::Custom("Invalid issue tracker url".into()));
+    }
+
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
@@ -309,6 +325,13 @@ pub(crate) async fn mod_inn_post(
         DB.open_tree("inns_private")?.insert(&iid_ivec, &[])?;
     }
 
+    let issue_trackers_tree = DB.open_tree("inn_issue_trackers")?;
+    if issue_tracker.is_empty() {
+        issue_trackers_tree.remove(&iid_ivec)?;
+    } else {
+        issue_trackers_tree.insert(&iid_ivec, issue_tracker)?;
+    }
+
     set_one(&DB, "inns", iid, &inn)?;
     inn_names_tree.insert(inn_name_key, iid_ivec)?;
 
@@ -694,6 +717,10 @@ pub(crate) async fn edit_post_post(
         return Err(AppError::LockedOrHidden);
     }
 
+    if old_pid == 0 && inn_role < InnRole::Mod && Role::from(claim.role) != Role::Admin {
+        check_inn_flood(&DB, &site_config, iid, created_at)?;
+    }
+
     let pid = if old_pid == 0 {
         incr_id(&DB, "posts_count")?
     } else {
@@ -789,6 +816,13 @@ pub(crate) async fn edit_post_post(
     };
 
     set_one(&DB, "posts", pid, &post)?;
+    update_summary(&DB, &post, &site_config)?;
+    let targets = if site_config.backlinks {
+        linked_pids(&content, &site_config.domain)
+    } else {
+        BTreeSet::new()
+    };
+    update_backlinks(&DB, pid, &targets)?;
 
     let iid_ivec = u32_to_ivec(iid);
     if old_pid == 0 {
@@ -825,10 +859,11 @@ struct OutPostList {
     uid: u32,
     username: String,
     title: String,
-    created_at: String,
+    created_at: i64,
     comment_count: u32,
     last_reply: Option<(u32, String)>,
     is_pinned: bool,
+    summary: Option<String>,
 }
 
 /// Page data: `tag.html`
@@ -1166,6 +1201,7 @@ struct Entry {
     pid: u32,
     updated: String,
     author: (String, u32),
+    summary: Option<String>,
     content: String,
 }
 
@@ -1190,7 +1226,7 @@ pub(crate) async fn inn_feed(Path(i): Path<String>) -> Result<impl IntoResponse,
 
     if iid == 0 {
         index = get_pids_all(&DB, &[], &page_params, false)?;
-        title = site_config.site_name;
+        title = site_config.site_name.clone();
         description = md2html(&site_config.description);
     } else {
         let inn: Inn = get_one(&DB, "inns", iid)?;
@@ -1208,7 +1244,10 @@ pub(crate) async fn inn_feed(Path(i): Path<String>) -> Result<impl IntoResponse,
     for i in index.into_iter() {
         let post: Post = get_one(&DB, "posts", i)?;
         let user: User = get_one(&DB, "users", post.uid)?;
-        let content = post.content.to_html(&DB)?;
+        let url = format!("{}/post/{}/{}", site_config.domain, post.iid, post.pid);
+        let content = post
+            .content
+            .to_excerpt_html(&DB, post.iid, &url, &site_config)?;
         let updated = Timestamp::from_second(post.created_at)
             .unwrap()
             .strftime("%Y-%m-%dT%H:%M:%SZ")
@@ -1219,6 +1258,7 @@ pub(crate) async fn inn_feed(Path(i): Path<String>) -> Result<impl IntoResponse,
             pid: post.pid,
             updated,
             author: (user.username, user.uid),
+            summary: get_summary(&DB, post.pid)?,
             content,
         };
 
@@ -1239,6 +1279,65 @@ pub(crate) async fn inn_feed(Path(i): Path<String>) -> Result<impl IntoResponse,
     Ok(into_response(&page_atom))
 }
 
+/// Store the summary of a long post in `post_summaries`, see [summarize]. Summaries of short
+/// posts are removed, as they were edited or summaries were turned off.
+fn update_summary(db: &Db, post: &Post, site_config: &SiteConfig) -> Result<(), AppError> {
+    let tree = db.open_tree("post_summaries")?;
+    let k = u32_to_ivec(post.pid);
+    if let PostContent::Markdown(ref md) = post.content {
+        if site_config.auto_summary && md.chars().count() >= site_config.summary_min_chars {
+            let summary = summarize(md, SUMMARY_MAX_CHARS);
+            if !summary.is_empty() {
+                tree.insert(k, summary.as_bytes())?;
+                return Ok(());
+            }
+        }
+    }
+    tree.remove(k)?;
+    Ok(())
+}
+
+/// Replace the links of post `pid` in `backlinks` with `targets`, so links removed by an edit
+/// are gone from the index too. `post_links` keeps the targets of each post for that.
+fn update_backlinks(db: &Db, pid: u32, targets: &BTreeSet<u32>) -> Result<(), AppError> {
+    let backlinks = db.open_tree("backlinks")?;
+    let post_links = db.open_tree("post_links")?;
+    let pid_ivec = u32_to_ivec(pid);
+    for i in post_links.scan_prefix(&pid_ivec) {
+        let (k, _) = i?;
+        let target = u8_slice_to_u32(&k[4..8]);
+        if !targets.contains(&target) {
+            backlinks.remove([&u32_to_ivec(target), &pid_ivec].concat())?;
+            post_links.remove(k)?;
+        }
+    }
+    for target in targets {
+        if *target == pid {
+            continue;
+        }
+        let target_ivec = u32_to_ivec(*target);
+        backlinks.insert([&target_ivec, &pid_ivec].concat(), &[])?;
+        post_links.insert([&pid_ivec, &target_ivec].concat(), &[])?;
+    }
+    Ok(())
+}
+
+/// Pids of the posts linking to post `pid`
+fn get_backlinks(db: &Db, pid: u32) -> Result<Vec<u32>, AppError> {
+    let mut pids = Vec::new();
+    for i in db.open_tree("backlinks")?.scan_prefix(u32_to_ivec(pid)) {
+        let (k, _) = i?;
+        pids.push(u8_slice_to_u32(&k[4..8]));
+    }
+    Ok(pids)
+}
+
+/// Summary of a post, if it is long enough to have one
+fn get_summary(db: &Db, pid: u32) -> Result<Option<String>, AppError> {
+    let v = db.open_tree("post_summaries")?.get(u32_to_ivec(pid))?;
+    Ok(v.map(|v| String::from_utf8_lossy(&v).into_owned()))
+}
+
 /// get [OutPostList] from pids
 fn get_out_post_list(db: &Db, index: &[u32]) -> Result<Vec<OutPostList>, AppError> {
     let mut post_lists = Vec::with_capacity(index.len());
@@ -1246,7 +1345,6 @@ fn get_out_post_list(db: &Db, index: &[u32]) -> Result<Vec<OutPostList>, AppErro
         for pid in index {
             let post: Post = get_one(db, "posts", *pid)?;
             let user: User = get_one(db, "users", post.uid)?;
-            let date = ts_to_date(post.created_at);
             let inn: Inn = get_one(db, "inns", post.iid)?;
             let comment_count =
                 get_count_by_prefix(db, "post_comments", &u32_to_ivec(*pid))? as u32;
@@ -1267,6 +1365,7 @@ fn get_out_post_list(db: &Db, index: &[u32]) -> Result<Vec<OutPostList>, AppErro
             let k = [&u32_to_ivec(post.iid), &u32_to_ivec(post.pid)].concat();
             let is_pinned = db.open_tree("post_pins")?.contains_key(k)?;
 
+            let summary = get_summary(db, post.pid)?;
             let post_list = OutPostList {
                 pid: post.pid,
                 iid: post.iid,
@@ -1274,10 +1373,11 @@ fn get_out_post_list(db: &Db, index: &[u32]) -> Result<Vec<OutPostList>, AppErro
                 uid: post.uid,
                 username: user.username,
                 title: post.title,
-                created_at: date,
+                created_at: post.created_at,
                 comment_count,
                 last_reply,
                 is_pinned,
+                summary,
             };
             post_lists.push(post_list);
         }
@@ -1327,7 +1427,11 @@ fn get_pids_all(
 }
 
 /// get pids by multi iids, sorted by timestamp
-fn get_pids_by_iids(db: &Db, iids: &[u32], page_params: &ParamsPage) -> Result<Vec<u32>, AppError> {
+pub(super) fn get_pids_by_iids(
+    db: &Db,
+    iids: &[u32],
+    page_params: &ParamsPage,
+) -> Result<Vec<u32>, AppError> {
     let mut pids = Vec::with_capacity(page_params.n);
     let mut pairs = Vec::new();
     for iid in iids {
@@ -1451,7 +1555,7 @@ struct OutPost {
     tags: Vec<String>,
     content_html: String,
     og_content: String,
-    created_at: String,
+    created_at: i64,
     upvotes: usize,
     downvotes: usize,
     status: String,
@@ -1476,6 +1580,9 @@ struct PagePost<'a> {
     is_mod: bool,
     is_author: bool,
     can_delete: bool,
+    /// broken outbound links and why, only for the author and mods
+    dead_links: Vec<(String, String)>,
+    has_backlinks: bool,
 }
 
 /// Vec data: Comment
@@ -1484,7 +1591,7 @@ struct OutComment {
     uid: u32,
     username: String,
     content: String,
-    created_at: String,
+    created_at: i64,
     upvotes: usize,
     downvotes: usize,
     is_upvoted: bool,
@@ -1500,25 +1607,31 @@ pub(crate) struct ParamsPost {
     nid: Option<u32>,
 }
 
-/// `GET /inn/:iid/:pid` post page
-pub(crate) async fn post(
-    cookie: Option<TypedHeader<Cookie>>,
-    Path((iid, pid)): Path<(u32, u32)>,
-    Query(params): Query<ParamsPost>,
-) -> Result<impl IntoResponse, AppError> {
-    let site_config = SiteConfig::get(&DB)?;
-    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+/// Page data: `reader.html`
+#[derive(Template)]
+#[template(path = "reader.html", escape = "none")]
+struct PageReader<'a> {
+    page_data: PageData<'a>,
+    pid: u32,
+    iid: u32,
+    inn_name: String,
+    username: String,
+    created_at: i64,
+    content_html: String,
+}
 
-    let post: Post = get_one(&DB, "posts", pid)?;
-    let user: User = get_one(&DB, "users", post.uid)?;
-    let date = ts_to_date(post.created_at);
+/// Load a post the viewer is allowed to read, checking the inn is open and, for private inns,
+/// that the viewer has joined.
+fn readable_post(claim: Option<&Claim>, iid: u32, pid: u32) -> Result<(Post, Inn), AppError> {
+    let post: Post =
+        info_span!("db_get", tree = "posts", pid).in_scope(|| get_one(&DB, "posts", pid))?;
     let inn: Inn = get_one(&DB, "inns", post.iid)?;
     if inn.is_closed() {
         return Err(AppError::LockedOrHidden);
     }
 
     if InnType::from(inn.inn_type) == InnType::Private {
-        match claim.as_ref() {
+        match claim {
             Some(claim) => {
                 let k = [&u32_to_ivec(claim.uid), &u32_to_ivec(iid)].concat();
                 if !DB.open_tree("user_inns")?.contains_key(k)?
@@ -1535,6 +1648,118 @@ pub(crate) async fn post(
         return Err(AppError::NotFound);
     }
 
+    Ok((post, inn))
+}
+
+/// `GET /reader/post/:iid/:pid` a lightweight page of the post, without site chrome, scripts
+/// or comments, for slow connections, screen readers and printing
+pub(crate) async fn reader_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid)): Path<(u32, u32)>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+
+    let (post, inn) = readable_post(claim.as_ref(), iid, pid)?;
+    let user: User = get_one(&DB, "users", post.uid)?;
+    let content_html = match post.status {
+        PostStatus::HiddenByMod => "<p><i>Hidden by mod.</i></p>".into(),
+        PostStatus::HiddenByUser => "<p><i>Hidden by user.</i></p>".into(),
+        _ => post.content.to_html(&DB, post.iid)?,
+    };
+
+    let page_reader = PageReader {
+        page_data: PageData::new(&post.title, &site_config, claim, false),
+        pid: post.pid,
+        iid: post.iid,
+        inn_name: inn.inn_name,
+        username: user.username,
+        created_at: post.created_at,
+        content_html,
+    };
+
+    Ok(into_response(&page_reader))
+}
+
+/// Page data: `backlinks.html`
+#[derive(Template)]
+#[template(path = "backlinks.html")]
+struct PageBacklinks<'a> {
+    page_data: PageData<'a>,
+    posts: Vec<OutPostList>,
+    iid: u32,
+    pid: u32,
+    title: String,
+}
+
+/// `GET /post/:iid/:pid/backlinks` posts linking to the post, that the viewer can read
+pub(crate) async fn backlinks(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid)): Path<(u32, u32)>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    if !site_config.backlinks {
+        return Err(AppError::NotFound);
+    }
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+    let (post, _) = readable_post(claim.as_ref(), iid, pid)?;
+
+    let mut index = Vec::new();
+    for source in get_backlinks(&DB, pid)? {
+        let Ok(source_post) = get_one::<Post>(&DB, "posts", source) else {
+            continue;
+        };
+        let hidden = matches!(
+            source_post.status,
+            PostStatus::HiddenByUser | PostStatus::HiddenByMod
+        );
+        if !hidden && readable_post(claim.as_ref(), source_post.iid, source).is_ok() {
+            index.push(source);
+        }
+    }
+    index.reverse();
+    let out_post_list = get_out_post_list(&DB, &index)?;
+
+    let has_unread = if let Some(ref claim) = claim {
+        User::has_unread(&DB, claim.uid)?
+    } else {
+        false
+    };
+    let page_data = PageData::new(&post.title, &site_config, claim, has_unread);
+    let page_backlinks = PageBacklinks {
+        page_data,
+        posts: out_post_list,
+        iid,
+        pid,
+        title: post.title,
+    };
+
+    Ok(into_response(&page_backlinks))
+}
+
+/// `GET /p/:code` short permalink, redirects to the post page
+pub(crate) async fn permalink(Path(code): Path<String>) -> Result<impl IntoResponse, AppError> {
+    Ok(Redirect::to(&permalink_target(&DB, &code)?))
+}
+
+fn permalink_target(db: &Db, code: &str) -> Result<String, AppError> {
+    let pid = base62_decode(code).ok_or(AppError::NotFound)?;
+    let post: Post = get_one(db, "posts", pid)?;
+    Ok(format!("/post/{}/{}", post.iid, post.pid))
+}
+
+/// `GET /inn/:iid/:pid` post page
+pub(crate) async fn post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid)): Path<(u32, u32)>,
+    Query(params): Query<ParamsPost>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+
+    let (post, inn) = readable_post(claim.as_ref(), iid, pid)?;
+    let user: User = get_one(&DB, "users", post.uid)?;
+
     let mut has_joined = false;
     let mut is_upvoted = false;
     let mut is_downvoted = false;
@@ -1592,7 +1817,11 @@ pub(crate) async fn post(
         PostStatus::HiddenByMod => "Hidden by mod.".into(),
         PostStatus::HiddenByUser => "Hidden by user.".into(),
         _ => match post.content {
-            PostContent::Markdown(ref content) => escape(content, Html).unwrap().to_string(),
+            PostContent::Markdown(ref content) => {
+                let summary = get_summary(&DB, pid)?;
+                let og = summary.as_deref().unwrap_or(content);
+                escape(og, Html).unwrap().to_string()
+            }
             PostContent::FeedItemId(_) => "This post is auto-generated from RSS feed".into(),
         },
     };
@@ -1615,10 +1844,10 @@ pub(crate) async fn post(
                     "#,
                     diff
                 );
-                content.push_str(&post.content.to_html(&DB)?);
+                content.push_str(&post.content.to_html(&DB, post.iid)?);
                 content
             } else {
-                post.content.to_html(&DB)?
+                post.content.to_html(&DB, post.iid)?
             }
         }
     };
@@ -1637,7 +1866,7 @@ pub(crate) async fn post(
         status: post.status.to_string(),
         content_html: content,
         og_content,
-        created_at: date,
+        created_at: post.created_at,
         upvotes,
         downvotes,
         is_upvoted,
@@ -1664,7 +1893,6 @@ pub(crate) async fn post(
             if let Some(v) = v {
                 let (comment, _): (Comment, usize) = bincode::decode_from_slice(v, standard())?;
                 let user: User = get_one(&DB, "users", comment.uid)?;
-                let date = ts_to_date(comment.created_at);
 
                 let mut is_upvoted = false;
                 let mut is_downvoted = false;
@@ -1691,7 +1919,7 @@ pub(crate) async fn post(
                     uid: comment.uid,
                     username: user.username,
                     content: comment.content,
-                    created_at: date,
+                    created_at: comment.created_at,
                     upvotes,
                     downvotes,
                     is_upvoted,
@@ -1714,6 +1942,26 @@ pub(crate) async fn post(
         can_delete = true;
     }
 
+    let mut dead_links = Vec::new();
+    if is_author || is_mod {
+        for i in DB.open_tree("dead_links")?.scan_prefix(u32_to_ivec(pid)) {
+            let (k, v) = i?;
+            let url = String::from_utf8_lossy(&k[4..]);
+            let reason = String::from_utf8_lossy(&v);
+            dead_links.push((
+                escape(url.as_ref(), Html).unwrap().to_string(),
+                escape(reason.as_ref(), Html).unwrap().to_string(),
+            ));
+        }
+    }
+
+    let has_backlinks = site_config.backlinks
+        && DB
+            .open_tree("backlinks")?
+            .scan_prefix(u32_to_ivec(pid))
+            .next()
+            .is_some();
+
     let pageview = incr_id(&DB.open_tree("post_pageviews")?, u32_to_ivec(pid))?;
     let has_unread = if let Some(ref claim) = claim {
         User::has_unread(&DB, claim.uid)?
@@ -1735,6 +1983,8 @@ pub(crate) async fn post(
         is_mod,
         is_author,
         can_delete,
+        dead_links,
+        has_backlinks,
     };
 
     Ok(into_response(&page_post))
@@ -1891,12 +2141,44 @@ struct PagePreview<'a> {
     content: String,
 }
 
-/// `POST /preview`
+/// Form data: `/preview`, shared by the post, comment and solo editors
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormPreview {
+    #[garde(length(min = 1, max = 65535))]
+    content: String,
+}
+
+/// Max previews per user per minute
+const PREVIEW_PER_MIN: u32 = 30;
+
+/// `uid` to `(minute, previews in that minute)`
+static PREVIEW_COUNTS: LazyLock<Mutex<HashMap<u32, (i64, u32)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn preview_allowed(counts: &mut HashMap<u32, (i64, u32)>, uid: u32, now: i64) -> bool {
+    let minute = now / 60;
+    counts.retain(|_, (m, _)| *m == minute);
+    let (_, n) = counts.entry(uid).or_insert((minute, 0));
+    *n += 1;
+    *n <= PREVIEW_PER_MIN
+}
+
+/// `POST /preview` renders markdown with the same pipeline as posts, without saving it
 pub(crate) async fn preview(
-    WithValidation(input): WithValidation<Form<FormComment>>,
+    cookie: Option<TypedHeader<Cookie>>,
+    WithValidation(input): WithValidation<Form<FormPreview>>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
-    let page_data = PageData::new("preview", &site_config, None, false);
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+
+    let now = Timestamp::now().as_second();
+    if !preview_allowed(&mut PREVIEW_COUNTS.lock().unwrap(), claim.uid, now) {
+        return Err(AppError::WriteInterval);
+    }
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("preview", &site_config, Some(claim), has_unread);
 
     let page_preview = PagePreview {
         page_data,
@@ -2088,6 +2370,8 @@ pub(crate) async fn post_delete(
     if count == 0 && post.uid == claim.uid {
         post.content = PostContent::Markdown("*Post deleted by author.*".into());
         set_one(&DB, "posts", pid, &post)?;
+        DB.open_tree("post_summaries")?.remove(u32_to_ivec(pid))?;
+        update_backlinks(&DB, pid, &BTreeSet::new())?;
 
         // remove this post from inn timeline
         inn_rm_index(&DB, iid, pid)?;
@@ -2235,3 +2519,118 @@ pub(crate) async fn post_pin(
     let target = format!("/post/{iid}/{pid}");
     Ok(Redirect::to(&target))
 }
+
+/// Export an inn with its posts and comments as pretty printed json.
+pub fn export_inn(db: &Db, iid: u32) -> Result<String, AppError> {
+    let inn: Inn = get_one(db, "inns", iid)?;
+
+    let mut posts = Vec::new();
+    for i in db.open_tree("inn_posts")?.scan_prefix(u32_to_ivec(iid)) {
+        let (k, _) = i?;
+        let pid = u8_slice_to_u32(&k[4..8]);
+        let post: Post = get_one(db, "posts", pid)?;
+
+        let mut comments = Vec::new();
+        for i in db.open_tree("post_comments")?.scan_prefix(u32_to_ivec(pid)) {
+            let (_, v) = i?;
+            let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+            comments.push(comment);
+        }
+        posts.push(serde_json::json!({ "post": post, "comments": comments }));
+    }
+
+    let export = serde_json::json!({ "inn": inn, "posts": posts });
+    serde_json::to_string_pretty(&export).map_err(|e| AppError::Custom(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::db_utils::base62_encode;
+
+    #[test]
+    fn test_reader_page() {
+        let site_config = SiteConfig::default();
+        let page_reader = PageReader {
+            page_data: PageData::new("Reader title", &site_config, None, false),
+            pid: 2,
+            iid: 1,
+            inn_name: "inn".into(),
+            username: "alice".into(),
+            created_at: 0,
+            content_html: md2html("Some **bold** words."),
+        };
+        let html = page_reader.render().unwrap();
+
+        assert!(html.contains("<h1>Reader title</h1>"));
+        assert!(html.contains("<strong>bold</strong>"));
+        assert!(html.contains(r#"href="/post/1/2""#));
+        assert!(!html.contains("<nav"));
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("<form"));
+    }
+
+    #[test]
+    fn test_permalink() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let post = Post {
+            pid: 4000,
+            uid: 1,
+            iid: 7,
+            title: "title".into(),
+            tags: vec![],
+            content: PostContent::Markdown("content".into()),
+            created_at: 0,
+            status: PostStatus::Normal,
+        };
+        set_one(&db, "posts", post.pid, &post).unwrap();
+
+        let code = base62_encode(post.pid);
+        assert_eq!(code, "12W");
+        assert_eq!(permalink_target(&db, &code).unwrap(), "/post/7/4000");
+        assert!(matches!(
+            permalink_target(&db, "12X"),
+            Err(AppError::NotFound)
+        ));
+        assert!(matches!(
+            permalink_target(&db, "not-base62"),
+            Err(AppError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_backlinks() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let domain = "http://127.0.0.1:3001";
+        let save = |pid, md| update_backlinks(&db, pid, &linked_pids(md, domain)).unwrap();
+
+        // adding posts linking to post 1, and one linking to itself
+        save(2, "As said in [[1]].");
+        save(3, "See [the first post](/post/7/1) and [[3]].");
+        assert_eq!(get_backlinks(&db, 1).unwrap(), vec![2, 3]);
+        assert!(get_backlinks(&db, 3).unwrap().is_empty());
+
+        // editing a link away, or to another post
+        save(2, "As said before.");
+        save(3, "See [[2]] instead.");
+        assert!(get_backlinks(&db, 1).unwrap().is_empty());
+        assert_eq!(get_backlinks(&db, 2).unwrap(), vec![3]);
+
+        // deleting the linking post
+        update_backlinks(&db, 3, &BTreeSet::new()).unwrap();
+        assert!(get_backlinks(&db, 2).unwrap().is_empty());
+        assert!(db.open_tree("post_links").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_preview_allowed() {
+        let mut counts = HashMap::new();
+        for _ in 0..PREVIEW_PER_MIN {
+            assert!(preview_allowed(&mut counts, 1, 600));
+        }
+        assert!(!preview_allowed(&mut counts, 1, 659));
+        assert!(preview_allowed(&mut counts, 2, 659));
+        // a new minute starts over
+        assert!(preview_allowed(&mut counts, 1, 660));
+    }
+}
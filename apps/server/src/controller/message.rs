@@ -1,7 +1,7 @@
 use axum::{
     extract::{Path, Query},
     response::{IntoResponse, Redirect},
-    Form,
+    Extension, Form,
 };
 use axum_extra::{headers::Cookie, TypedHeader};
 use rinja_axum::{into_response, Template};
@@ -12,7 +12,7 @@ use crate::{controller::fmt::clean_html, error::AppError, DB};
 
 use super::{
     db_utils::{get_one, incr_id, u32_to_ivec, u8_slice_to_u32},
-    meta_handler::PageData,
+    meta_handler::{CspNonce, PageData},
     notification::{add_notification, mark_read, NtType},
     Claim, SiteConfig, User,
 };
@@ -25,11 +25,13 @@ struct PageMessage<'a> {
     pub_key: Option<String>,
     receiver_id: u32,
     receiver_name: String,
+    csp_nonce: String,
 }
 
 /// `GET /message/:uid`
 pub(crate) async fn message(
     cookie: Option<TypedHeader<Cookie>>,
+    Extension(CspNonce(csp_nonce)): Extension<CspNonce>,
     Path(uid): Path<u32>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookie.ok_or(AppError::NonLogin)?;
@@ -57,6 +59,7 @@ pub(crate) async fn message(
         page_data: PageData::new(&title, &site_config, Some(claim), false),
         pub_key,
         receiver_name: user.username,
+        csp_nonce,
     };
 
     Ok(into_response(&page_message))
@@ -103,11 +106,13 @@ pub(crate) async fn message_post(
 struct PageKey<'a> {
     page_data: PageData<'a>,
     pub_key: String,
+    csp_nonce: String,
 }
 
 /// `GET /key`
 pub(crate) async fn key(
     cookie: Option<TypedHeader<Cookie>>,
+    Extension(CspNonce(csp_nonce)): Extension<CspNonce>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
@@ -122,6 +127,7 @@ pub(crate) async fn key(
     let page_key = PageKey {
         page_data: PageData::new("Generate Key Pairs", &site_config, Some(claim), false),
         pub_key,
+        csp_nonce,
     };
 
     Ok(into_response(&page_key))
@@ -158,6 +164,7 @@ struct PageInbox<'a> {
     message: String,
     sender_id: u32,
     sender_name: String,
+    csp_nonce: String,
 }
 
 /// url params: `inbox.html`
@@ -169,6 +176,7 @@ pub(crate) struct ParamsInbox {
 /// `GET /inbox`
 pub(crate) async fn inbox(
     cookie: Option<TypedHeader<Cookie>>,
+    Extension(CspNonce(csp_nonce)): Extension<CspNonce>,
     Path(mid): Path<u32>,
     Query(params): Query<ParamsInbox>,
 ) -> Result<impl IntoResponse, AppError> {
@@ -203,6 +211,7 @@ pub(crate) async fn inbox(
         message,
         sender_id,
         sender_name: sender.username,
+        csp_nonce,
     };
 
     Ok(into_response(&page_inbox))
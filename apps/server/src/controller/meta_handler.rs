@@ -1,17 +1,31 @@
-use std::sync::LazyLock;
+use std::{
+    collections::VecDeque,
+    sync::{LazyLock, Mutex},
+};
 
-use super::{db_utils::u32_to_ivec, fmt::md2html, Claim, SiteConfig};
-use crate::{controller::filters, error::AppError, DB};
+use super::{
+    db_utils::u32_to_ivec,
+    fmt::{md2html, video_frame_src},
+    user::{get_timezone, Role, TimeDisplay},
+    Claim, SiteConfig,
+};
+use crate::{controller::filters, error::AppError, DB, VERSION};
 use axum::{
+    extract::{Query, Request},
     http::{HeaderMap, HeaderValue, Uri},
+    middleware::Next,
     response::{IntoResponse, Redirect, Response},
 };
 use axum_extra::{
-    headers::{Cookie, Referer},
+    headers::{Cookie, HeaderMapExt, Referer},
     TypedHeader,
 };
+use data_encoding::BASE64URL_NOPAD;
 use http::{HeaderName, StatusCode};
+use jiff::Timestamp;
+use ring::rand::{SecureRandom, SystemRandom};
 use rinja_axum::{into_response, Template};
+use serde::Deserialize;
 use tracing::error;
 
 #[derive(Template)]
@@ -27,6 +41,7 @@ impl IntoResponse for AppError {
         let status = match self {
             AppError::CaptchaError
             | AppError::NameExists
+            | AppError::NameUnavailable
             | AppError::InnCreateLimit
             | AppError::NameInvalid
             | AppError::WrongPassword
@@ -36,16 +51,22 @@ impl IntoResponse for AppError {
             | AppError::ValidationError(_)
             | AppError::NoJoinedInn
             | AppError::Custom(_)
+            | AppError::InvalidInvite
+            | AppError::Infected(_)
             | AppError::AxumFormRejection(_) => StatusCode::BAD_REQUEST,
             AppError::NotFound => StatusCode::NOT_FOUND,
-            AppError::WriteInterval => StatusCode::TOO_MANY_REQUESTS,
+            AppError::WriteInterval | AppError::InnFlood => StatusCode::TOO_MANY_REQUESTS,
             AppError::NonLogin => return Redirect::to("/signin").into_response(),
             AppError::Unauthorized => StatusCode::UNAUTHORIZED,
-            AppError::Banned => StatusCode::FORBIDDEN,
+            AppError::Banned | AppError::RegistrationClosed => StatusCode::FORBIDDEN,
+            AppError::Maintenance | AppError::ScanUnavailable => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
         error!("{}, {}", status, self);
+        if status.is_server_error() {
+            record_error(format!("{status}, {self}"));
+        }
         let site_config = SiteConfig::get(&DB).unwrap_or_default();
         let page_data = PageData::new("Error", &site_config, None, false);
         let page_error = PageError {
@@ -58,11 +79,131 @@ impl IntoResponse for AppError {
     }
 }
 
+/// How many server errors are kept for the admin dashboard
+const RECENT_ERRORS_MAX: usize = 10;
+
+/// `(timestamp, error)` of the latest server errors since startup, newest first
+static RECENT_ERRORS: LazyLock<Mutex<VecDeque<(i64, String)>>> =
+    LazyLock::new(|| Mutex::new(VecDeque::with_capacity(RECENT_ERRORS_MAX)));
+
+fn record_error(error: String) {
+    let mut errors = RECENT_ERRORS.lock().unwrap();
+    if errors.len() == RECENT_ERRORS_MAX {
+        errors.pop_back();
+    }
+    errors.push_front((Timestamp::now().as_second(), error));
+}
+
+/// The latest server errors, newest first. They are not persisted, so a restart clears them.
+pub(super) fn recent_errors() -> Vec<(i64, String)> {
+    RECENT_ERRORS.lock().unwrap().iter().cloned().collect()
+}
+
 pub(crate) async fn handler_404(uri: Uri) -> impl IntoResponse {
     error!("No route for {}", uri);
     AppError::NotFound
 }
 
+/// Paths that stay reachable in maintenance mode, so admins can sign in and probes keep working
+const MAINTENANCE_EXEMPT: [&str; 6] = [
+    "/signin",
+    "/signout",
+    "/static/",
+    "/favicon.svg",
+    "/robots.txt",
+    "/api/health",
+];
+
+/// Middleware: with [SiteConfig] `maintenance` on, everyone except admins gets 503.
+pub(crate) async fn maintenance(req: Request, next: Next) -> Response {
+    let Ok(site_config) = SiteConfig::get(&DB) else {
+        return next.run(req).await;
+    };
+    let claim = if site_config.maintenance {
+        req.headers()
+            .typed_get::<Cookie>()
+            .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
+    } else {
+        None
+    };
+
+    if is_under_maintenance(&site_config, req.uri().path(), claim.as_ref()) {
+        AppError::Maintenance.into_response()
+    } else {
+        next.run(req).await
+    }
+}
+
+/// Per-request nonce of inline `<script>`, added to the `Content-Security-Policy` header
+#[derive(Clone)]
+pub(crate) struct CspNonce(pub(super) String);
+
+/// Middleware: add the [SiteConfig] content security policy to html responses.
+///
+/// Templates may set a stricter policy with `<meta http-equiv>`; browsers enforce both.
+pub(crate) async fn csp(req: Request, next: Next) -> Response {
+    let site_config = SiteConfig::get(&DB).unwrap_or_default();
+    with_csp(&site_config, req, next).await
+}
+
+async fn with_csp(site_config: &SiteConfig, mut req: Request, next: Next) -> Response {
+    let mut nonce = [0u8; 16];
+    if SystemRandom::new().fill(&mut nonce).is_err() {
+        error!("failed to generate csp nonce");
+    }
+    let nonce = BASE64URL_NOPAD.encode(&nonce);
+    req.extensions_mut().insert(CspNonce(nonce.clone()));
+
+    let mut res = next.run(req).await;
+    let is_html = res
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    if is_html {
+        let name = if site_config.csp_report_only {
+            "content-security-policy-report-only"
+        } else {
+            "content-security-policy"
+        };
+        if let Ok(value) = HeaderValue::from_str(&csp_policy(site_config, &nonce)) {
+            res.headers_mut()
+                .insert(HeaderName::from_static(name), value);
+        }
+    }
+    res
+}
+
+fn csp_policy(site_config: &SiteConfig, nonce: &str) -> String {
+    let mut policy = format!(
+        "default-src 'self'; script-src {} 'nonce-{nonce}'; style-src {}; img-src {}; \
+        connect-src {}; object-src 'none'; base-uri 'self'; form-action 'self'; \
+        frame-ancestors 'none'",
+        site_config.csp_script_src,
+        site_config.csp_style_src,
+        site_config.csp_img_src,
+        site_config.csp_connect_src,
+    );
+    if !site_config.video_embed_hosts.is_empty() || !site_config.embed_providers.is_empty() {
+        policy.push_str("; frame-src 'self' ");
+        policy.push_str(&video_frame_src(
+            &site_config.video_embed_hosts,
+            &site_config.embed_providers,
+        ));
+    }
+    if !site_config.csp_report_uri.is_empty() {
+        policy.push_str("; report-uri ");
+        policy.push_str(&site_config.csp_report_uri);
+    }
+    policy
+}
+
+fn is_under_maintenance(site_config: &SiteConfig, path: &str, claim: Option<&Claim>) -> bool {
+    site_config.maintenance
+        && !MAINTENANCE_EXEMPT.iter().any(|p| path.starts_with(p))
+        && !claim.is_some_and(|claim| Role::from(claim.role) == Role::Admin)
+}
+
 pub(crate) async fn home(
     cookie: Option<TypedHeader<Cookie>>,
 ) -> Result<impl IntoResponse, AppError> {
@@ -104,70 +245,164 @@ static CSS: LazyLock<String> = LazyLock::new(|| {
     css
 });
 
-pub(crate) async fn style() -> (HeaderMap, &'static str) {
-    let mut headers = HeaderMap::new();
+static CSS_HASH: LazyLock<String> = LazyLock::new(|| content_hash(CSS.as_bytes()));
+
+/// Content hash of the bundled css, used as `?v=` in `/static/style.css` links.
+pub(super) fn css_hash() -> &'static str {
+    &CSS_HASH
+}
 
+/// A short hex digest of `data`, used for `ETag` and hashed asset urls.
+fn content_hash(data: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+    data_encoding::HEXLOWER.encode(&digest.as_ref()[0..8])
+}
+
+const CACHE_CONTROL: &str = "public, max-age=1209600, s-maxage=86400";
+pub(crate) const CACHE_CONTROL_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Serve a bundled asset with `ETag` and answer `If-None-Match` with `304 Not Modified`.
+fn static_asset(
+    req_headers: &HeaderMap,
+    content_type: &'static str,
+    cache_control: &'static str,
+    etag: &str,
+    body: &'static str,
+) -> Response {
+    let etag = format!(r#""{etag}""#);
+    let mut headers = HeaderMap::new();
     headers.insert(
-        HeaderName::from_static("content-type"),
-        HeaderValue::from_static("text/css"),
+        HeaderName::from_static("cache-control"),
+        HeaderValue::from_static(cache_control),
     );
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        headers.insert(HeaderName::from_static("etag"), value);
+    }
+
+    let not_modified = req_headers
+        .get("if-none-match")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim() == etag || t.trim() == "*"));
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+
     headers.insert(
-        HeaderName::from_static("cache-control"),
-        HeaderValue::from_static("public, max-age=1209600, s-maxage=86400"),
+        HeaderName::from_static("content-type"),
+        HeaderValue::from_static(content_type),
     );
+    (headers, body).into_response()
+}
 
-    (headers, &CSS)
+#[derive(Deserialize)]
+pub(crate) struct ParamsStyle {
+    v: Option<String>,
 }
 
-pub(crate) async fn favicon() -> (HeaderMap, &'static str) {
-    let mut headers = HeaderMap::new();
+/// `GET /static/style.css`. Requests with the current content hash as `v` are cached forever.
+pub(crate) async fn style(Query(params): Query<ParamsStyle>, headers: HeaderMap) -> Response {
+    let cache_control = if params.v.as_deref() == Some(css_hash()) {
+        CACHE_CONTROL_IMMUTABLE
+    } else {
+        CACHE_CONTROL
+    };
+    static_asset(&headers, "text/css", cache_control, css_hash(), &CSS)
+}
+
+const FAVICON: &str = include_str!("../../static/favicon.svg");
+const ENCRYPTION_JS: &str = include_str!("../../static/js/encryption-helper.js");
+const ENCODING_JS: &str = include_str!("../../static/js/encoding-helper.js");
+
+static FAVICON_HASH: LazyLock<String> = LazyLock::new(|| content_hash(FAVICON.as_bytes()));
+static ENCRYPTION_JS_HASH: LazyLock<String> =
+    LazyLock::new(|| content_hash(ENCRYPTION_JS.as_bytes()));
+static ENCODING_JS_HASH: LazyLock<String> = LazyLock::new(|| content_hash(ENCODING_JS.as_bytes()));
+
+pub(crate) async fn favicon(headers: HeaderMap) -> Response {
+    static_asset(
+        &headers,
+        "image/svg+xml",
+        CACHE_CONTROL,
+        &FAVICON_HASH,
+        FAVICON,
+    )
+}
+
+pub(crate) async fn encryption_js(headers: HeaderMap) -> Response {
+    static_asset(
+        &headers,
+        "text/javascript",
+        CACHE_CONTROL,
+        &ENCRYPTION_JS_HASH,
+        ENCRYPTION_JS,
+    )
+}
+
+pub(crate) async fn encoding_js(headers: HeaderMap) -> Response {
+    static_asset(
+        &headers,
+        "text/javascript",
+        CACHE_CONTROL,
+        &ENCODING_JS_HASH,
+        ENCODING_JS,
+    )
+}
 
+/// `GET /static/theme.css`, the accent color of [SiteConfig]. Pages link it with the color as
+/// `v`, so a new color is a new url.
+pub(crate) async fn theme() -> Response {
+    let site_config = SiteConfig::get(&DB).unwrap_or_default();
+    let mut headers = HeaderMap::new();
     headers.insert(
         HeaderName::from_static("content-type"),
-        HeaderValue::from_static("image/svg+xml"),
+        HeaderValue::from_static("text/css"),
     );
     headers.insert(
         HeaderName::from_static("cache-control"),
-        HeaderValue::from_static("public, max-age=1209600, s-maxage=86400"),
+        HeaderValue::from_static(CACHE_CONTROL),
     );
+    (headers, theme_css(&site_config.accent_color)).into_response()
+}
 
-    let favicon = include_str!("../../static/favicon.svg");
+fn theme_css(accent_color: &str) -> String {
+    if accent_color.is_empty() {
+        return String::new();
+    }
+    format!(
+        ":root {{ --accent: {accent_color}; }}\n\
+        a, .has-text-link {{ color: var(--accent); }}\n\
+        .button.is-link, .tag.is-link, .notification.is-link {{ background-color: var(--accent); }}\n"
+    )
+}
 
-    (headers, favicon)
+pub(crate) async fn robots() -> &'static str {
+    include_str!("../../static/robots.txt")
 }
 
-pub(crate) async fn encryption_js() -> (HeaderMap, &'static str) {
+/// `GET /api/health` readiness probe. It stays 200 in maintenance mode, which is reported
+/// in the body instead.
+pub(crate) async fn api_health() -> (HeaderMap, String) {
     let mut headers = HeaderMap::new();
     headers.insert(
         HeaderName::from_static("content-type"),
-        HeaderValue::from_static("text/javascript"),
+        HeaderValue::from_static("application/json"),
     );
-    headers.insert(
-        HeaderName::from_static("cache-control"),
-        HeaderValue::from_static("public, max-age=1209600, s-maxage=86400"),
-    );
-    let js = include_str!("../../static/js/encryption-helper.js");
+    let maintenance = SiteConfig::get(&DB).is_ok_and(|site_config| site_config.maintenance);
+    let health = serde_json::json!({ "status": "ok", "maintenance": maintenance }).to_string();
 
-    (headers, js)
+    (headers, health)
 }
 
-pub(crate) async fn encoding_js() -> (HeaderMap, &'static str) {
+/// `GET /api/version`
+pub(crate) async fn api_version() -> (HeaderMap, String) {
     let mut headers = HeaderMap::new();
     headers.insert(
         HeaderName::from_static("content-type"),
-        HeaderValue::from_static("text/javascript"),
-    );
-    headers.insert(
-        HeaderName::from_static("cache-control"),
-        HeaderValue::from_static("public, max-age=1209600, s-maxage=86400"),
+        HeaderValue::from_static("application/json"),
     );
-    let js = include_str!("../../static/js/encoding-helper.js");
+    let version = serde_json::json!({ "version": VERSION }).to_string();
 
-    (headers, js)
-}
-
-pub(crate) async fn robots() -> &'static str {
-    include_str!("../../static/robots.txt")
+    (headers, version)
 }
 
 pub(super) struct PageData<'a> {
@@ -177,6 +412,17 @@ pub(super) struct PageData<'a> {
     pub(super) claim: Option<Claim>,
     pub(super) has_unread: bool,
     pub(super) lang: String,
+    /// [SiteConfig] `relative_time`, unless the user chose, see [TimeDisplay]
+    pub(super) relative_time: bool,
+    /// IANA time zone of the user, UTC if empty
+    pub(super) timezone: String,
+    pub(super) logo: &'a str,
+    pub(super) favicon: &'a str,
+    /// [SiteConfig] `accent_color` without the leading `#`
+    pub(super) accent: &'a str,
+    /// CSP `frame-src` of the video players of [SiteConfig] `video_embed_hosts` and of the
+    /// `embed_providers`
+    pub(super) frame_src: String,
 }
 
 impl<'a> PageData<'a> {
@@ -191,6 +437,13 @@ impl<'a> PageData<'a> {
             .as_ref()
             .and_then(|claim| claim.lang.as_ref())
             .map_or_else(|| site_config.lang.clone(), |lang| lang.to_owned());
+        let uid = claim.as_ref().map(|claim| claim.uid);
+        let relative_time = match uid.map(|uid| TimeDisplay::get(&DB, uid)) {
+            Some(TimeDisplay::Relative) => true,
+            Some(TimeDisplay::Absolute) => false,
+            _ => site_config.relative_time,
+        };
+        let timezone = uid.map(|uid| get_timezone(&DB, uid)).unwrap_or_default();
 
         Self {
             title,
@@ -199,6 +452,15 @@ impl<'a> PageData<'a> {
             claim,
             has_unread,
             lang,
+            relative_time,
+            timezone,
+            logo: &site_config.logo,
+            favicon: &site_config.favicon,
+            accent: site_config.accent_color.trim_start_matches('#'),
+            frame_src: video_frame_src(
+                &site_config.video_embed_hosts,
+                &site_config.embed_providers,
+            ),
         }
     }
 }
@@ -221,3 +483,134 @@ pub(super) struct ParamsPage {
     pub(super) n: usize,
     pub(super) is_desc: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware::from_fn, response::Html, routing::get, Extension, Router};
+    use garde::Validate;
+    use tower::ServiceExt;
+
+    fn claim(role: Role) -> Claim {
+        Claim {
+            uid: 1,
+            username: "test".into(),
+            role: role as u8,
+            last_write: 0,
+            session_id: "test".into(),
+            lang: None,
+            last_seen: 0,
+            remember: false,
+        }
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let mut site_config = SiteConfig::default();
+        let user = claim(Role::Standard);
+        let admin = claim(Role::Admin);
+        assert!(!is_under_maintenance(&site_config, "/", Some(&user)));
+
+        site_config.maintenance = true;
+        assert!(is_under_maintenance(&site_config, "/", None));
+        assert!(is_under_maintenance(&site_config, "/inn/0", Some(&user)));
+        assert!(!is_under_maintenance(&site_config, "/inn/0", Some(&admin)));
+        assert!(!is_under_maintenance(&site_config, "/signin", None));
+        assert!(!is_under_maintenance(&site_config, "/api/health", None));
+        assert!(is_under_maintenance(&site_config, "/api/version", None));
+    }
+
+    #[test]
+    fn test_instance_branding() {
+        let mut site_config = SiteConfig {
+            site_name: "Acme Forum".into(),
+            logo: "/static/upload/logo_abc.png".into(),
+            accent_color: "#ff6600".into(),
+            ..Default::default()
+        };
+        // PageData::new renders the description with the global render config
+        let page_data = PageData {
+            title: "Error",
+            site_name: &site_config.site_name,
+            site_description: String::new(),
+            claim: None,
+            has_unread: false,
+            lang: "en".into(),
+            relative_time: false,
+            timezone: String::new(),
+            logo: &site_config.logo,
+            favicon: &site_config.favicon,
+            accent: site_config.accent_color.trim_start_matches('#'),
+            frame_src: "'none'".into(),
+        };
+        let html = PageError {
+            page_data,
+            status: "404".into(),
+            error: "Not found".into(),
+        }
+        .render()
+        .unwrap();
+        assert!(html.contains("<title>Acme Forum - Error</title>"));
+        assert!(html.contains(r#"src="/static/upload/logo_abc.png""#));
+        assert!(html.contains(r#"href="/static/theme.css?v=ff6600""#));
+        assert!(html.contains(r#"href="/favicon.svg""#));
+        assert!(theme_css(&site_config.accent_color).contains("--accent: #ff6600;"));
+
+        assert!(site_config.validate().is_ok());
+        site_config.accent_color = "red; }".into();
+        assert!(site_config.validate().is_err());
+        site_config.accent_color = String::new();
+        site_config.favicon = "https://example.com/x.png".into();
+        assert!(site_config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_csp() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(
+                    |Extension(CspNonce(nonce)): Extension<CspNonce>| async move {
+                        Html(format!(r#"<script nonce="{nonce}">1</script>"#))
+                    },
+                ),
+            )
+            .route("/version", get(api_version))
+            .layer(from_fn(|req: Request, next: Next| async move {
+                let mut site_config = SiteConfig::default();
+                site_config.csp_report_uri = "/csp-report".into();
+                with_csp(&site_config, req, next).await
+            }));
+
+        let req = http::Request::builder()
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        let policy = res.headers()["content-security-policy"]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert!(policy.contains("object-src 'none'"));
+        assert!(policy.ends_with("; report-uri /csp-report"));
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let nonce = body
+            .strip_prefix(r#"<script nonce=""#)
+            .and_then(|s| s.split('"').next())
+            .unwrap();
+        assert_eq!(nonce.len(), 22);
+        assert!(policy.contains(&format!("script-src 'self' 'nonce-{nonce}';")));
+
+        // only html responses get the header
+        let req = http::Request::builder()
+            .uri("/version")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert!(!res.headers().contains_key("content-security-policy"));
+    }
+}
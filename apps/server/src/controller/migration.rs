@@ -0,0 +1,323 @@
+//! ## Schema migrations
+//!
+//! The stored schema version lives in the `meta` tree. On startup every migration after that
+//! version is applied in order, and the version is bumped after each step, so a step only runs
+//! once. Steps should still be idempotent, as a crash may happen before the version is saved.
+
+use super::db_utils::{ivec_to_u32, u32_to_ivec};
+use super::{audit::AuditEntry, Claim, SiteConfig};
+use crate::error::AppError;
+use bincode::config::standard;
+use bincode::{Decode, Encode};
+use jiff::Timestamp;
+use sled::Db;
+use tracing::info;
+
+type Migration = fn(&Db) -> Result<(), AppError>;
+
+/// Ordered migration steps. The schema version after step `i` is `i + 1`.
+///
+/// Only append new steps, never reorder or remove old ones.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("add schema_version", |_| Ok(())),
+    ("add new site_config fields", migrate_site_config),
+    ("add last_seen to sessions", migrate_sessions_last_seen),
+    ("add remember to sessions", migrate_sessions_remember),
+    ("add audit_head", migrate_audit_head),
+];
+
+/// Apply pending migrations. Startup should be aborted if it fails.
+pub fn migrate(db: &Db) -> Result<(), AppError> {
+    run_migrations(db, MIGRATIONS)
+}
+
+fn run_migrations(db: &Db, migrations: &[(&str, Migration)]) -> Result<(), AppError> {
+    let meta_tree = db.open_tree("meta")?;
+    let version = meta_tree
+        .get("schema_version")?
+        .map_or(0, |v| ivec_to_u32(&v)) as usize;
+
+    if version > migrations.len() {
+        return Err(AppError::Custom(format!(
+            "db schema version {version} is newer than the latest known version {}",
+            migrations.len()
+        )));
+    }
+
+    for (idx, (name, migration)) in migrations.iter().enumerate().skip(version) {
+        let target = idx as u32 + 1;
+        info!("migrating db schema to version {target}: {name}");
+        migration(db)
+            .map_err(|e| AppError::Custom(format!("migration {target} ({name}) failed: {e}")))?;
+        meta_tree.insert("schema_version", u32_to_ivec(target))?;
+        db.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Decode `v` as `T` only if it takes up all of `v`.
+///
+/// bincode ignores trailing bytes, so a value of a newer layout may also decode as an older one.
+fn decode_exact<T: Decode>(v: &[u8]) -> Option<T> {
+    match bincode::decode_from_slice(v, standard()) {
+        Ok((one, len)) if len == v.len() => Some(one),
+        _ => None,
+    }
+}
+
+/// [SiteConfig] before the html, csp, appearance and inn flood settings were added
+#[derive(Encode, Decode)]
+struct SiteConfigV0 {
+    site_name: String,
+    domain: String,
+    description: String,
+    read_only: bool,
+    inn_mod_max: usize,
+    title_max_length: usize,
+    article_max_length: usize,
+    comment_max_length: usize,
+    solo_interval: i64,
+    post_interval: i64,
+    comment_interval: i64,
+    per_page: usize,
+    captcha_difficulty: String,
+    captcha_name: String,
+    home_page: u8,
+    spam_regex: Option<String>,
+    lang: String,
+}
+
+/// Keep the saved site config, with defaults for the new fields.
+fn migrate_site_config(db: &Db) -> Result<(), AppError> {
+    let Some(v) = db.get("site_config")? else {
+        return Ok(());
+    };
+    if decode_exact::<SiteConfig>(&v).is_some() {
+        return Ok(());
+    }
+
+    let (old, _): (SiteConfigV0, usize) = bincode::decode_from_slice(&v, standard())?;
+    let site_config = SiteConfig {
+        site_name: old.site_name,
+        domain: old.domain,
+        description: old.description,
+        read_only: old.read_only,
+        inn_mod_max: old.inn_mod_max,
+        title_max_length: old.title_max_length,
+        article_max_length: old.article_max_length,
+        comment_max_length: old.comment_max_length,
+        solo_interval: old.solo_interval,
+        post_interval: old.post_interval,
+        comment_interval: old.comment_interval,
+        per_page: old.per_page,
+        captcha_difficulty: old.captcha_difficulty,
+        captcha_name: old.captcha_name,
+        home_page: old.home_page,
+        spam_regex: old.spam_regex,
+        lang: old.lang,
+        ..SiteConfig::default()
+    };
+    db.insert(
+        "site_config",
+        bincode::encode_to_vec(&site_config, standard())?,
+    )?;
+    Ok(())
+}
+
+/// [Claim] before `last_seen` was added
+#[derive(Encode, Decode)]
+struct ClaimV0 {
+    uid: u32,
+    username: String,
+    role: u8,
+    last_write: i64,
+    session_id: String,
+    lang: Option<String>,
+}
+
+/// [Claim] before `remember` was added
+#[derive(Encode, Decode)]
+struct ClaimV1 {
+    uid: u32,
+    username: String,
+    role: u8,
+    last_write: i64,
+    session_id: String,
+    lang: Option<String>,
+    last_seen: i64,
+}
+
+/// Keep users logged in, counting their idle time from now on.
+fn migrate_sessions_last_seen(db: &Db) -> Result<(), AppError> {
+    let now = Timestamp::now().as_second();
+    let tree = db.open_tree("sessions")?;
+    for i in &tree {
+        let (k, v) = i?;
+        let Some(old) = decode_exact::<ClaimV0>(&v) else {
+            continue;
+        };
+
+        let claim = ClaimV1 {
+            uid: old.uid,
+            username: old.username,
+            role: old.role,
+            last_write: old.last_write,
+            session_id: old.session_id,
+            lang: old.lang,
+            last_seen: now,
+        };
+        tree.insert(k, bincode::encode_to_vec(&claim, standard())?)?;
+    }
+    Ok(())
+}
+
+/// Whether a session was "remember me" wasn't stored, so existing sessions are kept until
+/// their absolute expiry, as they were before the idle timeout.
+fn migrate_sessions_remember(db: &Db) -> Result<(), AppError> {
+    let tree = db.open_tree("sessions")?;
+    for i in &tree {
+        let (k, v) = i?;
+        let Some(old) = decode_exact::<ClaimV1>(&v) else {
+            continue;
+        };
+
+        let claim = Claim {
+            uid: old.uid,
+            username: old.username,
+            role: old.role,
+            last_write: old.last_write,
+            session_id: old.session_id,
+            lang: old.lang,
+            last_seen: old.last_seen,
+            remember: true,
+        };
+        tree.insert(k, bincode::encode_to_vec(&claim, standard())?)?;
+    }
+    Ok(())
+}
+
+/// The hash of the newest audit entry wasn't stored, so the chain so far is trusted.
+fn migrate_audit_head(db: &Db) -> Result<(), AppError> {
+    if db.contains_key("audit_head")? {
+        return Ok(());
+    }
+    if let Some((_, v)) = db.open_tree("audit_log")?.last()? {
+        let (entry, _): (AuditEntry, usize) = bincode::decode_from_slice(&v, standard())?;
+        db.insert("audit_head", entry.hash)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_migrations() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let migrations: &[(&str, Migration)] = &[
+            ("first", |db| {
+                db.insert("counter", u32_to_ivec(1))?;
+                Ok(())
+            }),
+            ("second", |db| {
+                let n = ivec_to_u32(&db.get("counter")?.unwrap());
+                db.insert("counter", u32_to_ivec(n + 1))?;
+                Ok(())
+            }),
+        ];
+
+        run_migrations(&db, migrations).unwrap();
+        let meta_tree = db.open_tree("meta").unwrap();
+        let version = meta_tree.get("schema_version").unwrap().unwrap();
+        assert_eq!(ivec_to_u32(&version), 2);
+        assert_eq!(ivec_to_u32(&db.get("counter").unwrap().unwrap()), 2);
+
+        // applied migrations are not run again
+        run_migrations(&db, migrations).unwrap();
+        assert_eq!(ivec_to_u32(&db.get("counter").unwrap().unwrap()), 2);
+
+        assert!(run_migrations(&db, &migrations[..1]).is_err());
+    }
+
+    #[test]
+    fn test_migrate_baseline_db() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let site_config = SiteConfigV0 {
+            site_name: "old site".into(),
+            domain: "example.com".into(),
+            description: "kept".into(),
+            read_only: true,
+            inn_mod_max: 3,
+            title_max_length: 100,
+            article_max_length: 65535,
+            comment_max_length: 5000,
+            solo_interval: 20,
+            post_interval: 30,
+            comment_interval: 40,
+            per_page: 25,
+            captcha_difficulty: "Hard".into(),
+            captcha_name: "Lines".into(),
+            home_page: 2,
+            spam_regex: Some("casino".into()),
+            lang: "zh_cn".into(),
+        };
+        db.insert(
+            "site_config",
+            bincode::encode_to_vec(&site_config, standard()).unwrap(),
+        )
+        .unwrap();
+        let claim = ClaimV0 {
+            uid: 7,
+            username: "alice".into(),
+            role: 10,
+            last_write: 1,
+            session_id: "ffffffff_abc".into(),
+            lang: Some("en".into()),
+        };
+        db.open_tree("sessions")
+            .unwrap()
+            .insert(
+                "ffffffff_abc",
+                bincode::encode_to_vec(&claim, standard()).unwrap(),
+            )
+            .unwrap();
+
+        run_migrations(&db, MIGRATIONS).unwrap();
+
+        let site_config = SiteConfig::get(&db).unwrap();
+        assert_eq!(site_config.site_name, "old site");
+        assert_eq!(site_config.domain, "example.com");
+        assert!(site_config.read_only);
+        assert_eq!(site_config.per_page, 25);
+        assert_eq!(site_config.spam_regex.as_deref(), Some("casino"));
+        assert_eq!(site_config.lang, "zh_cn");
+        let default = SiteConfig::default();
+        assert_eq!(site_config.html_allowed_tags, default.html_allowed_tags);
+        assert_eq!(
+            site_config.inn_post_window_secs,
+            default.inn_post_window_secs
+        );
+
+        let v = db
+            .open_tree("sessions")
+            .unwrap()
+            .get("ffffffff_abc")
+            .unwrap()
+            .unwrap();
+        let claim: Claim = decode_exact(&v).unwrap();
+        assert_eq!(claim.uid, 7);
+        assert_eq!(claim.username, "alice");
+        assert_eq!(claim.session_id, "ffffffff_abc");
+        assert_eq!(claim.lang.as_deref(), Some("en"));
+        assert!(claim.last_seen > 0);
+        assert!(claim.remember);
+
+        // the steps are idempotent
+        let meta_tree = db.open_tree("meta").unwrap();
+        meta_tree.remove("schema_version").unwrap();
+        run_migrations(&db, MIGRATIONS).unwrap();
+        assert_eq!(SiteConfig::get(&db).unwrap().site_name, "old site");
+    }
+}
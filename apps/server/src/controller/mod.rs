@@ -18,6 +18,12 @@
 //! | "tan"            | `ctype#id`           | `&[]`            |
 //! | "lang"           | `uid`                | `lang`           |
 //!
+//! ### invite
+//! | tree          | key          | value       |
+//! |---------------|--------------|-------------|
+//! | "invites"     | `code`       | [`Invite`]  |
+//! | "invite_uses" | `code#uid`   | `timestamp` |
+//!
 //! ### notification
 //! | tree            | key                   | value             |
 //! |-----------------|-----------------------|-------------------|
@@ -47,25 +53,44 @@
 //! | "sessions" | `timestamp_nanoid` | [`Claim`] |
 //!
 //! ### site config
-//! | tree      | key           | value          |
-//! |-----------|---------------|----------------|
-//! | default   | "site_config" | [`SiteConfig`] |
+//! | tree      | key            | value          |
+//! |-----------|----------------|----------------|
+//! | default   | "site_config"  | [`SiteConfig`] |
+//! | default   | "imgproxy_key" | hmac secret    |
+//!
+//! ### custom emoji
+//! | tree            | key         | value       |
+//! |-----------------|-------------|-------------|
+//! | "custom_emojis" | `shortcode` | `image_url` |
+//!
+//! ### meta
+//! | tree   | key              | value |
+//! |--------|------------------|-------|
+//! | "meta" | "schema_version" | N     |
+//!
+//! ### audit log
+//! | tree        | key           | value        |
+//! |-------------|---------------|--------------|
+//! | default     | "audit_count" | N            |
+//! | "audit_log" | `id`          | `AuditEntry` |
 //!
 //! ### inn
-//! | tree            | key           | value               |
-//! |-----------------|---------------|---------------------|
-//! | default         | "inns_count"  | N                   |
-//! | "inns"          | `iid`         | [`Inn`]             |
-//! | "inn_names"     | `inn_name`    | `iid`               |
-//! | "topics"        | `topic#iid`   | `&[]`               |
-//! | "mod_inns"      | `uid#iid`     | `&[]`               |
-//! | "user_inns"     | `uid#iid`     | `&[]`               |
-//! | "inn_apply"     | `iid#uid`     | `&[]`               |
-//! | "inn_users"     | `iid#uid`     | `&[1/2/3/4/5/8/10]` |
-//! | "inns_private"  | `iid`         | `&[]`               |
-//! | "drafts"        | `uid`         | [`FormPost`]        |
-//! | "inn_feeds"     | `iid#feed_id` | `uid`               |
-//! | "inn_items"     | `iid#item_id` | `&[]`               |
+//! | tree                 | key           | value               |
+//! |----------------------|---------------|---------------------|
+//! | default              | "inns_count"  | N                   |
+//! | "inns"               | `iid`         | [`Inn`]             |
+//! | "inn_names"          | `inn_name`    | `iid`               |
+//! | "topics"             | `topic#iid`   | `&[]`               |
+//! | "mod_inns"           | `uid#iid`     | `&[]`               |
+//! | "user_inns"          | `uid#iid`     | `&[]`               |
+//! | "inn_apply"          | `iid#uid`     | `&[]`               |
+//! | "inn_users"          | `iid#uid`     | `&[1/2/3/4/5/8/10]` |
+//! | "inns_private"       | `iid`         | `&[]`               |
+//! | "drafts"             | `uid`         | [`FormPost`]        |
+//! | "inn_feeds"          | `iid#feed_id` | `uid`               |
+//! | "inn_items"          | `iid#item_id` | `&[]`               |
+//! | "inn_keys"           | `iid`         | rsa pkcs8           |
+//! | "inn_issue_trackers" | `iid`         | "url"               |
 //!
 //! ### post
 //! | tree                | key                 | value                |
@@ -81,6 +106,11 @@
 //! | "post_timeline"     | `timestamp#iid#pid` | `inn_type`           |
 //! | "post_pageviews"    | `pid`               | N                    |
 //! | "post_pins"         | `iid#pid`           | `&[]`                |
+//! | "link_status"       | `url`               | `LinkStatus`         |
+//! | "dead_links"        | `pid#url`           | "reason"             |
+//! | "post_summaries"    | `pid`               | "summary"            |
+//! | "backlinks"         | `target_pid#pid`    | `&[]`                |
+//! | "post_links"        | `pid#target_pid`    | `&[]`                |
 //!
 //! ### comment
 //! | tree                  | key                  | value       |
@@ -114,10 +144,16 @@
 //! | "pub_keys"            | `uid`            | `pub_key`          |
 //! | "user_messages"       | `uid#mid`        | `&[]`              |
 
+pub(super) mod activitypub;
+pub(super) mod audit;
 pub(super) mod db_utils;
+pub(super) mod dead_links;
 pub(super) mod feed;
+pub(super) mod image_proxy;
 pub(super) mod meta_handler;
+pub(super) mod migration;
 pub(super) mod notification;
+pub(super) mod rate_limit;
 pub(super) mod tantivy;
 
 pub(super) mod admin;
@@ -132,7 +168,7 @@ mod fmt;
 use self::db_utils::{
     get_ids_by_prefix, get_one, incr_id, ivec_to_u32, u32_to_ivec, u8_slice_to_u32,
 };
-use self::fmt::md2html;
+use self::fmt::{md2html_in_inn, md_excerpt};
 use self::tantivy::{ToDoc, FIELDS};
 use self::user::Role;
 use crate::error::AppError;
@@ -301,6 +337,12 @@ impl Inn {
         InnType::from(self.inn_type) == InnType::Hidden
             || InnType::from(self.inn_type) == InnType::PrivateHidden
     }
+
+    /// Repository url that issue references in posts of the inn link to
+    fn issue_tracker(db: &Db, iid: u32) -> Result<Option<String>, AppError> {
+        let v = db.open_tree("inn_issue_trackers")?.get(u32_to_ivec(iid))?;
+        Ok(v.map(|v| String::from_utf8_lossy(&v).into_owned()))
+    }
 }
 
 #[derive(Encode, Decode, Serialize, PartialEq, PartialOrd, Debug, Clone)]
@@ -325,9 +367,12 @@ pub(super) enum PostContent {
 }
 
 impl PostContent {
-    fn to_html(&self, db: &Db) -> Result<String, AppError> {
+    /// Html of the content of a post in inn `iid`
+    fn to_html(&self, db: &Db, iid: u32) -> Result<String, AppError> {
         match self {
-            PostContent::Markdown(md) => Ok(md2html(md)),
+            PostContent::Markdown(md) => {
+                Ok(md2html_in_inn(md, Inn::issue_tracker(db, iid)?.as_deref()))
+            }
             PostContent::FeedItemId(id) => {
                 let item: Item = get_one(db, "items", *id)?;
                 let mut content = format!(
@@ -348,6 +393,34 @@ impl PostContent {
             }
         }
     }
+
+    /// Html of the excerpt, see [md_excerpt], with a link to the full post at `url`.
+    /// Posts of feed items are shown in full.
+    fn to_excerpt_html(
+        &self,
+        db: &Db,
+        iid: u32,
+        url: &str,
+        site_config: &SiteConfig,
+    ) -> Result<String, AppError> {
+        match self {
+            PostContent::Markdown(md) => {
+                let tracker = Inn::issue_tracker(db, iid)?;
+                match md_excerpt(md, site_config.excerpt_chars) {
+                    Some(excerpt) => {
+                        let read_more =
+                            filters::l10n("read_more", &site_config.lang).unwrap_or_default();
+                        Ok(format!(
+                            r#"{}<p><a href="{url}">{read_more}</a></p>"#,
+                            md2html_in_inn(excerpt, tracker.as_deref())
+                        ))
+                    }
+                    None => Ok(md2html_in_inn(md, tracker.as_deref())),
+                }
+            }
+            PostContent::FeedItemId(_) => self.to_html(db, iid),
+        }
+    }
 }
 
 impl Display for PostContent {
@@ -486,18 +559,176 @@ pub(super) struct SiteConfig {
     spam_regex: Option<String>,
     #[garde(length(max = 16))]
     lang: String,
+    #[garde(length(max = 1024))]
+    html_allowed_tags: String,
+    #[garde(length(max = 1024))]
+    html_allowed_attributes: String,
+    #[garde(length(max = 256))]
+    html_allowed_url_schemes: String,
+    #[garde(skip)]
+    autolink: bool,
+    #[garde(skip)]
+    nofollow: bool,
+    #[garde(skip)]
+    allow_raw_html: bool,
+    #[garde(skip)]
+    emoji_shortcodes: bool,
+    #[garde(skip)]
+    shift_headings: bool,
+    #[garde(skip)]
+    image_proxy: bool,
+    #[garde(skip)]
+    maintenance: bool,
+    #[garde(length(max = 512), custom(is_csp_sources))]
+    csp_script_src: String,
+    #[garde(length(max = 512), custom(is_csp_sources))]
+    csp_style_src: String,
+    #[garde(length(max = 512), custom(is_csp_sources))]
+    csp_img_src: String,
+    #[garde(length(max = 512), custom(is_csp_sources))]
+    csp_connect_src: String,
+    #[garde(skip)]
+    csp_report_only: bool,
+    #[garde(length(max = 256), custom(is_csp_sources))]
+    csp_report_uri: String,
+    #[garde(skip)]
+    relative_time: bool,
+    #[garde(skip)]
+    strip_tracking_params: bool,
+    #[garde(length(max = 256), custom(is_upload_url))]
+    logo: String,
+    #[garde(length(max = 256), custom(is_upload_url))]
+    favicon: String,
+    #[garde(custom(is_hex_color))]
+    accent_color: String,
+    #[garde(length(max = 512), custom(is_host_list))]
+    video_embed_hosts: String,
+    #[garde(range(max = 65535))]
+    excerpt_chars: usize,
+    #[garde(length(max = 10000))]
+    word_filter: String,
+    #[garde(length(max = 32), custom(is_plain_text))]
+    word_filter_replacement: String,
+    #[garde(length(max = 4096))]
+    banned_usernames: String,
+    #[garde(skip)]
+    heading_anchors: bool,
+    /// new posts allowed in an inn per `inn_post_window_secs`, 0 disables flood control
+    #[garde(skip)]
+    inn_post_limit: u32,
+    #[garde(range(min = 1))]
+    inn_post_window_secs: u32,
+    /// link `#123` in posts to the issue tracker of the inn
+    #[garde(skip)]
+    issue_refs: bool,
+    /// store an extractive summary of posts longer than `summary_min_chars`
+    #[garde(skip)]
+    auto_summary: bool,
+    #[garde(range(max = 65535))]
+    summary_min_chars: usize,
+    /// embeds of other sites than the known video hosts, see [fmt::EmbedProvider]
+    #[garde(length(max = 10000), custom(is_embed_providers))]
+    embed_providers: String,
+    /// index the posts linking to each post, shown as "what links here"
+    #[garde(skip)]
+    backlinks: bool,
+}
+
+/// Text without html special characters, as it is shown unescaped in titles.
+fn is_plain_text(value: &str, _: &()) -> garde::Result {
+    if value.contains(['<', '>', '&', '"', '\'']) {
+        Err(garde::Error::new("html special characters are not allowed"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Hosts separated by commas or whitespace. They are added to the CSP as is.
+fn is_host_list(value: &str, _: &()) -> garde::Result {
+    if value.chars().all(|c| {
+        c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == ',' || c.is_ascii_whitespace()
+    }) {
+        Ok(())
+    } else {
+        Err(garde::Error::new("only host names are allowed"))
+    }
+}
+
+/// One provider per line, see [fmt::EmbedProvider::parse_list].
+fn is_embed_providers(value: &str, _: &()) -> garde::Result {
+    fmt::EmbedProvider::parse_list(value)
+        .map(|_| ())
+        .map_err(garde::Error::new)
+}
+
+/// Empty, or a file of the upload path, as the built-in CSP only allows images of this site.
+fn is_upload_url(value: &str, _: &()) -> garde::Result {
+    let is_valid = value.is_empty()
+        || value.strip_prefix("/static/upload/").is_some_and(|name| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+        });
+    if is_valid {
+        Ok(())
+    } else {
+        Err(garde::Error::new("must be a file of /static/upload/"))
+    }
+}
+
+/// Empty, or a css color written as `#rgb` or `#rrggbb`.
+fn is_hex_color(value: &str, _: &()) -> garde::Result {
+    let is_valid = value.is_empty()
+        || value.strip_prefix('#').is_some_and(|hex| {
+            (hex.len() == 3 || hex.len() == 6) && hex.chars().all(|c| c.is_ascii_hexdigit())
+        });
+    if is_valid {
+        Ok(())
+    } else {
+        Err(garde::Error::new("must be a color like #3273dc"))
+    }
+}
+
+/// A source list of one CSP directive, so it can't end the directive or add another.
+fn is_csp_sources(value: &str, _: &()) -> garde::Result {
+    if value
+        .chars()
+        .any(|c| c == ';' || c == ',' || c.is_control())
+    {
+        Err(garde::Error::new(
+            "; , and control characters are not allowed",
+        ))
+    } else {
+        Ok(())
+    }
 }
 
 impl SiteConfig {
-    /// get [SiteConfig]
+    /// get [SiteConfig], the default one if it was never saved
+    ///
+    /// A stored config that fails to decode is an error rather than the default, so a layout
+    /// change without a [migration] can't silently reset it.
     fn get(db: &Db) -> Result<SiteConfig, AppError> {
-        let site_config = &db.get("site_config")?.unwrap_or_default();
+        let Some(site_config) = db.get("site_config")? else {
+            return Ok(SiteConfig::default());
+        };
         let (site_config, _): (SiteConfig, usize) =
-            bincode::decode_from_slice(site_config, standard()).unwrap_or_default();
+            bincode::decode_from_slice(&site_config, standard())?;
         Ok(site_config)
     }
 }
 
+/// Invite code for [RegistrationMode::Invite](crate::config::RegistrationMode)
+#[derive(Encode, Decode, Debug)]
+struct Invite {
+    uses_remaining: u32,
+    /// `None` means the code never expires
+    expires_at: Option<i64>,
+    created_by: u32,
+    created_at: i64,
+}
+
 #[derive(Encode, Decode)]
 struct Claim {
     uid: u32,
@@ -506,6 +737,9 @@ struct Claim {
     last_write: i64,
     session_id: String,
     lang: Option<String>,
+    last_seen: i64,
+    /// "remember me" sessions are not expired for being idle
+    remember: bool,
 }
 
 mod filters {
@@ -540,6 +774,31 @@ mod filters {
         i18n
     });
 
+    /// mask the words of [SiteConfig](super::SiteConfig) `word_filter`
+    pub(super) fn mask_words(s: &str) -> ::rinja::Result<String> {
+        Ok(super::fmt::mask_text(s))
+    }
+
+    /// short permalink code of a pid, see `/p/:code`
+    pub(super) fn base62(pid: &u32) -> ::rinja::Result<String> {
+        Ok(super::db_utils::base62_encode(*pid))
+    }
+
+    /// a timestamp as a date in the time zone `tz`, or relative like "3 hours ago" if `relative`
+    pub(super) fn timestamp(
+        timestamp: &i64,
+        lang: &str,
+        relative: &bool,
+        tz: &str,
+    ) -> ::rinja::Result<String> {
+        if *relative {
+            let now = jiff::Timestamp::now().as_second();
+            Ok(super::fmt::ts_to_relative(*timestamp, now, lang, tz))
+        } else {
+            Ok(super::fmt::ts_to_local_date(*timestamp, tz))
+        }
+    }
+
     pub(super) fn l10n(s: &str, lang: &str) -> ::rinja::Result<&'static str> {
         if let Some(v) = I18N.get(&(lang, s)) {
             Ok(v)
@@ -47,6 +47,7 @@ pub(super) enum NtType {
     PostHide = 11,
     CommentHide = 12,
     CommentMention = 13,
+    InnFlood = 14,
 }
 
 impl From<u8> for NtType {
@@ -65,6 +66,7 @@ impl From<u8> for NtType {
             11 => Self::PostHide,
             12 => Self::CommentHide,
             13 => Self::CommentMention,
+            14 => Self::InnFlood,
             _ => unreachable!(),
         }
     }
@@ -361,6 +363,23 @@ pub(crate) async fn notification(
                 };
                 notifications.push(notification);
             }
+            NtType::InnFlood => {
+                let iid = u8_slice_to_u32(&value[0..4]);
+                let limit = u8_slice_to_u32(&value[4..8]);
+                let inn: Inn = get_one(&DB, "inns", iid)?;
+                let content2 = format!(
+                    "Flood control: <a href='/inn/{iid}'>{}</a> reached {limit} new posts, more are refused for now",
+                    inn.inn_name
+                );
+                let notification = Notification {
+                    nid,
+                    uid: claim.uid,
+                    content1: String::new(),
+                    content2,
+                    is_read,
+                };
+                notifications.push(notification);
+            }
             NtType::SiteNotification => {
                 let role = u8_slice_to_u32(&value[0..4]);
                 let role_desc = Role::from(role as u8).to_string();
@@ -0,0 +1,351 @@
+//! Global request rate limit, a token bucket per signed-in user or per client ip. Also the
+//! flood control of new posts per inn.
+
+use super::{
+    notification::{add_notification, NtType},
+    user::Role,
+    Claim, SiteConfig, User,
+};
+use crate::{client_ip::ClientIp, config::CONFIG, error::AppError, DB};
+use axum::{
+    extract::Request,
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::headers::{Cookie, HeaderMapExt};
+use bincode::config::standard;
+use sled::Db;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+    time::Instant,
+};
+use tracing::warn;
+
+/// Paths that are never limited
+const RATE_LIMIT_EXEMPT: [&str; 4] = ["/static/", "/favicon.svg", "/robots.txt", "/api/health"];
+
+/// Idle buckets are dropped once there are this many
+const MAX_BUCKETS: usize = 10_000;
+
+static LIMITER: LazyLock<RateLimiter> =
+    LazyLock::new(|| RateLimiter::new(CONFIG.rate_global_per_min));
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum RateKey {
+    User(u32),
+    Ip(IpAddr),
+}
+
+impl RateKey {
+    /// An IPv6 client is keyed by its /64, as a host usually gets a whole /64 and could
+    /// otherwise rotate addresses to escape the limit.
+    fn ip(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V6(ip) => {
+                let prefix = u128::from(ip) & !(u128::from(u64::MAX));
+                Self::Ip(IpAddr::V6(prefix.into()))
+            }
+            ip => Self::Ip(ip),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// Refill for the time since the last request, then take one token. On failure returns
+    /// the seconds until a token is available.
+    fn take(&mut self, now: Instant, capacity: f64, per_sec: f64) -> Result<(), u64> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(capacity);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - self.tokens) / per_sec).ceil() as u64)
+        }
+    }
+}
+
+struct RateLimiter {
+    per_min: u32,
+    buckets: Mutex<HashMap<RateKey, Bucket>>,
+}
+
+impl RateLimiter {
+    fn new(per_min: u32) -> Self {
+        Self {
+            per_min,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check(&self, key: RateKey, now: Instant) -> Result<(), u64> {
+        let capacity = self.per_min as f64;
+        let per_sec = capacity / 60.0;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, b| {
+                b.tokens + now.saturating_duration_since(b.last).as_secs_f64() * per_sec < capacity
+            });
+        }
+        buckets
+            .entry(key)
+            .or_insert(Bucket {
+                tokens: capacity,
+                last: now,
+            })
+            .take(now, capacity, per_sec)
+    }
+}
+
+/// Middleware: limit requests to `rate_global_per_min` per user, or per client ip for
+/// visitors. `0` disables the limit.
+pub(crate) async fn rate_limit(req: Request, next: Next) -> Response {
+    if CONFIG.rate_global_per_min == 0
+        || RATE_LIMIT_EXEMPT
+            .iter()
+            .any(|p| req.uri().path().starts_with(p))
+    {
+        return next.run(req).await;
+    }
+
+    let uid = req.headers().typed_get::<Cookie>().and_then(|cookie| {
+        let site_config = SiteConfig::get(&DB).ok()?;
+        Claim::get(&DB, &cookie, &site_config).map(|claim| claim.uid)
+    });
+    let key = match uid {
+        Some(uid) => Some(RateKey::User(uid)),
+        None => req
+            .extensions()
+            .get::<ClientIp>()
+            .map(|ClientIp(ip)| RateKey::ip(*ip)),
+    };
+    limit(&LIMITER, key, req, next).await
+}
+
+async fn limit(limiter: &RateLimiter, key: Option<RateKey>, req: Request, next: Next) -> Response {
+    let Some(key) = key else {
+        return next.run(req).await;
+    };
+    match limiter.check(key, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            let mut res = (StatusCode::TOO_MANY_REQUESTS, "Too many requests").into_response();
+            res.headers_mut()
+                .insert("retry-after", HeaderValue::from(retry_after));
+            res
+        }
+    }
+}
+
+/// New posts of each inn, see [SiteConfig] `inn_post_limit`
+pub(super) static INN_POSTS: LazyLock<SlidingWindow> = LazyLock::new(SlidingWindow::default);
+
+/// Timestamps of the hits of each key within the window
+#[derive(Default)]
+pub(super) struct SlidingWindow {
+    /// hits, and whether the refusal of the current flood has been reported
+    keys: Mutex<HashMap<u32, (VecDeque<i64>, bool)>>,
+}
+
+impl SlidingWindow {
+    /// Count a hit at `now` if there are fewer than `max` in the last `window_secs`. When
+    /// refused, returns whether it's the first refusal since the last accepted hit.
+    pub(super) fn hit(&self, key: u32, now: i64, max: u32, window_secs: i64) -> Result<(), bool> {
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|_, (hits, _)| hits.back().is_some_and(|t| now - t < window_secs));
+        let (hits, reported) = keys.entry(key).or_default();
+        while hits.front().is_some_and(|t| now - t >= window_secs) {
+            hits.pop_front();
+        }
+        if hits.len() < max as usize {
+            hits.push_back(now);
+            *reported = false;
+            Ok(())
+        } else {
+            Err(!std::mem::replace(reported, true))
+        }
+    }
+}
+
+/// Refuse a new post in `iid` if the inn had `inn_post_limit` new posts in the window.
+/// Admins are notified once per flood.
+pub(super) fn check_inn_flood(
+    db: &Db,
+    site_config: &SiteConfig,
+    iid: u32,
+    now: i64,
+) -> Result<(), AppError> {
+    if site_config.inn_post_limit == 0 {
+        return Ok(());
+    }
+    let window_secs = i64::from(site_config.inn_post_window_secs);
+    match INN_POSTS.hit(iid, now, site_config.inn_post_limit, window_secs) {
+        Ok(()) => Ok(()),
+        Err(first_refusal) => {
+            if first_refusal {
+                warn!(iid, "flood control: new posts of the inn are refused");
+                notify_admins(db, iid, site_config.inn_post_limit)?;
+            }
+            Err(AppError::InnFlood)
+        }
+    }
+}
+
+fn notify_admins(db: &Db, iid: u32, limit: u32) -> Result<(), AppError> {
+    for i in &db.open_tree("users")? {
+        let (_, v) = i?;
+        let (user, _): (User, usize) = bincode::decode_from_slice(&v, standard())?;
+        if Role::from(user.role) == Role::Admin {
+            add_notification(db, user.uid, NtType::InnFlood, iid, limit)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware::from_fn, routing::get, Router};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_bucket_refill() {
+        let t0 = Instant::now();
+        // 60 per minute, one token every second
+        let mut bucket = Bucket {
+            tokens: 60.0,
+            last: t0,
+        };
+        for _ in 0..60 {
+            assert!(bucket.take(t0, 60.0, 1.0).is_ok());
+        }
+        assert_eq!(bucket.take(t0, 60.0, 1.0), Err(1));
+        assert_eq!(
+            bucket.take(t0 + Duration::from_millis(500), 60.0, 1.0),
+            Err(1)
+        );
+        assert!(bucket
+            .take(t0 + Duration::from_millis(1000), 60.0, 1.0)
+            .is_ok());
+        assert_eq!(
+            bucket.take(t0 + Duration::from_millis(1000), 60.0, 1.0),
+            Err(1)
+        );
+
+        // refill stops at capacity
+        let t1 = t0 + Duration::from_secs(3600);
+        for _ in 0..60 {
+            assert!(bucket.take(t1, 60.0, 1.0).is_ok());
+        }
+        assert!(bucket.take(t1, 60.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_rate_key_ipv6_prefix() {
+        let ip = |s: &str| RateKey::ip(s.parse().unwrap());
+        assert_eq!(ip("2001:db8::1"), ip("2001:db8::ffff:1234:5678:9abc"));
+        assert_eq!(
+            ip("2001:db8::1"),
+            RateKey::Ip("2001:db8::".parse().unwrap())
+        );
+        assert_ne!(ip("2001:db8::1"), ip("2001:db8:0:1::1"));
+        assert_eq!(ip("192.0.2.1"), RateKey::Ip("192.0.2.1".parse().unwrap()));
+        assert_eq!(ip("::ffff:192.0.2.1"), ip("192.0.2.1"));
+        assert_ne!(ip("192.0.2.1"), ip("192.0.2.2"));
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let window = SlidingWindow::default();
+        assert!(window.hit(1, 0, 2, 60).is_ok());
+        assert!(window.hit(1, 30, 2, 60).is_ok());
+        assert_eq!(window.hit(1, 40, 2, 60), Err(true));
+        assert_eq!(window.hit(1, 50, 2, 60), Err(false));
+        // other inns are not affected
+        assert!(window.hit(2, 50, 2, 60).is_ok());
+        // the first hit left the window
+        assert!(window.hit(1, 60, 2, 60).is_ok());
+        assert_eq!(window.hit(1, 61, 2, 60), Err(true));
+        assert!(window.hit(1, 200, 2, 60).is_ok());
+    }
+
+    #[test]
+    fn test_inn_flood_cap() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        for (uid, role) in [(1, Role::Admin), (2, Role::Standard)] {
+            let user = User {
+                uid,
+                role: role as u8,
+                ..Default::default()
+            };
+            crate::set_one(&db, "users", uid, &user).unwrap();
+        }
+        let site_config = SiteConfig {
+            inn_post_limit: 3,
+            inn_post_window_secs: 600,
+            ..Default::default()
+        };
+
+        // an iid no other test uses, as the window is shared
+        let iid = 9001;
+        for now in 0..3 {
+            check_inn_flood(&db, &site_config, iid, now).unwrap();
+        }
+        for now in 3..6 {
+            assert!(matches!(
+                check_inn_flood(&db, &site_config, iid, now),
+                Err(AppError::InnFlood)
+            ));
+        }
+        check_inn_flood(&db, &site_config, iid, 600).unwrap();
+
+        // only the admin is notified, once
+        let notifications = db.open_tree("notifications").unwrap();
+        assert_eq!(notifications.len(), 1);
+        let (k, v) = notifications.first().unwrap().unwrap();
+        assert_eq!(k[0..4], 1u32.to_be_bytes());
+        assert_eq!(k[8], NtType::InnFlood as u8);
+        assert_eq!(
+            v[0..8],
+            [&iid.to_be_bytes()[..], &3u32.to_be_bytes()].concat()
+        );
+    }
+
+    static TEST_LIMITER: LazyLock<RateLimiter> = LazyLock::new(|| RateLimiter::new(2));
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(from_fn(move |req: Request, next: Next| async move {
+                limit(&TEST_LIMITER, Some(RateKey::Ip(ip)), req, next).await
+            }));
+
+        for _ in 0..2 {
+            let req = http::Request::builder()
+                .uri("/")
+                .body(Body::empty())
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let req = http::Request::builder()
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "30");
+    }
+}
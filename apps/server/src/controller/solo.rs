@@ -3,7 +3,7 @@ use super::{
         extract_element, get_count_by_prefix, get_id_by_name, get_ids_by_tag, get_range, set_one,
         IterType,
     },
-    fmt::{md2html, ts_to_date},
+    fmt::md2html,
     get_ids_by_prefix, get_one, incr_id, ivec_to_u32,
     meta_handler::{get_referer, PageData, ParamsPage},
     notification::{add_notification, mark_read, NtType},
@@ -62,7 +62,7 @@ struct OutSolo {
     sid: u32,
     username: String,
     content: String,
-    created_at: String,
+    created_at: i64,
     solo_type: u32,
     like: bool,
     like_count: usize,
@@ -75,7 +75,6 @@ impl OutSolo {
     fn get(db: &Db, sid: u32, current_uid: Option<u32>) -> Result<Option<Self>, AppError> {
         let solo: Solo = get_one(db, "solos", sid)?;
         let user: User = get_one(db, "users", solo.uid)?;
-        let date = ts_to_date(solo.created_at);
 
         let mut can_visit = false;
         let solo_type = SoloType::from(solo.solo_type);
@@ -117,7 +116,7 @@ impl OutSolo {
             sid: solo.sid,
             username: user.username,
             content: solo.content,
-            created_at: date,
+            created_at: solo.created_at,
             solo_type: solo.solo_type,
             like,
             like_count,
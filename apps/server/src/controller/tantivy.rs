@@ -20,7 +20,7 @@ use tantivy::{
     tokenizer::{Token, TokenStream, Tokenizer},
     Index, IndexReader, IndexWriter, TantivyDocument,
 };
-use tracing::{info, warn};
+use tracing::{info, info_span, warn};
 use unicode_segmentation::UnicodeSegmentation;
 use whichlang::detect_language;
 
@@ -98,9 +98,11 @@ pub(crate) async fn search(
         }
 
         let searcher = SEARCHER.reader.searcher();
-        let top_docs: Vec<(_, _)> = searcher
-            .search(&query, &TopDocs::with_limit(20).and_offset(offset))
-            .unwrap_or_default();
+        let top_docs: Vec<(_, _)> = info_span!("tantivy_search", search, offset).in_scope(|| {
+            searcher
+                .search(&query, &TopDocs::with_limit(20).and_offset(offset))
+                .unwrap_or_default()
+        });
 
         for (_score, doc_address) in top_docs {
             let doc: TantivyDocument = searcher.doc(doc_address)?;
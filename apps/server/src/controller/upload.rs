@@ -1,5 +1,6 @@
 use super::{
-    db_utils::{u8_slice_to_u32, IterType},
+    audit::add_audit_entry,
+    db_utils::{set_one_with_key, u8_slice_to_u32, IterType},
     incr_id,
     inn::ParamsTag,
     meta_handler::{get_referer, PageData},
@@ -8,23 +9,25 @@ use super::{
     user::{InnRole, Role},
     Claim, SiteConfig, User,
 };
-use crate::{config::CONFIG, controller::filters, error::AppError, DB};
+use crate::{clamav::scan_upload, config::CONFIG, controller::filters, error::AppError, DB};
 use axum::{
+    body::Bytes,
     extract::{Multipart, Path, Query},
-    response::{IntoResponse, Redirect},
+    response::{IntoResponse, Redirect, Response},
 };
 use axum_extra::{
     headers::{Cookie, Referer},
     TypedHeader,
 };
 use data_encoding::HEXLOWER;
-use image::{imageops::FilterType, ImageFormat};
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
 use img_parts::{DynImage, ImageEXIF};
 use mozjpeg::{ColorSpace, Compress, ScanMode};
 use ring::digest::{Context, SHA1_FOR_LEGACY_USE_ONLY};
 use rinja_axum::{into_response, Template};
 use serde::Deserialize;
 use sled::Batch;
+use std::io::Cursor;
 use tokio::fs::{self, remove_file};
 use tracing::error;
 
@@ -34,7 +37,14 @@ pub(crate) struct UploadPicParams {
     iid: Option<u32>,
 }
 
-/// `POST /mod/inn_icon` && `/user/avatar`
+/// Logos and favicons larger than this are rejected
+const MAX_SITE_IMAGE_BYTES: usize = 512 * 1024;
+
+/// Uploaded images with more pixels than this are rejected before they are decoded, as a
+/// small file can claim a huge size
+const MAX_IMAGE_PIXELS: u64 = 40_000_000;
+
+/// `POST /mod/inn_icon` && `/user/avatar` && `/admin/logo` && `/admin/favicon`
 pub(crate) async fn upload_pic_post(
     cookie: Option<TypedHeader<Cookie>>,
     Query(params): Query<UploadPicParams>,
@@ -62,6 +72,12 @@ pub(crate) async fn upload_pic_post(
             target = "/user/setting".to_string();
             format!("{}/{}.png", &CONFIG.avatars_path.display(), claim.uid)
         }
+        "logo" | "favicon" => {
+            if Role::from(claim.role) != Role::Admin {
+                return Err(AppError::Unauthorized);
+            }
+            return site_image_post(site_config, claim.uid, &params.page_type, multipart).await;
+        }
         _ => unreachable!(),
     };
 
@@ -75,12 +91,75 @@ pub(crate) async fn upload_pic_post(
         };
         let image_format_detected = image::guess_format(&data)?;
         image::load_from_memory_with_format(&data, image_format_detected)?;
+        scan_upload(&data).await?;
         fs::write(fname, &data).await.unwrap();
     }
 
     Ok(Redirect::to(&target).into_response())
 }
 
+/// Save the uploaded logo or favicon to the upload path and point [SiteConfig] to it.
+async fn site_image_post(
+    mut site_config: SiteConfig,
+    uid: u32,
+    page_type: &str,
+    mut multipart: Multipart,
+) -> Result<Response, AppError> {
+    let Some(field) = multipart.next_field().await.unwrap() else {
+        return Err(AppError::NotFound);
+    };
+    let data = match field.bytes().await {
+        Ok(data) => data,
+        Err(e) => {
+            error!("{:?}", e);
+            return Ok(e.into_response());
+        }
+    };
+    let ext = site_image_ext(&data)?;
+    scan_upload(&data).await?;
+
+    let mut context = Context::new(&SHA1_FOR_LEGACY_USE_ONLY);
+    context.update(&data);
+    let sha1 = HEXLOWER.encode(context.finish().as_ref());
+    let fname = format!("{}_{}.{}", page_type, &sha1[0..20], ext);
+    fs::write(
+        format!("{}/{}", &CONFIG.upload_path.display(), fname),
+        &data,
+    )
+    .await?;
+
+    let url = format!("/static/upload/{fname}");
+    if page_type == "logo" {
+        site_config.logo = url.clone();
+    } else {
+        site_config.favicon = url.clone();
+    }
+    set_one_with_key(&DB, "__sled__default", "site_config", &site_config)?;
+    add_audit_entry(&DB, uid, page_type, &url)?;
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+/// Check the size and type of a logo or favicon, returning its file extension.
+fn site_image_ext(data: &[u8]) -> Result<&'static str, AppError> {
+    if data.len() > MAX_SITE_IMAGE_BYTES {
+        return Err(AppError::Custom("Image too large".into()));
+    }
+    let format = image::guess_format(data)?;
+    let ext = match format {
+        ImageFormat::Png => "png",
+        ImageFormat::Jpeg => "jpeg",
+        ImageFormat::Gif => "gif",
+        _ => {
+            return Err(AppError::Custom(format!(
+                "Unsupported image type: {format:?}"
+            )))
+        }
+    };
+    image::load_from_memory_with_format(data, format)?;
+    Ok(ext)
+}
+
 /// Page data: `gallery.html`
 #[derive(Template)]
 #[template(path = "gallery.html")]
@@ -247,67 +326,10 @@ pub(crate) async fn upload_post(
                 return Ok(e.into_response());
             }
         };
+        scan_upload(&data).await?;
 
-        let image_format_detected = image::guess_format(&data)?;
-        let ext;
-        let img_data = match image_format_detected {
-            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP => {
-                if let Ok(Some(mut img)) = DynImage::from_bytes(data) {
-                    img.set_exif(None);
-                    let img_noexif = img.encoder().bytes();
-
-                    // author: "Kim tae hyeon <kimth0734@gmail.com>"
-                    // https://github.com/altair823/image_compressor/blob/main/src/compressor.rs
-                    // license = "MIT"
-                    let dyn_img =
-                        image::load_from_memory_with_format(&img_noexif, image_format_detected)?;
-                    let factor = Factor::get(img_noexif.len());
-
-                    // resize
-                    let width = (dyn_img.width() as f32 * factor.size_ratio) as u32;
-                    let height = (dyn_img.width() as f32 * factor.size_ratio) as u32;
-                    let resized_img = dyn_img.resize(width, height, FilterType::Lanczos3);
-
-                    // compress
-                    let mut comp = Compress::new(ColorSpace::JCS_RGB);
-                    comp.set_scan_optimization_mode(ScanMode::Auto);
-                    comp.set_quality(factor.quality);
-
-                    let target_width = resized_img.width() as usize;
-                    let target_height = resized_img.height() as usize;
-                    comp.set_size(target_width, target_height);
-
-                    comp.set_optimize_scans(true);
-                    let mut comp = comp.start_compress(Vec::new()).unwrap();
-
-                    let mut line: usize = 0;
-                    let resized_img_data = resized_img.into_rgb8().into_vec();
-                    loop {
-                        if line > target_height - 1 {
-                            break;
-                        }
-                        let idx = line * target_width * 3..(line + 1) * target_width * 3;
-                        comp.write_scanlines(&resized_img_data[idx]).unwrap();
-                        line += 1;
-                    }
-
-                    if let Ok(comp) = comp.finish() {
-                        ext = "jpeg";
-                        comp
-                    } else {
-                        continue;
-                    }
-                } else {
-                    continue;
-                }
-            }
-            ImageFormat::Gif => {
-                ext = "gif";
-                data.to_vec()
-            }
-            _ => {
-                continue;
-            }
+        let Some((img_data, ext)) = process_image(data, CONFIG.max_image_px)? else {
+            continue;
         };
 
         let mut context = Context::new(&SHA1_FOR_LEGACY_USE_ONLY);
@@ -338,6 +360,94 @@ pub(crate) async fn upload_post(
     Ok(into_response(&page_upload))
 }
 
+/// Strip exif, downscale and compress an uploaded image. Returns the image data and extension,
+/// or `None` if the image is not supported.
+fn process_image(data: Bytes, max_px: u32) -> Result<Option<(Vec<u8>, &'static str)>, AppError> {
+    let image_format_detected = image::guess_format(&data)?;
+    if !matches!(
+        image_format_detected,
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Gif
+    ) {
+        return Ok(None);
+    }
+    let (width, height) =
+        image::ImageReader::with_format(Cursor::new(&data), image_format_detected)
+            .into_dimensions()?;
+    if u64::from(width) * u64::from(height) > MAX_IMAGE_PIXELS {
+        return Ok(None);
+    }
+
+    let img = match image_format_detected {
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP => {
+            if let Ok(Some(mut img)) = DynImage::from_bytes(data) {
+                img.set_exif(None);
+                let img_noexif = img.encoder().bytes();
+
+                // author: "Kim tae hyeon <kimth0734@gmail.com>"
+                // https://github.com/altair823/image_compressor/blob/main/src/compressor.rs
+                // license = "MIT"
+                let dyn_img =
+                    image::load_from_memory_with_format(&img_noexif, image_format_detected)?;
+                let factor = Factor::get(img_noexif.len());
+
+                // resize
+                let width = (dyn_img.width() as f32 * factor.size_ratio) as u32;
+                let height = (dyn_img.width() as f32 * factor.size_ratio) as u32;
+                let resized_img = dyn_img.resize(width, height, FilterType::Lanczos3);
+                let resized_img = fit_within(resized_img, max_px);
+
+                // compress
+                let mut comp = Compress::new(ColorSpace::JCS_RGB);
+                comp.set_scan_optimization_mode(ScanMode::Auto);
+                comp.set_quality(factor.quality);
+
+                let target_width = resized_img.width() as usize;
+                let target_height = resized_img.height() as usize;
+                comp.set_size(target_width, target_height);
+
+                comp.set_optimize_scans(true);
+                let mut comp = comp.start_compress(Vec::new()).unwrap();
+
+                let mut line: usize = 0;
+                let resized_img_data = resized_img.into_rgb8().into_vec();
+                loop {
+                    if line > target_height - 1 {
+                        break;
+                    }
+                    let idx = line * target_width * 3..(line + 1) * target_width * 3;
+                    comp.write_scanlines(&resized_img_data[idx]).unwrap();
+                    line += 1;
+                }
+
+                match comp.finish() {
+                    Ok(comp) => (comp, "jpeg"),
+                    Err(_) => return Ok(None),
+                }
+            } else {
+                return Ok(None);
+            }
+        }
+        ImageFormat::Gif => {
+            // downscaling would drop the animation, so oversized gifs are rejected
+            if width > max_px || height > max_px {
+                return Ok(None);
+            }
+            (data.to_vec(), "gif")
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(img))
+}
+
+/// Downscale `img` to fit within `max_px` on both axes, preserving aspect ratio.
+fn fit_within(img: DynamicImage, max_px: u32) -> DynamicImage {
+    if img.width() > max_px || img.height() > max_px {
+        img.resize(max_px, max_px, FilterType::Lanczos3)
+    } else {
+        img
+    }
+}
+
 #[derive(Copy, Clone)]
 struct Factor {
     /// Quality of the new compressed image.
@@ -382,3 +492,38 @@ impl Factor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_image_px() {
+        let img = DynamicImage::new_rgb8(8000, 1000);
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let (data, ext) = process_image(Bytes::from(png), 2048).unwrap().unwrap();
+        assert_eq!(ext, "jpeg");
+        let stored = image::load_from_memory(&data).unwrap();
+        assert!(stored.width() <= 2048 && stored.height() <= 2048);
+        assert_eq!(stored.width(), 2048);
+        assert_eq!(stored.height(), 256);
+
+        assert!(process_image(Bytes::from_static(b"not an image"), 2048).is_err());
+    }
+
+    #[test]
+    fn test_max_image_pixels() {
+        let img = DynamicImage::new_rgb8(8, 8);
+        let mut jpeg = Vec::new();
+        img.write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+
+        // claim 60000x60000 in the frame header, the data is never decoded
+        let sof = jpeg.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        jpeg[sof + 5..sof + 9].copy_from_slice(&[0xea, 0x60, 0xea, 0x60]);
+        assert!(process_image(Bytes::from(jpeg), 2048).unwrap().is_none());
+    }
+}
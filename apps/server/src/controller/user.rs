@@ -1,18 +1,23 @@
 //! ## [User] sign up/in/out, user profile/list controller
 
 use super::{
+    audit::add_audit_entry,
     db_utils::{
         generate_nanoid_ttl, get_count, get_count_by_prefix, get_id_by_name, get_range,
-        is_valid_name, ivec_to_u32, set_one, set_one_with_key, IterType,
+        i64_to_ivec, is_valid_name, ivec_to_u32, set_one, set_one_with_key, IterType,
     },
     filters,
     fmt::{clean_html, ts_to_date},
     get_ids_by_prefix, get_one, incr_id,
     meta_handler::{PageData, ParamsPage},
     notification::{add_notification, NtType},
-    u32_to_ivec, u8_slice_to_u32, Claim, Inn, InnType, SiteConfig, User,
+    u32_to_ivec, u8_slice_to_u32, Claim, Inn, InnType, Invite, SiteConfig, User,
+};
+use crate::{
+    config::{RegistrationMode, CONFIG},
+    error::AppError,
+    DB,
 };
-use crate::{config::CONFIG, error::AppError, DB};
 use ::rand::{thread_rng, Rng};
 use axum::{
     extract::{Form, Path, Query},
@@ -29,16 +34,20 @@ use captcha::{
 use data_encoding::BASE64;
 use garde::Validate;
 use identicon::Identicon;
-use jiff::Timestamp;
+use jiff::{tz::TimeZone, Timestamp};
 use ring::{
     pbkdf2,
     rand::{self, SecureRandom},
 };
 use rinja_axum::{into_response, Template};
 use serde::Deserialize;
-use sled::Db;
+use sled::{
+    transaction::{abort, ConflictableTransactionError, TransactionError, Transactional},
+    Db, IVec, Tree,
+};
 use std::{cmp::Ordering, fmt::Display, num::NonZeroU32, time::Duration};
 use tokio::time::sleep;
+use tracing::{info, warn};
 
 /// Page data: `user.html`
 #[derive(Template)]
@@ -238,6 +247,47 @@ impl Display for Role {
     }
 }
 
+/// How a user wants times shown, stored in the `time_display` tree
+#[derive(PartialEq, Debug)]
+#[repr(u8)]
+pub(super) enum TimeDisplay {
+    /// as [SiteConfig] `relative_time` says
+    Site = 0,
+    Relative = 1,
+    Absolute = 2,
+}
+
+impl From<u8> for TimeDisplay {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => TimeDisplay::Relative,
+            2 => TimeDisplay::Absolute,
+            _ => TimeDisplay::Site,
+        }
+    }
+}
+
+impl TimeDisplay {
+    pub(super) fn get(db: &Db, uid: u32) -> Self {
+        let v = db
+            .open_tree("time_display")
+            .and_then(|tree| tree.get(u32_to_ivec(uid)))
+            .ok()
+            .flatten();
+        v.map_or(TimeDisplay::Site, |v| TimeDisplay::from(v[0]))
+    }
+}
+
+/// IANA time zone of the user, stored in the `timezones` tree. Empty if not set.
+pub(super) fn get_timezone(db: &Db, uid: u32) -> String {
+    db.open_tree("timezones")
+        .and_then(|tree| tree.get(u32_to_ivec(uid)))
+        .ok()
+        .flatten()
+        .map(|v| String::from_utf8_lossy(&v).into_owned())
+        .unwrap_or_default()
+}
+
 #[derive(PartialEq, PartialOrd, Debug)]
 #[repr(u8)]
 pub(super) enum InnRole {
@@ -550,6 +600,8 @@ pub(crate) async fn role_post(
                 }
 
                 add_notification(&DB, uid, NtType::InnNotification, inn_role as u32, id)?;
+                let change = format!("inn {id} user {uid}: {}", form.role);
+                add_audit_entry(&DB, claim.uid, "inn_role", &change)?;
             }
 
             target = format!("/user/list?filter=inn&id={id}");
@@ -574,6 +626,8 @@ pub(crate) async fn role_post(
                 Claim::update_role(&DB, uid)?;
 
                 add_notification(&DB, uid, NtType::SiteNotification, role as u32, 0)?;
+                let change = format!("user {uid}: {}", form.role);
+                add_audit_entry(&DB, claim.uid, "role", &change)?;
             }
             target = "/user/list".to_string();
         }
@@ -596,6 +650,18 @@ pub(crate) struct FormUser {
     home_page: u8,
     #[garde(skip)]
     lang: String,
+    #[garde(range(max = 2))]
+    time_display: u8,
+    #[garde(length(max = 64), custom(is_timezone))]
+    timezone: String,
+}
+
+fn is_timezone(value: &str, _: &()) -> garde::Result {
+    if value.is_empty() || TimeZone::get(value).is_ok() {
+        Ok(())
+    } else {
+        Err(garde::Error::new("unknown time zone"))
+    }
 }
 
 /// Page data: `user_setting.html`
@@ -609,6 +675,8 @@ struct PageUserSetting<'a> {
     about: String,
     sessions: Vec<String>,
     home_page: u8,
+    time_display: u8,
+    timezone: String,
 }
 
 /// `GET /user/setting`
@@ -638,6 +706,9 @@ pub(crate) async fn user_setting(
         .get(u32_to_ivec(claim.uid))?
         .map_or(0, |hp| hp[0]);
 
+    let time_display = TimeDisplay::get(&DB, claim.uid) as u8;
+    let timezone = get_timezone(&DB, claim.uid);
+
     let has_unread = User::has_unread(&DB, claim.uid)?;
     let page_user_setting = PageUserSetting {
         uid: claim.uid,
@@ -647,6 +718,8 @@ pub(crate) async fn user_setting(
         url: user.url,
         sessions,
         home_page,
+        time_display,
+        timezone,
     };
 
     Ok(into_response(&page_user_setting))
@@ -751,6 +824,12 @@ pub(crate) async fn user_setting_post(
 
     let username = username.trim();
     let username_key = username.replace(' ', "_").to_lowercase();
+    if user.username != username
+        && Role::from(claim.role) != Role::Admin
+        && is_banned_name(&site_config.banned_usernames, &username_key)
+    {
+        return Err(AppError::NameUnavailable);
+    }
 
     let username_tree = DB.open_tree("usernames")?;
     if let Some(v) = username_tree.get(&username_key)? {
@@ -769,6 +848,14 @@ pub(crate) async fn user_setting_post(
     user.url = clean_html(&input.url);
     DB.open_tree("home_pages")?
         .insert(u32_to_ivec(user.uid), &[input.home_page])?;
+    DB.open_tree("time_display")?
+        .insert(u32_to_ivec(user.uid), &[input.time_display])?;
+    let timezones_tree = DB.open_tree("timezones")?;
+    if input.timezone.is_empty() {
+        timezones_tree.remove(u32_to_ivec(user.uid))?;
+    } else {
+        timezones_tree.insert(u32_to_ivec(user.uid), input.timezone.as_str())?;
+    }
 
     let lang = match input.lang.as_str() {
         "en" | "zh_cn" | "ja" | "fr" => {
@@ -817,6 +904,154 @@ pub(crate) async fn user_password_post(
     }
 }
 
+/// Form data: `/user/delete`
+#[derive(Deserialize)]
+pub(crate) struct FormDelete {
+    password: String,
+}
+
+/// `POST /user/delete`
+pub(crate) async fn user_delete_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Form(input): Form<FormDelete>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    let user: User = get_one(&DB, "users", claim.uid)?;
+
+    if !check_password(&input.password, &user.password_hash) {
+        sleep(Duration::from_secs(1)).await;
+        return Err(AppError::WrongPassword);
+    }
+
+    anonymize_user(&DB, claim.uid)?;
+    let avatar = format!("{}/{}.png", &CONFIG.avatars_path.display(), claim.uid);
+    if let Err(e) = tokio::fs::remove_file(&avatar).await {
+        warn!(%e, avatar, "failed to remove avatar");
+    }
+    add_audit_entry(&DB, claim.uid, "delete_account", &claim.uid.to_string())?;
+    info!(uid = claim.uid, "account deleted");
+
+    let cookie = format!(
+        "{COOKIE_NAME}=deleted; SameSite=Strict; Path=/; Secure; HttpOnly; expires=Thu, 01 Jan 1970 00:00:00 GMT"
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(SET_COOKIE, cookie.parse().unwrap());
+    Ok((headers, Redirect::to("/")))
+}
+
+/// Shown as the author of posts, comments and solos of deleted accounts.
+const DELETED_USERNAME: &str = "[deleted]";
+
+/// Anonymize a deleted account. Its posts, comments and solos, and the uploads they link to,
+/// are kept under [DELETED_USERNAME]. Everything else tied to the user is removed: the username
+/// can be taken again, the password is replaced by a random one, and sessions, follows, inn
+/// memberships, mod roles and applications, drafts, settings and the e2ee public key are dropped.
+fn anonymize_user(db: &Db, uid: u32) -> Result<(), AppError> {
+    let k = u32_to_ivec(uid);
+    let users = db.open_tree("users")?;
+    let usernames = db.open_tree("usernames")?;
+
+    // the keys of the user in other trees
+    let mut removed: Vec<(Tree, Vec<IVec>)> = Vec::new();
+
+    let sessions = db.open_tree("sessions")?;
+    let mut session_ids = Vec::new();
+    for i in sessions.iter() {
+        let (k, v) = i?;
+        let decoded: Result<(Claim, _), _> = bincode::decode_from_slice(&v, standard());
+        if decoded.is_ok_and(|(claim, _)| claim.uid == uid) {
+            session_ids.push(k);
+        }
+    }
+    removed.push((sessions, session_ids));
+
+    // `uid#uid` keys, each follow is stored once in each tree
+    let following = db.open_tree("user_following")?;
+    let followers = db.open_tree("user_followers")?;
+    let mut following_keys = Vec::new();
+    let mut followers_keys = Vec::new();
+    for i in following.scan_prefix(&k).keys() {
+        let key = i?;
+        followers_keys.push([&key[4..8], &key[0..4]].concat().into());
+        following_keys.push(key);
+    }
+    for i in followers.scan_prefix(&k).keys() {
+        let key = i?;
+        following_keys.push([&key[4..8], &key[0..4]].concat().into());
+        followers_keys.push(key);
+    }
+    removed.push((following, following_keys));
+    removed.push((followers, followers_keys));
+
+    // `uid#iid` keys
+    for name in ["mod_inns", "user_inns"] {
+        let tree = db.open_tree(name)?;
+        let keys = tree.scan_prefix(&k).keys().collect::<Result<_, _>>()?;
+        removed.push((tree, keys));
+    }
+    // `iid#uid` keys
+    for name in ["inn_users", "inn_apply"] {
+        let tree = db.open_tree(name)?;
+        let mut keys = Vec::new();
+        for i in tree.iter().keys() {
+            let key = i?;
+            if key[4..8] == k[..] {
+                keys.push(key);
+            }
+        }
+        removed.push((tree, keys));
+    }
+    // `uid` keys
+    for name in [
+        "drafts",
+        "home_pages",
+        "lang",
+        "time_display",
+        "timezones",
+        "pub_keys",
+    ] {
+        removed.push((db.open_tree(name)?, vec![k.clone()]));
+    }
+
+    let password_hash = generate_password_hash(&gen_password());
+    let mut trees = vec![&users, &usernames];
+    trees.extend(removed.iter().map(|(tree, _)| tree));
+    // a slice, as sled transactions take at most 14 trees in a tuple
+    trees
+        .transaction(|trees| {
+            let (users, usernames) = (&trees[0], &trees[1]);
+            let Some(v) = users.get(&k)? else {
+                return abort(AppError::NotFound);
+            };
+            let (mut user, _): (User, usize) = bincode::decode_from_slice(&v, standard())
+                .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+
+            usernames.remove(user.username.replace(' ', "_").to_lowercase().as_bytes())?;
+            user.username = DELETED_USERNAME.to_owned();
+            user.password_hash = password_hash.clone();
+            user.recovery_hash = None;
+            user.role = Role::Banned as u8;
+            user.url = String::new();
+            user.about = String::new();
+            let v = bincode::encode_to_vec(&user, standard())
+                .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            users.insert(k.clone(), v)?;
+
+            for (tree, (_, keys)) in trees[2..].iter().zip(&removed) {
+                for key in keys {
+                    tree.remove(key.clone())?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+}
+
 pub(crate) const COOKIE_NAME: &str = "id";
 
 /// Form data: `/signin`
@@ -824,7 +1059,8 @@ pub(crate) const COOKIE_NAME: &str = "id";
 pub(crate) struct FormSignin {
     username: String,
     password: String,
-    remember: String,
+    #[serde(default)]
+    remember: bool,
 }
 
 /// Page data: `signin.html`
@@ -864,9 +1100,12 @@ pub(crate) async fn signin_post(Form(input): Form<FormSignin>) -> impl IntoRespo
         if site_config.read_only && Role::from(user.role) != Role::Admin {
             return Err(AppError::ReadOnly);
         }
+        if site_config.maintenance && Role::from(user.role) != Role::Admin {
+            return Err(AppError::Maintenance);
+        }
 
         let mut headers = HeaderMap::new();
-        let cookie = Claim::generate_cookie(&DB, user, &input.remember)?;
+        let cookie = Claim::generate_cookie(&DB, user, input.remember)?;
         headers.insert(SET_COOKIE, cookie.parse().unwrap());
 
         if headers.is_empty() {
@@ -892,6 +1131,8 @@ pub(crate) struct FormSignup {
     password2: String,
     captcha_id: String,
     captcha_value: String,
+    #[serde(default)]
+    invite_code: String,
 }
 
 /// Page data: `signup.html`
@@ -901,14 +1142,27 @@ struct PageSignup<'a> {
     page_data: PageData<'a>,
     captcha_id: String,
     captcha_image: String,
+    invite_required: bool,
+    invite_code: String,
+}
+
+/// url params: `signup.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsSignup {
+    invite: Option<String>,
 }
 
 /// `GET /signup`
-pub(crate) async fn signup() -> Result<impl IntoResponse, AppError> {
+pub(crate) async fn signup(
+    Query(params): Query<ParamsSignup>,
+) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     if site_config.read_only {
         return Err(AppError::ReadOnly);
     }
+    if CONFIG.registration_mode == RegistrationMode::Closed {
+        return Err(AppError::RegistrationClosed);
+    }
     let page_data = PageData::new("Sign up", &site_config, None, false);
 
     let captcha_difficulty = match site_config.captcha_difficulty.as_str() {
@@ -934,6 +1188,8 @@ pub(crate) async fn signup() -> Result<impl IntoResponse, AppError> {
         page_data,
         captcha_id,
         captcha_image: captcha.as_base64().unwrap(),
+        invite_required: invite_required(&DB)?,
+        invite_code: clean_html(&params.invite.unwrap_or_default()),
     };
     Ok(into_response(&page_signup))
 }
@@ -958,10 +1214,42 @@ fn captcha_digits() -> Captcha {
     c
 }
 
+/// Whether the lowercase `username_key` matches one of [SiteConfig] `banned_usernames`,
+/// separated by commas or whitespace. `*` matches any characters, like `admin*`.
+fn is_banned_name(banned: &str, username_key: &str) -> bool {
+    banned
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|pattern| !pattern.is_empty())
+        .any(|pattern| wildcard_match(&pattern.to_lowercase(), username_key))
+}
+
+fn wildcard_match(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+    let Some(last) = parts.next_back() else {
+        // no `*` at all
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
 /// `POST /signup`
 pub(crate) async fn signup_post(
     WithValidation(input): WithValidation<Form<FormSignup>>,
 ) -> Result<impl IntoResponse, AppError> {
+    if CONFIG.registration_mode == RegistrationMode::Closed {
+        return Err(AppError::RegistrationClosed);
+    }
+
     let username = clean_html(&input.username);
     if !is_valid_name(&username) {
         return Err(AppError::NameInvalid);
@@ -979,18 +1267,37 @@ pub(crate) async fn signup_post(
 
     let username = username.trim();
     let username_key = username.replace(' ', "_").to_lowercase();
+    let site_config = SiteConfig::get(&DB)?;
+    if is_banned_name(&site_config.banned_usernames, &username_key) {
+        return Err(AppError::NameUnavailable);
+    }
+
     let usernames_tree = DB.open_tree("usernames")?;
     if usernames_tree.contains_key(&username_key)? {
         return Err(AppError::NameExists);
     }
 
+    let created_at = Timestamp::now().as_second();
+    let invite_code = input.invite_code.trim();
+    let invite_required = invite_required(&DB)?;
+    // fail early on unknown codes, the invite is only taken once everything else is done
+    if invite_required && !DB.open_tree("invites")?.contains_key(invite_code)? {
+        return Err(AppError::InvalidInvite);
+    }
+
     let password_hash = generate_password_hash(&input.password);
     let uid = incr_id(&DB, "users_count")?;
 
     let avatar = format!("{}/{}.png", &CONFIG.avatars_path.display(), uid);
-    Identicon::new(&generate_salt()).image().save(avatar)?;
+    Identicon::new(&generate_salt()).image().save(&avatar)?;
+
+    if invite_required {
+        if let Err(e) = take_invite(&DB, invite_code, uid, created_at) {
+            let _ = std::fs::remove_file(&avatar);
+            return Err(e);
+        }
+    }
 
-    let created_at = Timestamp::now().as_second();
     let role = if uid == 1 {
         Role::Admin
     } else {
@@ -1008,12 +1315,116 @@ pub(crate) async fn signup_post(
     set_one(&DB, "users", uid, &user)?;
     usernames_tree.insert(username_key, u32_to_ivec(uid))?;
 
-    let cookie = Claim::generate_cookie(&DB, user, "4h")?;
+    let cookie = Claim::generate_cookie(&DB, user, false)?;
     let mut headers = HeaderMap::new();
     headers.insert(SET_COOKIE, cookie.parse().unwrap());
     Ok((headers, Redirect::to("/")))
 }
 
+/// Make `username` an admin, creating the user with a random password if it does not exist.
+/// Returns the password of a newly created user.
+pub fn create_admin(db: &Db, username: &str) -> Result<Option<String>, AppError> {
+    if let Some(uid) = get_id_by_name(db, "usernames", username)? {
+        let mut user: User = get_one(db, "users", uid)?;
+        user.role = Role::Admin as u8;
+        set_one(db, "users", uid, &user)?;
+        return Ok(None);
+    }
+
+    let username = username.trim();
+    if !is_valid_name(username) {
+        return Err(AppError::NameInvalid);
+    }
+
+    let password = gen_password();
+    let uid = incr_id(db, "users_count")?;
+    let avatar = format!("{}/{}.png", &CONFIG.avatars_path.display(), uid);
+    Identicon::new(&generate_salt()).image().save(avatar)?;
+
+    let user = User {
+        uid,
+        username: username.to_owned(),
+        password_hash: generate_password_hash(&password),
+        created_at: Timestamp::now().as_second(),
+        role: Role::Admin as u8,
+        ..Default::default()
+    };
+    set_one(db, "users", uid, &user)?;
+    db.open_tree("usernames")?
+        .insert(username.replace(' ', "_").to_lowercase(), u32_to_ivec(uid))?;
+
+    Ok(Some(password))
+}
+
+/// Set a random password for `username` and remove all of its sessions. Returns the password.
+pub fn reset_password(db: &Db, username: &str) -> Result<String, AppError> {
+    let uid = get_id_by_name(db, "usernames", username)?.ok_or(AppError::NotFound)?;
+    let mut user: User = get_one(db, "users", uid)?;
+    let password = gen_password();
+    user.password_hash = generate_password_hash(&password);
+    set_one(db, "users", uid, &user)?;
+
+    let sessions = db.open_tree("sessions")?;
+    for i in &sessions {
+        let (k, v) = i?;
+        if let Ok((claim, _)) = bincode::decode_from_slice::<Claim, _>(&v, standard()) {
+            if claim.uid == uid {
+                sessions.remove(k)?;
+            }
+        }
+    }
+
+    Ok(password)
+}
+
+/// The first user (admin) can always sign up, so the site can be bootstrapped in invite mode.
+fn invite_required(db: &Db) -> Result<bool, AppError> {
+    Ok(CONFIG.registration_mode == RegistrationMode::Invite && db.contains_key("users_count")?)
+}
+
+/// Take one use of the invite code for `uid`. It fails if the code does not exist, has
+/// expired or has been used up.
+fn take_invite(db: &Db, code: &str, uid: u32, now: i64) -> Result<(), AppError> {
+    if code.is_empty() {
+        return Err(AppError::InvalidInvite);
+    }
+
+    let invites = db.open_tree("invites")?;
+    let invite_uses = db.open_tree("invite_uses")?;
+    (&invites, &invite_uses)
+        .transaction(|(tx, uses_tx)| {
+            let Some(v) = tx.get(code)? else {
+                return abort(AppError::InvalidInvite);
+            };
+            let (mut invite, _): (Invite, usize) = bincode::decode_from_slice(&v, standard())
+                .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+
+            let expired = invite.expires_at.is_some_and(|t| t < now);
+            if expired || invite.uses_remaining == 0 {
+                return abort(AppError::InvalidInvite);
+            }
+
+            invite.uses_remaining -= 1;
+            let v = bincode::encode_to_vec(&invite, standard())
+                .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            tx.insert(code.as_bytes(), v)?;
+            uses_tx.insert(invite_use_key(code, uid), i64_to_ivec(now))?;
+            Ok(())
+        })
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+}
+
+/// `code#uid` key of the "invite_uses" tree. Codes vary in length, so the `#` keeps the
+/// uses of one code from matching the prefix of another.
+fn invite_use_key(code: &str, uid: u32) -> Vec<u8> {
+    let mut k = format!("{code}#").into_bytes();
+    k.extend_from_slice(&u32_to_ivec(uid));
+    k
+}
+
 /// `GET /signout`
 pub(crate) async fn signout(
     cookie: Option<TypedHeader<Cookie>>,
@@ -1145,18 +1556,8 @@ impl Claim {
         site_config: &SiteConfig,
     ) -> Option<Self> {
         let session = cookie.get(COOKIE_NAME)?;
-        let timestamp = session.split_once('_')?.0;
-        let tree = &db.open_tree("sessions").ok()?;
-        let timestamp = i64::from_str_radix(timestamp, 16).ok()?;
-        let now = Timestamp::now();
-
-        if timestamp < now.as_second() {
-            tree.remove(session).ok()?;
-            return None;
-        }
-
-        let v = tree.get(session).ok()??;
-        let (claim, _): (Claim, usize) = bincode::decode_from_slice(&v, standard()).ok()?;
+        let now = Timestamp::now().as_second();
+        let claim = get_session(db, session, now, CONFIG.session_idle_secs)?;
 
         if site_config.read_only && Role::from(claim.role) != Role::Admin {
             return None;
@@ -1207,11 +1608,19 @@ impl Claim {
     }
 
     /// generate a Claim from user and store it in session tree, then return a cookie with a session id.
-    fn generate_cookie(db: &Db, user: User, expiry: &str) -> Result<String, AppError> {
+    ///
+    /// A "remember me" session lasts `session_ttl_secs` at most, however long it is idle.
+    /// Otherwise it lasts [SHORT_SESSION_SECS] at most, expires after `session_idle_secs` of
+    /// idleness, and the cookie is removed when the browser is closed.
+    fn generate_cookie(db: &Db, user: User, remember: bool) -> Result<String, AppError> {
         if user.role == 0 {
             return Err(AppError::Banned);
         }
-        let seconds = expire_seconds(expiry);
+        let seconds = if remember {
+            CONFIG.session_ttl_secs
+        } else {
+            CONFIG.session_ttl_secs.min(SHORT_SESSION_SECS)
+        };
         let now = Timestamp::now().as_second();
         let session_id = generate_nanoid_ttl(seconds);
         let lang = db
@@ -1226,40 +1635,73 @@ impl Claim {
             last_write: now,
             session_id: session_id.clone(),
             lang,
+            last_seen: now,
+            remember,
         };
 
         set_one_with_key(db, "sessions", &session_id, &claim)?;
 
-        let cookie = format!(
-            "{COOKIE_NAME}={session_id}; SameSite=Strict; Path=/; Secure; HttpOnly; Max-Age={seconds}"
-        );
+        let mut cookie =
+            format!("{COOKIE_NAME}={session_id}; SameSite=Strict; Path=/; Secure; HttpOnly");
+        if remember {
+            cookie.push_str(&format!("; Max-Age={seconds}"));
+        }
         Ok(cookie)
     }
 }
 
-/// Compute expire time in seconds from a string like "1h", "1day".
-fn expire_seconds(expiry: &str) -> i64 {
-    match expiry {
-        "30m" => 1800,
-        "1h" => 3600,
-        "2h" => 2 * 3600,
-        "4h" => 4 * 3600,
-        "8h" => 8 * 3600,
-        "12h" => 12 * 3600,
-        "1day" => 24 * 3600,
-        "2days" => 2 * 24 * 3600,
-        "3days" => 3 * 24 * 3600,
-        "4days" => 4 * 24 * 3600,
-        "5days" => 5 * 24 * 3600,
-        "1week" => 7 * 24 * 3600,
-        "2weeks" => 2 * 7 * 24 * 3600,
-        _ => 4 * 3600,
+/// Absolute lifetime of a session without "remember me"
+const SHORT_SESSION_SECS: i64 = 24 * 3600;
+
+/// Persist `last_seen` at most once per this many seconds, to avoid a write on every request
+const SESSION_RENEW_SECS: i64 = 60;
+
+/// Get the session if it is neither past its absolute expiry (encoded in the session id) nor idle
+/// for more than `idle_secs`, unless it is a "remember me" one. Expired sessions are removed,
+/// valid ones slide the idle window.
+fn get_session(db: &Db, session: &str, now: i64, idle_secs: i64) -> Option<Claim> {
+    let timestamp = session.split_once('_')?.0;
+    let tree = &db.open_tree("sessions").ok()?;
+    let timestamp = i64::from_str_radix(timestamp, 16).ok()?;
+
+    if timestamp < now {
+        tree.remove(session).ok()?;
+        return None;
+    }
+
+    let v = tree.get(session).ok()??;
+    let (mut claim, _): (Claim, usize) = bincode::decode_from_slice(&v, standard()).ok()?;
+
+    if !claim.remember && now - claim.last_seen > idle_secs {
+        tree.remove(session).ok()?;
+        return None;
+    }
+
+    if now - claim.last_seen >= SESSION_RENEW_SECS {
+        claim.last_seen = now;
+        set_one_with_key(db, "sessions", session, &claim).ok()?;
     }
+
+    Some(claim)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::controller::db_utils::get_one_by_key;
+
+    #[test]
+    fn test_banned_names() {
+        let banned = "Admin, mod*, *bot\n*support*";
+        assert!(is_banned_name(banned, "admin"));
+        assert!(is_banned_name(banned, "moderator"));
+        assert!(is_banned_name(banned, "spam_bot"));
+        assert!(is_banned_name(banned, "customer_support_1"));
+        assert!(!is_banned_name(banned, "admins"));
+        assert!(!is_banned_name(banned, "alice"));
+        assert!(!is_banned_name(banned, "bots"));
+        assert!(!is_banned_name("", "admin"));
+    }
 
     #[test]
     fn test_generate_salt_len() {
@@ -1278,4 +1720,211 @@ mod tests {
         // must generate different password_hash and salt with the same password
         assert_ne!(password_hash, password_hash2);
     }
+
+    fn session_db(expires_at: i64, last_seen: i64, remember: bool) -> (Db, String) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let session_id = format!("{expires_at:x}_test");
+        let claim = Claim {
+            uid: 1,
+            username: "test".into(),
+            role: Role::Standard as u8,
+            last_write: last_seen,
+            session_id: session_id.clone(),
+            lang: None,
+            last_seen,
+            remember,
+        };
+        set_one_with_key(&db, "sessions", &session_id, &claim).unwrap();
+        (db, session_id)
+    }
+
+    #[test]
+    fn test_session_absolute_expiry() {
+        let (db, session_id) = session_db(1000, 900, true);
+        assert!(get_session(&db, &session_id, 950, 3600).is_some());
+        assert!(get_session(&db, &session_id, 1001, 3600).is_none());
+        // expired session is removed
+        assert!(!db
+            .open_tree("sessions")
+            .unwrap()
+            .contains_key(&session_id)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_session_idle_expiry() {
+        let (db, session_id) = session_db(100_000, 1000, false);
+        // used within the idle window, last_seen slides forward
+        let claim = get_session(&db, &session_id, 1500, 600).unwrap();
+        assert_eq!(claim.last_seen, 1500);
+        assert!(get_session(&db, &session_id, 2000, 600).is_some());
+        // idle for too long
+        assert!(get_session(&db, &session_id, 2700, 600).is_none());
+        assert!(get_session(&db, &session_id, 2000, 600).is_none());
+    }
+
+    #[test]
+    fn test_session_remember_idle() {
+        let (db, session_id) = session_db(100_000, 1000, true);
+        // idle for longer than the idle window, but remembered
+        let claim = get_session(&db, &session_id, 1000 + 10 * 600, 600).unwrap();
+        assert_eq!(claim.last_seen, 7000);
+        assert!(get_session(&db, &session_id, 50_000, 600).is_some());
+        // still bounded by the absolute expiry
+        assert!(get_session(&db, &session_id, 100_001, 600).is_none());
+    }
+
+    #[test]
+    fn test_anonymize_user() {
+        let (db, session_id) = session_db(100_000, 1000, false);
+        let user = User {
+            uid: 1,
+            username: "Test User".into(),
+            password_hash: generate_password_hash("password"),
+            recovery_hash: Some(generate_password_hash("recovery")),
+            role: Role::Standard as u8,
+            about: "about me".into(),
+            ..Default::default()
+        };
+        set_one(&db, "users", 1, &user).unwrap();
+        db.open_tree("usernames")
+            .unwrap()
+            .insert("test_user", u32_to_ivec(1))
+            .unwrap();
+        let follow = [&u32_to_ivec(2)[..], &u32_to_ivec(1)[..]].concat();
+        db.open_tree("user_following")
+            .unwrap()
+            .insert(&follow, &[])
+            .unwrap();
+        db.open_tree("user_followers")
+            .unwrap()
+            .insert([&u32_to_ivec(1)[..], &u32_to_ivec(2)[..]].concat(), &[])
+            .unwrap();
+        // mod of inn 5, member of inn 6 and applying to inn 7, where user 2 is also a member
+        let uid_iid = |uid, iid| [u32_to_ivec(uid), u32_to_ivec(iid)].concat();
+        for iid in [5, 6] {
+            db.open_tree("user_inns")
+                .unwrap()
+                .insert(uid_iid(1, iid), &[])
+                .unwrap();
+        }
+        db.open_tree("mod_inns")
+            .unwrap()
+            .insert(uid_iid(1, 5), &[])
+            .unwrap();
+        let inn_users = db.open_tree("inn_users").unwrap();
+        inn_users.insert(uid_iid(5, 1), &[10]).unwrap();
+        inn_users.insert(uid_iid(6, 1), &[4]).unwrap();
+        inn_users.insert(uid_iid(7, 1), &[1]).unwrap();
+        inn_users.insert(uid_iid(7, 2), &[4]).unwrap();
+        db.open_tree("inn_apply")
+            .unwrap()
+            .insert(uid_iid(7, 1), &[])
+            .unwrap();
+        db.open_tree("user_uploads")
+            .unwrap()
+            .insert(uid_iid(1, 1), "a.jpeg")
+            .unwrap();
+
+        anonymize_user(&db, 1).unwrap();
+
+        // nothing resolves to the old uid anymore
+        assert_eq!(get_id_by_name(&db, "usernames", "Test User").unwrap(), None);
+        assert!(db
+            .open_tree("usernames")
+            .unwrap()
+            .iter()
+            .values()
+            .all(|v| ivec_to_u32(&v.unwrap()) != 1));
+        assert!(get_session(&db, &session_id, 1500, 600).is_none());
+        for tree in ["sessions", "user_inns", "mod_inns", "inn_apply"] {
+            assert!(db.open_tree(tree).unwrap().is_empty(), "{tree}");
+        }
+        assert_eq!(inn_users.len(), 1);
+        assert!(inn_users.contains_key(uid_iid(7, 2)).unwrap());
+        // uploads are kept, as the kept posts may link to them
+        assert_eq!(db.open_tree("user_uploads").unwrap().len(), 1);
+        assert!(!db
+            .open_tree("user_following")
+            .unwrap()
+            .contains_key(&follow)
+            .unwrap());
+        assert!(db.open_tree("user_followers").unwrap().is_empty());
+
+        let user: User = get_one(&db, "users", 1).unwrap();
+        assert_eq!(user.username, DELETED_USERNAME);
+        assert_eq!(Role::from(user.role), Role::Banned);
+        assert!(user.recovery_hash.is_none());
+        assert!(user.about.is_empty());
+        assert!(!check_password("password", &user.password_hash));
+
+        assert!(matches!(anonymize_user(&db, 9), Err(AppError::NotFound)));
+    }
+
+    #[test]
+    fn test_time_prefs() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        assert_eq!(TimeDisplay::get(&db, 1), TimeDisplay::Site);
+        assert_eq!(get_timezone(&db, 1), "");
+
+        db.open_tree("time_display")
+            .unwrap()
+            .insert(u32_to_ivec(1), &[2])
+            .unwrap();
+        db.open_tree("timezones")
+            .unwrap()
+            .insert(u32_to_ivec(1), "Asia/Tokyo")
+            .unwrap();
+        assert_eq!(TimeDisplay::get(&db, 1), TimeDisplay::Absolute);
+        assert_eq!(get_timezone(&db, 1), "Asia/Tokyo");
+
+        assert!(is_timezone("", &()).is_ok());
+        assert!(is_timezone("Europe/Paris", &()).is_ok());
+        assert!(is_timezone("Mars/Olympus_Mons", &()).is_err());
+    }
+
+    fn invite_db(uses_remaining: u32, expires_at: Option<i64>) -> Db {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let invite = Invite {
+            uses_remaining,
+            expires_at,
+            created_by: 1,
+            created_at: 0,
+        };
+        set_one_with_key(&db, "invites", "code", &invite).unwrap();
+        db
+    }
+
+    #[test]
+    fn test_take_invite_valid() {
+        let db = invite_db(2, Some(100));
+        assert!(take_invite(&db, "code", 2, 50).is_ok());
+        let invite: Invite = get_one_by_key(&db, "invites", "code").unwrap();
+        assert_eq!(invite.uses_remaining, 1);
+        let uses = db.open_tree("invite_uses").unwrap();
+        assert!(uses.contains_key(invite_use_key("code", 2)).unwrap());
+        assert!(matches!(
+            take_invite(&db, "unknown", 2, 50),
+            Err(AppError::InvalidInvite)
+        ));
+    }
+
+    #[test]
+    fn test_take_invite_expired() {
+        let db = invite_db(2, Some(100));
+        assert!(matches!(
+            take_invite(&db, "code", 2, 101),
+            Err(AppError::InvalidInvite)
+        ));
+    }
+
+    #[test]
+    fn test_take_invite_exhausted() {
+        let db = invite_db(1, None);
+        assert!(take_invite(&db, "code", 2, 50).is_ok());
+        assert!(matches!(
+            take_invite(&db, "code", 2, 50),
+            Err(AppError::InvalidInvite)
+        ));
+    }
 }
@@ -23,6 +23,8 @@ pub enum AppError {
     CaptchaError,
     #[error("Name already exists")]
     NameExists,
+    #[error("This name is not available, please choose another one")]
+    NameUnavailable,
     #[error("Too many inns you are managing")]
     InnCreateLimit,
     #[error("Name should not start with a number, should be <a href='https://doc.rust-lang.org/std/primitive.char.html#method.is_alphanumeric'>alphanumeric</a> or '_' or ' '")]
@@ -33,6 +35,8 @@ pub enum AppError {
     WrongPassword,
     #[error("Too many attempts please try again later")]
     WriteInterval,
+    #[error("Too many new posts in this inn, please try again later")]
+    InnFlood,
     #[error("unauthorized")]
     Unauthorized,
     #[error("Please login first")]
@@ -45,12 +49,22 @@ pub enum AppError {
     ImageError(#[from] image::ImageError),
     #[error("The site is under maintenance. It is read only at the moment")]
     ReadOnly,
+    #[error("The site is under maintenance. Please come back later")]
+    Maintenance,
     #[error(transparent)]
     ValidationError(#[from] garde::Error),
     #[error(transparent)]
     AxumFormRejection(#[from] axum::extract::rejection::FormRejection),
     #[error("Invalid feed link")]
     InvalidFeedLink,
+    #[error("Invalid, expired or used up invite code")]
+    InvalidInvite,
+    #[error("Registration is closed")]
+    RegistrationClosed,
+    #[error("The file was rejected by the virus scanner: {0}")]
+    Infected(String),
+    #[error("The virus scanner is unavailable, please try again later")]
+    ScanUnavailable,
     #[error(transparent)]
     Reqwest(#[from] reqwest::Error),
     #[error("{0}")]
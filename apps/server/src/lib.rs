@@ -1,16 +1,34 @@
 #![doc = include_str!("../README.md")]
 
-pub use app_router::router;
+pub use app_router::{listener, router};
+pub use cli::{Command, CLI};
 pub use config::CONFIG;
 pub use controller::db_utils::{clear_invalid, get_one, ivec_to_u32, set_one, u8_slice_to_u32};
-pub use controller::{feed::cron_feed, tantivy::Tan};
+pub use controller::{
+    audit::add_audit_entry,
+    dead_links::cron_dead_links,
+    feed::cron_feed,
+    inn::export_inn,
+    migration::migrate,
+    tantivy::Tan,
+    user::{create_admin, reset_password},
+};
 pub use error::AppError;
+pub use telemetry::init_tracing;
+pub use tls::{reload_on_sighup, tls_config};
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 mod app_router;
+mod cache_policy;
+mod clamav;
+mod cli;
+mod client_ip;
 mod config;
 mod controller;
 mod error;
+mod origin_check;
+mod telemetry;
+mod tls;
 
 use data_encoding::HEXLOWER;
 use ring::digest::{Context, Digest, SHA256};
@@ -56,8 +74,15 @@ pub static DB: LazyLock<Db> = LazyLock::new(|| {
     info!(GIT_COMMIT);
 
     let db_url = &CONFIG.db;
-    let config = sled::Config::default().path(db_url);
+    let config = sled::Config::default()
+        .path(db_url)
+        .cache_capacity(CONFIG.db_cache_mb * 1024 * 1024)
+        .flush_every_ms(Some(CONFIG.db_flush_every_ms));
     let db = config.open().unwrap();
     info!("{}", db_url.display());
+    info!(
+        "db cache: {} MB, flush every {} ms",
+        CONFIG.db_cache_mb, CONFIG.db_flush_every_ms
+    );
     db
 });
@@ -8,11 +8,11 @@ use std::time::UNIX_EPOCH;
 use std::{fs, net::SocketAddr, path::PathBuf};
 
 use freedit::{
-    router, AppError, CONFIG, DB, VERSION, {clear_invalid, cron_feed, Tan},
+    add_audit_entry, create_admin, export_inn, init_tracing, listener, migrate, reload_on_sighup,
+    reset_password, router, tls_config, AppError, Command, CLI, CONFIG, DB, VERSION,
+    {clear_invalid, cron_dead_links, cron_feed, Tan},
 };
-use tokio::net::TcpListener;
 use tracing::{error, info, warn};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[cfg(not(target_os = "windows"))]
 #[global_allocator]
@@ -20,10 +20,16 @@ static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 #[tokio::main]
 async fn main() -> Result<(), AppError> {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new("info,tantivy=warn"))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let _telemetry = init_tracing();
+
+    if let Err(e) = migrate(&DB) {
+        error!(%e, "db migration failed, abort startup");
+        return Err(e);
+    }
+
+    if CLI.command != Command::Serve {
+        return run_command(&CLI.command);
+    }
 
     // only create snapshot in release mode
     #[cfg(not(debug_assertions))]
@@ -65,10 +71,25 @@ async fn main() -> Result<(), AppError> {
         }
     });
 
+    if CONFIG.dead_link_check_hours > 0 {
+        tokio::spawn(async move {
+            loop {
+                sleep_seconds(1800).await;
+                if let Err(e) = cron_dead_links(&DB).await {
+                    error!(%e);
+                }
+                sleep_seconds(3600 * CONFIG.dead_link_check_hours).await;
+            }
+        });
+    }
+
     tokio::spawn(async move {
         let mut tan = Tan::init().unwrap();
         if CONFIG.rebuild_index == Some(true) {
             tan.rebuild_index(&DB).unwrap();
+            if let Err(e) = add_audit_entry(&DB, 0, "reindex", "") {
+                error!(%e, "failed to audit the startup reindex");
+            }
         }
         let mut subscriber = DB.open_tree("tan").unwrap().watch_prefix(vec![]);
         while let Some(event) = (&mut subscriber).await {
@@ -86,18 +107,61 @@ async fn main() -> Result<(), AppError> {
         }
     });
 
+    let tls = tls_config(&CONFIG.tls_cert, &CONFIG.tls_key).await?;
     let app = router().await;
-    let addr: SocketAddr = CONFIG.addr.parse().unwrap();
-    let listener = TcpListener::bind(addr).await.unwrap();
+    let listener = listener(&CONFIG.addr)?;
+
+    if let Some(tls) = tls {
+        info!("listening on https://{}", listener.local_addr()?);
+        reload_on_sighup(tls.clone(), &CONFIG.tls_cert, &CONFIG.tls_key);
+        axum_server::from_tcp_rustls(listener.into_std()?, tls)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await?;
+        return Ok(());
+    }
 
-    info!("listening on http://{}", addr);
-    axum::serve(listener, app).await.unwrap();
+    info!("listening on http://{}", listener.local_addr()?);
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 
     Ok(())
 }
 
+/// Run an admin command against the db and exit, without starting the server.
+fn run_command(command: &Command) -> Result<(), AppError> {
+    match command {
+        Command::Serve => unreachable!(),
+        Command::Reindex => {
+            Tan::init()?.rebuild_index(&DB)?;
+            add_audit_entry(&DB, 0, "reindex", "")?;
+        }
+        Command::CreateAdmin(username) => {
+            match create_admin(&DB, username)? {
+                Some(password) => println!("created admin {username} with password: {password}"),
+                None => println!("{username} is now an admin"),
+            }
+            add_audit_entry(&DB, 0, "create_admin", username)?;
+        }
+        Command::ResetPassword(username) => {
+            let password = reset_password(&DB, username)?;
+            println!("new password of {username}: {password}");
+            add_audit_entry(&DB, 0, "reset_password", username)?;
+        }
+        Command::Backup => {
+            create_snapshot(&CONFIG.snapshots_path, &DB);
+            add_audit_entry(&DB, 0, "backup", "")?;
+        }
+        Command::ExportInn(iid) => println!("{}", export_inn(&DB, *iid)?),
+    }
+    DB.flush()?;
+    Ok(())
+}
+
 // TODO: TEST with https://github.com/hatoo/oha
-#[allow(dead_code)]
 fn create_snapshot(snapshot_path: &PathBuf, db: &sled::Db) {
     let checksum = db.checksum().unwrap();
     info!(%checksum);
@@ -0,0 +1,119 @@
+//! Reject cross-site form posts by their `Origin` or `Referer` header, a second line of
+//! defense besides the `SameSite` session cookie. Requests without either header, like those
+//! of non-browser clients, are let through.
+
+use crate::config::CONFIG;
+use axum::{
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{
+    header::{HOST, ORIGIN, REFERER},
+    HeaderMap, Method, StatusCode,
+};
+use tracing::warn;
+
+/// Middleware: with `check_origin` on, a state-changing request must come from this host or
+/// one of `trusted_origins`, otherwise it gets 403.
+pub(crate) async fn check_origin(req: Request, next: Next) -> Response {
+    if !CONFIG.check_origin
+        || is_allowed(
+            req.method(),
+            req.headers(),
+            req.uri().host(),
+            &CONFIG.trusted_origins,
+        )
+    {
+        return next.run(req).await;
+    }
+
+    warn!(
+        origin = ?req.headers().get(ORIGIN),
+        referer = ?req.headers().get(REFERER),
+        "cross-site request rejected"
+    );
+    (StatusCode::FORBIDDEN, "Cross-site request rejected").into_response()
+}
+
+/// `uri_host` is the `:authority` of HTTP/2 requests, which have no `Host` header.
+fn is_allowed(
+    method: &Method,
+    headers: &HeaderMap,
+    uri_host: Option<&str>,
+    trusted_origins: &[String],
+) -> bool {
+    if method.is_safe() {
+        return true;
+    }
+    let Some(source) = headers
+        .get(ORIGIN)
+        .or_else(|| headers.get(REFERER))
+        .map(|v| v.to_str().unwrap_or_default())
+    else {
+        return true;
+    };
+
+    // an opaque origin, like of a sandboxed iframe, is never ours
+    let Some((scheme, rest)) = source.split_once("://") else {
+        return false;
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = headers.get(HOST).and_then(|v| v.to_str().ok()).or(uri_host);
+    if host.is_some_and(|host| host.eq_ignore_ascii_case(authority)) {
+        return true;
+    }
+
+    let origin = format!("{scheme}://{authority}");
+    trusted_origins
+        .iter()
+        .any(|trusted| trusted.trim_end_matches('/').eq_ignore_ascii_case(&origin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn headers(pairs: &[(http::HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_check_origin() {
+        let trusted = vec!["https://forum.example.org/".to_owned()];
+        let post = Method::POST;
+
+        let same = headers(&[(HOST, "example.com"), (ORIGIN, "https://example.com")]);
+        assert!(is_allowed(&post, &same, None, &trusted));
+        let referer = headers(&[
+            (HOST, "example.com:3001"),
+            (REFERER, "http://example.com:3001/post/1/2?anchor=0"),
+        ]);
+        assert!(is_allowed(&post, &referer, None, &trusted));
+        let h2 = headers(&[(ORIGIN, "https://example.com")]);
+        assert!(is_allowed(&post, &h2, Some("example.com"), &trusted));
+        let other_domain = headers(&[(HOST, "example.com"), (ORIGIN, "https://forum.example.org")]);
+        assert!(is_allowed(&post, &other_domain, None, &trusted));
+        assert!(is_allowed(
+            &post,
+            &headers(&[(HOST, "example.com")]),
+            None,
+            &trusted
+        ));
+
+        let cross = headers(&[(HOST, "example.com"), (ORIGIN, "https://evil.example")]);
+        assert!(!is_allowed(&post, &cross, None, &trusted));
+        assert!(is_allowed(&Method::GET, &cross, None, &trusted));
+        let suffix = headers(&[(HOST, "example.com"), (ORIGIN, "https://example.com.evil")]);
+        assert!(!is_allowed(&post, &suffix, None, &trusted));
+        let opaque = headers(&[(HOST, "example.com"), (ORIGIN, "null")]);
+        assert!(!is_allowed(&post, &opaque, None, &trusted));
+        let cross_referer = headers(&[(HOST, "example.com"), (REFERER, "https://evil.example/x")]);
+        assert!(!is_allowed(&post, &cross_referer, None, &trusted));
+    }
+}
@@ -0,0 +1,187 @@
+//! Tracing setup. Traces and metrics are exported with OpenTelemetry OTLP when
+//! `otlp_endpoint` is set, otherwise only the `fmt` subscriber is installed.
+//!
+//! The log filter starts as `log_filter` of the config and can be changed at runtime by admins
+//! through `/admin/loglevel`.
+
+use crate::config::{LogFormat, CONFIG};
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    runtime,
+    trace::{Sampler, TracerProvider},
+    Resource,
+};
+use std::sync::OnceLock;
+use tracing::error;
+use tracing_opentelemetry::{MetricsLayer, OpenTelemetryLayer};
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+static LOG_FILTER: OnceLock<FilterHandle> = OnceLock::new();
+
+/// Flushes and shuts down the OpenTelemetry providers when dropped.
+pub struct Telemetry {
+    providers: Option<(TracerProvider, SdkMeterProvider)>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some((tracer_provider, meter_provider)) = self.providers.take() {
+            if let Err(e) = tracer_provider.shutdown() {
+                error!(%e, "failed to shut down tracer provider");
+            }
+            if let Err(e) = meter_provider.shutdown() {
+                error!(%e, "failed to shut down meter provider");
+            }
+        }
+    }
+}
+
+/// Install the global tracing subscriber. Must be called inside the tokio runtime.
+pub fn init_tracing() -> Telemetry {
+    let (providers, otel_error) = if CONFIG.otlp_endpoint.is_empty() {
+        (None, None)
+    } else {
+        match otel_providers(&CONFIG.otlp_endpoint, CONFIG.otlp_sample_ratio) {
+            Ok(providers) => (Some(providers), None),
+            Err(e) => (None, Some(e)),
+        }
+    };
+
+    let otel_layers = providers.as_ref().map(|(tracer_provider, meter_provider)| {
+        let tracer = tracer_provider.tracer("freedit");
+        OpenTelemetryLayer::new(tracer).and_then(MetricsLayer::new(meter_provider.clone()))
+    });
+
+    let (filter, filter_error) = match EnvFilter::try_new(&CONFIG.log_filter) {
+        Ok(filter) => (filter, None),
+        Err(e) => (EnvFilter::new("info"), Some(e)),
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = LOG_FILTER.set(handle);
+
+    let (pretty, json) = match CONFIG.log_format {
+        LogFormat::Pretty => (Some(tracing_subscriber::fmt::layer()), None),
+        LogFormat::Json => (None, Some(tracing_subscriber::fmt::layer().json())),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(pretty)
+        .with(json)
+        .with(otel_layers)
+        .init();
+
+    if let Some(e) = filter_error {
+        error!(%e, "invalid log_filter {}, using info", CONFIG.log_filter);
+    }
+    if let Some(e) = otel_error {
+        error!(%e, "failed to init OpenTelemetry, traces are not exported");
+    }
+
+    Telemetry { providers }
+}
+
+/// The current log filter directive, like `info,tantivy=warn`.
+pub(crate) fn log_filter() -> Option<String> {
+    LOG_FILTER.get()?.with_current(ToString::to_string).ok()
+}
+
+/// Replace the log filter. The old one is kept if `directive` is invalid.
+pub(crate) fn set_log_filter(directive: &str) -> Result<(), String> {
+    let handle = LOG_FILTER.get().ok_or("tracing is not initialized")?;
+    reload_filter(handle, directive)
+}
+
+fn reload_filter(handle: &FilterHandle, directive: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(directive).map_err(|e| e.to_string())?;
+    handle.reload(filter).map_err(|e| e.to_string())
+}
+
+fn otel_providers(
+    endpoint: &str,
+    sample_ratio: f64,
+) -> Result<(TracerProvider, SdkMeterProvider), Box<dyn std::error::Error>> {
+    let resource = Resource::new([KeyValue::new("service.name", "freedit")]);
+
+    let span_exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let tracer_provider = TracerProvider::builder()
+        .with_batch_exporter(span_exporter, runtime::Tokio)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            sample_ratio,
+        ))))
+        .with_resource(resource.clone())
+        .build();
+
+    let metric_exporter = MetricExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let reader = PeriodicReader::builder(metric_exporter, runtime::Tokio).build();
+    let meter_provider = SdkMeterProvider::builder()
+        .with_reader(reader)
+        .with_resource(resource)
+        .build();
+
+    global::set_tracer_provider(tracer_provider.clone());
+    global::set_meter_provider(meter_provider.clone());
+
+    Ok((tracer_provider, meter_provider))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{debug, info};
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reload_filter() {
+        let buf = Buf::default();
+        let writer = buf.clone();
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let subscriber = tracing_subscriber::registry().with(filter).with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(move || writer.clone()),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            debug!("hidden");
+            info!("shown");
+            assert!(reload_filter(&handle, "not a level=").is_err());
+            reload_filter(&handle, "debug").unwrap();
+            debug!("now visible");
+            assert_eq!(handle.with_current(ToString::to_string).unwrap(), "debug");
+        });
+
+        let logs = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert!(!logs.contains("hidden"));
+        assert!(logs.contains("shown"));
+        assert!(logs.contains("now visible"));
+    }
+}
@@ -0,0 +1,90 @@
+//! Built-in TLS for deployments without a reverse proxy. HTTP/2 is negotiated with ALPN when
+//! TLS is on.
+
+use crate::error::AppError;
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::Path;
+use tracing::info;
+
+/// Load the PEM certificate chain and private key. Both empty means plain HTTP, so `None` is
+/// returned. A file that can't be read or parsed is an error, so a broken setup fails at startup.
+pub async fn tls_config(cert: &Path, key: &Path) -> Result<Option<RustlsConfig>, AppError> {
+    match (cert.as_os_str().is_empty(), key.as_os_str().is_empty()) {
+        (true, true) => return Ok(None),
+        (false, false) => {}
+        _ => {
+            return Err(AppError::Custom(
+                "tls_cert and tls_key must be set together".into(),
+            ))
+        }
+    }
+
+    // reqwest may enable another provider of rustls, so pick one explicitly
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let config = RustlsConfig::from_pem_file(cert, key).await.map_err(|e| {
+        AppError::Custom(format!(
+            "failed to load tls_cert {} and tls_key {}: {e}",
+            cert.display(),
+            key.display()
+        ))
+    })?;
+    info!("tls certificate loaded from {}", cert.display());
+    Ok(Some(config))
+}
+
+/// Reload the certificate on `SIGHUP`, so a renewed certificate is used without a restart.
+/// If the new files are invalid, the old certificate is kept.
+pub fn reload_on_sighup(config: RustlsConfig, cert: &Path, key: &Path) {
+    #[cfg(unix)]
+    {
+        use std::path::PathBuf;
+        use tokio::signal::unix::{signal, SignalKind};
+        use tracing::error;
+
+        let (cert, key): (PathBuf, PathBuf) = (cert.into(), key.into());
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    error!(%e, "failed to listen for SIGHUP, tls certificate won't be reloaded");
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                match config.reload_from_pem_file(&cert, &key).await {
+                    Ok(()) => info!("tls certificate reloaded from {}", cert.display()),
+                    Err(e) => error!(%e, "failed to reload tls certificate, keeping the old one"),
+                }
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    let _ = (config, cert, key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tls_config() {
+        let dir = std::env::temp_dir().join(format!("freedit_tls_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        assert!(tls_config(Path::new(""), Path::new(""))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(tls_config(&cert_path, &key_path).await.unwrap().is_some());
+        assert!(tls_config(&cert_path, Path::new("")).await.is_err());
+        // a certificate is not a key
+        assert!(tls_config(&cert_path, &cert_path).await.is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}